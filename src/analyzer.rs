@@ -0,0 +1,423 @@
+use std::{
+    collections::VecDeque,
+    f32::consts::PI,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use rodio::Source;
+
+use crate::playback::CHANNELS;
+
+pub const SPECTRUM_BANDS: usize = 48;
+const FFT_SIZE: usize = 2048;
+const LOWEST_FREQUENCY: f32 = 30.0;
+const HIGHEST_FREQUENCY: f32 = 16000.0;
+// the range shown by the bars and meters, everything below is empty
+pub const FLOOR_DB: f32 = -60.0;
+const FRAME_INTERVAL: Duration = Duration::from_millis(33);
+// how fast bars and peaks sink back, in dB per frame
+const FALL_DB: f32 = 1.5;
+const RMS_WINDOW: Duration = Duration::from_millis(300);
+const PEAK_WINDOW: Duration = Duration::from_millis(50);
+// samples the tap collects before handing them over
+const CHUNK_LEN: usize = 1024;
+// what the tap keeps for the analysis thread if it doesn't come for them,
+// a second at 192kHz
+const MAX_PENDING_FRAMES: usize = 192_000;
+
+// What the ui draws, all levels in dB.
+#[derive(Clone)]
+pub struct Spectrum {
+    pub bands: [f32; SPECTRUM_BANDS],
+    pub rms: [f32; 2],
+    pub peak: [f32; 2],
+}
+
+impl Default for Spectrum {
+    fn default() -> Self {
+        Self {
+            bands: [FLOOR_DB; SPECTRUM_BANDS],
+            rms: [FLOOR_DB; 2],
+            peak: [FLOOR_DB; 2],
+        }
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    enabled: AtomicBool,
+    // whether anything is played, the analysis waits on `wake` otherwise
+    playing: AtomicBool,
+    idle: Mutex<()>,
+    wake: Condvar,
+    // interleaved samples collected by the tap since the analysis took them
+    pending: Mutex<Vec<f32>>,
+    spectrum: Mutex<Spectrum>,
+}
+
+impl Shared {
+    fn is_active(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed) && self.playing.load(Ordering::Relaxed)
+    }
+
+    fn wake(&self) {
+        // taken so the analysis is either waiting or sees the flags
+        let _idle = self.idle.lock().unwrap();
+        self.wake.notify_all();
+    }
+}
+
+// Owns the analysis thread, which only runs while the visualizer is shown
+// and waits while nothing plays.
+pub struct Analyzer {
+    shared: Arc<Shared>,
+    sample_rate: u32,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Analyzer {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            shared: Arc::new(Shared::default()),
+            sample_rate,
+            thread: None,
+        }
+    }
+
+    pub fn tap<S: Source<Item = f32>>(&self, input: S) -> AnalyzerTap<S> {
+        AnalyzerTap {
+            input,
+            shared: self.shared.clone(),
+            enabled: false,
+            chunk: Vec::with_capacity(CHUNK_LEN),
+            countdown: 0,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.thread.is_some()
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if enabled == self.is_enabled() {
+            return;
+        }
+        self.shared.enabled.store(enabled, Ordering::Relaxed);
+        if enabled {
+            let shared = self.shared.clone();
+            let sample_rate = self.sample_rate;
+            self.thread = Some(thread::spawn(move || analyze(shared, sample_rate)));
+        } else {
+            self.shared.wake();
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+            self.shared.pending.lock().unwrap().clear();
+            *self.shared.spectrum.lock().unwrap() = Spectrum::default();
+        }
+    }
+
+    // Nothing is analyzed while nothing plays, the bars are empty then.
+    pub fn set_playing(&self, playing: bool) {
+        if self.shared.playing.swap(playing, Ordering::Relaxed) == playing {
+            return;
+        }
+        if playing {
+            self.shared.wake();
+        } else {
+            *self.shared.spectrum.lock().unwrap() = Spectrum::default();
+        }
+    }
+
+    pub fn spectrum(&self) -> Spectrum {
+        self.shared.spectrum.lock().unwrap().clone()
+    }
+}
+
+impl Drop for Analyzer {
+    fn drop(&mut self) {
+        self.set_enabled(false);
+    }
+}
+
+fn analyze(shared: Arc<Shared>, sample_rate: u32) {
+    let fft = Fft::new(FFT_SIZE);
+    let window: Vec<f32> = (0..FFT_SIZE)
+        .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / FFT_SIZE as f32).cos())
+        .collect();
+    // a full scale sine ends up at 0 dB
+    let window_gain: f32 = window.iter().sum::<f32>() / 2.0;
+    let band_bins = band_bins(sample_rate);
+    let frames = |t: Duration| (t.as_secs_f64() * sample_rate as f64) as usize;
+    let (rms_frames, peak_frames) = (frames(RMS_WINDOW), frames(PEAK_WINDOW));
+    let history_frames = FFT_SIZE.max(rms_frames).max(peak_frames);
+    let channels = CHANNELS as usize;
+
+    // the newest samples, interleaved
+    let mut history: VecDeque<f32> = VecDeque::with_capacity(history_frames * channels);
+    let mut incoming = Vec::new();
+    // of the rms window, kept up to date as samples come and go
+    let mut power = [0f64; 2];
+    let mut buffer = vec![Complex::default(); FFT_SIZE];
+    while shared.enabled.load(Ordering::Relaxed) {
+        if !shared.playing.load(Ordering::Relaxed) {
+            let idle = shared.idle.lock().unwrap();
+            let _idle = shared
+                .wake
+                .wait_while(idle, |_| {
+                    !shared.playing.load(Ordering::Relaxed)
+                        && shared.enabled.load(Ordering::Relaxed)
+                })
+                .unwrap();
+            // what was played before has nothing to do with what comes
+            history.clear();
+            power = [0.0; 2];
+            continue;
+        }
+        thread::sleep(FRAME_INTERVAL);
+        std::mem::swap(&mut incoming, &mut *shared.pending.lock().unwrap());
+        for frame in incoming.chunks_exact(channels) {
+            for (sum, sample) in power.iter_mut().zip(frame) {
+                *sum += (sample * sample) as f64;
+            }
+            history.extend(frame);
+            let available = history.len() / channels;
+            if available > rms_frames {
+                let old = (available - rms_frames - 1) * channels;
+                for (channel, sum) in power.iter_mut().enumerate() {
+                    let sample = history[old + channel];
+                    *sum -= (sample * sample) as f64;
+                }
+            }
+            if available > history_frames {
+                history.drain(..channels);
+            }
+        }
+        incoming.clear();
+        let available = history.len() / channels;
+
+        let mut bands = [FLOOR_DB; SPECTRUM_BANDS];
+        if available >= FFT_SIZE {
+            let start = (available - FFT_SIZE) * channels;
+            for (i, value) in buffer.iter_mut().enumerate() {
+                let frame = start + i * channels;
+                let mono = (0..channels).map(|c| history[frame + c]).sum::<f32>() / channels as f32;
+                *value = Complex::new(mono * window[i], 0.0);
+            }
+            fft.process(&mut buffer);
+            for (band, &(low, high)) in bands.iter_mut().zip(band_bins.iter()) {
+                let magnitude = buffer[low..=high]
+                    .iter()
+                    .map(|c| c.norm())
+                    .fold(0.0, f32::max);
+                *band = to_db(magnitude / window_gain);
+            }
+        }
+
+        let mut rms = [FLOOR_DB; 2];
+        let mut peak = [FLOOR_DB; 2];
+        for channel in 0..channels.min(2) {
+            let count = rms_frames.min(available).max(1);
+            let mean = power[channel].max(0.0) / count as f64;
+            rms[channel] = to_db(mean.sqrt() as f32);
+            peak[channel] = to_db(
+                history
+                    .range(available.saturating_sub(peak_frames) * channels..)
+                    .skip(channel)
+                    .step_by(channels)
+                    .fold(0.0, |max, s| max.max(s.abs())),
+            );
+        }
+
+        // levels jump up at once but sink slowly, so the display doesn't flicker
+        let mut spectrum = shared.spectrum.lock().unwrap();
+        // paused in the meantime, the bars stay empty
+        if !shared.playing.load(Ordering::Relaxed) {
+            continue;
+        }
+        let fall = |old: f32, new: f32| new.max(old - FALL_DB);
+        for (old, new) in spectrum.bands.iter_mut().zip(bands) {
+            *old = fall(*old, new);
+        }
+        for channel in 0..2 {
+            spectrum.rms[channel] = fall(spectrum.rms[channel], rms[channel]);
+            spectrum.peak[channel] = fall(spectrum.peak[channel], peak[channel]);
+        }
+    }
+}
+
+fn to_db(amplitude: f32) -> f32 {
+    (20.0 * amplitude.max(1e-9).log10()).max(FLOOR_DB)
+}
+
+// The range of fft bins of every band. The bands are spaced
+// logarithmically, the low ones may share a bin.
+fn band_bins(sample_rate: u32) -> Vec<(usize, usize)> {
+    let bin_width = sample_rate as f32 / FFT_SIZE as f32;
+    let highest = HIGHEST_FREQUENCY.min(sample_rate as f32 / 2.0);
+    let ratio = (highest / LOWEST_FREQUENCY).powf(1.0 / SPECTRUM_BANDS as f32);
+    (0..SPECTRUM_BANDS)
+        .map(|band| {
+            let low = LOWEST_FREQUENCY * ratio.powi(band as i32);
+            let high = low * ratio;
+            let last = FFT_SIZE / 2;
+            let low_bin = ((low / bin_width).round() as usize).clamp(1, last);
+            let high_bin = ((high / bin_width).round() as usize).clamp(low_bin, last);
+            (low_bin, high_bin)
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy, Default)]
+struct Complex {
+    re: f32,
+    im: f32,
+}
+
+impl Complex {
+    fn new(re: f32, im: f32) -> Self {
+        Self { re, im }
+    }
+
+    fn norm(self) -> f32 {
+        (self.re * self.re + self.im * self.im).sqrt()
+    }
+
+    fn mul(self, other: Self) -> Self {
+        Self::new(
+            self.re * other.re - self.im * other.im,
+            self.re * other.im + self.im * other.re,
+        )
+    }
+}
+
+// Iterative radix 2 Cooley-Tukey, the size has to be a power of two.
+struct Fft {
+    twiddles: Vec<Complex>,
+}
+
+impl Fft {
+    fn new(size: usize) -> Self {
+        let twiddles = (0..size / 2)
+            .map(|k| {
+                let angle = -2.0 * PI * k as f32 / size as f32;
+                Complex::new(angle.cos(), angle.sin())
+            })
+            .collect();
+        Self { twiddles }
+    }
+
+    fn process(&self, data: &mut [Complex]) {
+        let n = data.len();
+        let bits = n.trailing_zeros();
+        for i in 0..n {
+            let j = i.reverse_bits() >> (usize::BITS - bits);
+            if j > i {
+                data.swap(i, j);
+            }
+        }
+        let mut len = 2;
+        while len <= n {
+            let stride = n / len;
+            for start in (0..n).step_by(len) {
+                for k in 0..len / 2 {
+                    let even = data[start + k];
+                    let odd = data[start + k + len / 2].mul(self.twiddles[k * stride]);
+                    data[start + k] = Complex::new(even.re + odd.re, even.im + odd.im);
+                    data[start + k + len / 2] = Complex::new(even.re - odd.re, even.im - odd.im);
+                }
+            }
+            len *= 2;
+        }
+    }
+}
+
+// Passes the audio through unchanged and, while the visualizer is shown and
+// something plays, hands a copy to the analysis thread. Otherwise it only
+// checks the flags now and then.
+pub struct AnalyzerTap<S> {
+    input: S,
+    shared: Arc<Shared>,
+    enabled: bool,
+    chunk: Vec<f32>,
+    countdown: usize,
+}
+
+impl<S: Source<Item = f32>> Iterator for AnalyzerTap<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let sample = self.input.next()?;
+        if self.countdown == 0 {
+            self.countdown = CHUNK_LEN;
+            if self.enabled {
+                let mut pending = self.shared.pending.lock().unwrap();
+                if pending.len() < MAX_PENDING_FRAMES * CHANNELS as usize {
+                    pending.extend(self.chunk.drain(..));
+                }
+            }
+            self.enabled = self.shared.is_active();
+            self.chunk.clear();
+        }
+        self.countdown -= 1;
+        if self.enabled {
+            self.chunk.push(sample);
+        }
+        Some(sample)
+    }
+}
+
+impl<S: Source<Item = f32>> Source for AnalyzerTap<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rodio::buffer::SamplesBuffer;
+
+    use super::*;
+
+    fn pending(analyzer: &Analyzer) -> usize {
+        analyzer.shared.pending.lock().unwrap().len()
+    }
+
+    #[test]
+    fn tap_only_collects_while_playing() {
+        let analyzer = Analyzer::new(48000);
+        analyzer.shared.enabled.store(true, Ordering::Relaxed);
+        let source = SamplesBuffer::new(CHANNELS, 48000, vec![0.5; 10 * CHUNK_LEN]);
+        let mut tap = analyzer.tap(source);
+        // passed through either way
+        assert!(tap.by_ref().take(4 * CHUNK_LEN).all(|s| s == 0.5));
+        assert_eq!(pending(&analyzer), 0);
+
+        analyzer.set_playing(true);
+        tap.by_ref().take(4 * CHUNK_LEN).for_each(drop);
+        assert!(pending(&analyzer) >= 2 * CHUNK_LEN);
+
+        analyzer.set_playing(false);
+        analyzer.shared.pending.lock().unwrap().clear();
+        tap.by_ref().take(2 * CHUNK_LEN).for_each(drop);
+        assert!(pending(&analyzer) <= CHUNK_LEN);
+        assert_eq!(analyzer.spectrum().rms, [FLOOR_DB; 2]);
+    }
+}
@@ -2,22 +2,53 @@ use std::collections::HashMap;
 use std::env;
 use std::path::Path;
 use std::path::PathBuf;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 use color_eyre::Result;
 use ratatui::crossterm::event::KeyCode;
 use ratatui::{
-    crossterm::event::{self, Event, KeyEventKind},
+    crossterm::event::{self, Event, KeyEvent, KeyEventKind},
     widgets::ListState,
     DefaultTerminal,
 };
 
+use crate::channels::ChannelSettings;
+use crate::chapters::{chapter_at, read_chapters, Chapter};
+use crate::compressor::CompressorSettings;
+use crate::config::Config;
+use crate::crossfeed::CrossfeedSettings;
+use crate::cue::{is_cue_sheet, read_cue_sheet, CueTrack};
+use crate::equalizer::Equalizer;
 use crate::file::get_entrys;
 use crate::helper;
-use crate::music::{get_song_length, MusicHandle};
+use crate::loudness::{self, AlbumGrouping, ScanProgress};
+use crate::music::{get_song_info, MusicHandle, PlayerEvent};
+use crate::output::OutputKind;
+use crate::playback::{LoopMarker, MAX_FADE, MIN_FADE};
+use crate::resume::{resume_key, ResumePositions};
+use rodio::source::SeekError;
+
+const MAX_CROSSFADE: u64 = 12;
+const LOOP_NUDGE_MS: i64 = 100;
+// previous chapter goes to the start of the current one when further in
+const CHAPTER_RESTART_SECS: u64 = 3;
+// how often the position of a long file is written down while it plays
+const RESUME_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+// the music fades out over this long before the sleep timer stops it
+const SLEEP_FADE: Duration = Duration::from_secs(60);
+// a day, longer is a typo
+const MAX_SLEEP_MINUTES: u64 = 24 * 60;
+// how long the input thread waits for a key before it looks at the stop flag
+const INPUT_POLL: Duration = Duration::from_millis(100);
 
 pub struct App {
     pub should_exit: bool,
+    events: Receiver<AppEvent>,
+    event_sender: Sender<AppEvent>,
     pub file_list_index_current_display: usize,
     pub playing_list: MusicPlayingList,
     pub inputmode: InputMode,
@@ -25,6 +56,42 @@ pub struct App {
     pub musicfile_of_dir: MusicfileOfDir,
     pub apptab: AppTab,
     pub control_table: helper::HelpTable,
+    pub status_message: String,
+    pub jump_input: String,
+    pub crossfade: u64,
+    pub loudness_scan: Option<Arc<Mutex<ScanProgress>>>,
+    pub scan_group_by: AlbumGrouping,
+    pub equalizer: Equalizer,
+    pub preset_name_input: String,
+    pub chapter_list_state: ListState,
+    pub resume: ResumePositions,
+    pub last_resume_save: Instant,
+    pub sleep_timer: Option<SleepTimer>,
+    pub sleep_input: String,
+    // one-shot, cleared once the playing song ended
+    pub stop_after_current: bool,
+    // stopped at the end of a song or by the sleep timer, the automatic
+    // modes wait until something is played
+    pub halted: bool,
+    // the playing song ending was handled, until something plays again
+    stop_handled: bool,
+    // threshold, ratio or makeup in the compressor panel
+    pub compressor_row: usize,
+}
+
+pub enum SleepTimer {
+    // stops at this time
+    At(Instant),
+    // stops when this many more songs ended, the playing one counts
+    Songs(u32),
+    // stops when the last song of this album ended
+    EndOfAlbum(Option<String>),
+}
+
+// What the main loop wakes up for.
+pub enum AppEvent {
+    Input(Event),
+    Player(PlayerEvent),
 }
 
 #[derive(Clone, Copy)]
@@ -32,6 +99,12 @@ pub enum InputMode {
     Filelist,
     Playinglist,
     Helper,
+    Jump,
+    Equalizer,
+    PresetName,
+    Chapters,
+    SleepTimer,
+    Compressor,
 }
 
 pub struct MusicFileList {
@@ -44,6 +117,10 @@ pub struct Musicfile {
     pub info: PathBuf,
     pub status: StatusOfMusicFile,
     pub num_added: u8,
+    // set for the tracks listed when a cue sheet is opened
+    pub cue_track: Option<CueTrack>,
+    // where it was left off, if it was
+    pub resume_position: Option<Duration>,
 }
 
 pub enum StatusOfMusicFile {
@@ -63,6 +140,10 @@ pub struct MusicPlayingList {
     pub last_selected: i64,
     pub playingmod: PlayingMod,
     pub total_time: u64,
+    pub playing_track_id: Option<u64>,
+    pub queued_music: Option<(u64, usize)>,
+    // sent to the music handle, becomes `queued_music` once it is queued
+    pub queue_request: Option<(u64, usize)>,
 }
 
 pub struct PlayingItem {
@@ -70,12 +151,44 @@ pub struct PlayingItem {
     pub status: StatusOfPlayingItem,
     pub index_in_dir_and_file: (usize, usize),
     pub length: u32,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    pub cue_track: Option<CueTrack>,
+    pub chapters: Vec<Chapter>,
+    pub resume_position: Option<Duration>,
 }
+
+impl PlayingItem {
+    pub fn name(&self) -> String {
+        match &self.cue_track {
+            Some(track) => track.name(),
+            None => self
+                .path_of_music
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .to_string(),
+        }
+    }
+
+    fn resume_key(&self) -> String {
+        resume_key(&self.path_of_music, self.cue_track.as_ref())
+    }
+
+    // The part of the file to play, `None` for the whole of it.
+    fn section(&self) -> Option<(Duration, Option<Duration>)> {
+        self.cue_track
+            .as_ref()
+            .map(|track| (track.start, track.end))
+    }
+}
+
 pub enum StatusOfPlayingItem {
     Playing,
     Pause,
     Waiting,
     Stop,
+    Error,
 }
 
 pub enum PlayingMod {
@@ -102,20 +215,75 @@ impl AppTab {
 
 impl Default for App {
     fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl App {
+    // `output` comes from the command line and wins over the config.
+    pub fn new(output: Option<OutputKind>) -> Self {
         // let folder_path = "/home/charles/Music/demo";
         let current_path = env::current_dir().unwrap();
         let folder_path = current_path;
-        let files_path_vec = get_entrys(&Path::new(&folder_path));
+        let files_path_vec = get_entrys(Path::new(&folder_path));
 
         let mut file_lists_dir = Vec::new();
-        let file_list = MusicFileList::from_iter(files_path_vec.into_iter());
+        let file_list = MusicFileList::from_iter(files_path_vec);
         file_lists_dir.push(file_list);
 
         let mut hash_map_dir_index = HashMap::new();
-        hash_map_dir_index.insert(PathBuf::from(folder_path), 0 as usize);
+        hash_map_dir_index.insert(folder_path, 0);
 
-        Self {
+        let (config, config_error) = Config::load();
+        let crossfade = (config.crossfade.round() as u64).min(MAX_CROSSFADE);
+        let output = output.unwrap_or(config.output);
+        let (event_sender, events) = mpsc::channel();
+        let player_events = event_sender.clone();
+        let (mut musichandle, output_error) = MusicHandle::new(&output, move |event| {
+            let _ = player_events.send(AppEvent::Player(event));
+        });
+        musichandle.set_crossfade(Duration::from_secs(crossfade));
+        musichandle.set_fade(Duration::from_millis(
+            config.fade_ms.clamp(MIN_FADE, MAX_FADE),
+        ));
+        musichandle.set_replay_gain_mode(config.replaygain);
+        musichandle.set_visualizer(config.visualizer);
+        if config.skip_silence {
+            musichandle.set_silence_skip(Some(config.silence_threshold.min(0.0)));
+        }
+        musichandle.set_channel_settings(ChannelSettings {
+            mode: config.channel_mode,
+            balance: config.balance,
+        });
+        musichandle.set_crossfeed(CrossfeedSettings {
+            enabled: config.crossfeed,
+            preset: config.crossfeed_preset,
+        });
+        musichandle.set_compressor(
+            CompressorSettings {
+                enabled: config.compressor,
+                threshold: config.compressor_threshold,
+                ratio: config.compressor_ratio,
+                makeup: config.compressor_makeup,
+            }
+            .clamped(),
+        );
+        let (resume, positions_error) = ResumePositions::load(
+            config.resume,
+            Duration::from_secs(config.resume_min_minutes * 60),
+            &config.resume_dirs,
+        );
+        let (equalizer, presets_error) = Equalizer::new(
+            musichandle.equalizer_settings(),
+            &config.eq_preset,
+            config.eq_auto_genre,
+            &config.eq_genres,
+        );
+
+        let mut app = Self {
             should_exit: false,
+            events,
+            event_sender,
             playing_list: MusicPlayingList {
                 items: Vec::new(),
                 state: ListState::default(),
@@ -123,9 +291,12 @@ impl Default for App {
                 last_selected: -1,
                 playingmod: PlayingMod::Manual,
                 total_time: 0,
+                playing_track_id: None,
+                queued_music: None,
+                queue_request: None,
             },
             inputmode: InputMode::Filelist,
-            musichandle: MusicHandle::new(),
+            musichandle,
             musicfile_of_dir: MusicfileOfDir {
                 file_lists_of_dir: file_lists_dir,
                 map_of_dir_index: hash_map_dir_index,
@@ -133,7 +304,29 @@ impl Default for App {
             file_list_index_current_display: 0,
             apptab: AppTab::Music,
             control_table: helper::HelpTable::new(),
-        }
+            status_message: output_error
+                .or(config_error)
+                .or(presets_error)
+                .or(positions_error)
+                .unwrap_or_default(),
+            jump_input: String::new(),
+            crossfade,
+            loudness_scan: None,
+            scan_group_by: config.scan_group_by,
+            equalizer,
+            preset_name_input: String::new(),
+            chapter_list_state: ListState::default(),
+            resume,
+            last_resume_save: Instant::now(),
+            sleep_timer: None,
+            sleep_input: String::new(),
+            stop_after_current: false,
+            halted: false,
+            stop_handled: false,
+            compressor_row: 0,
+        };
+        app.refresh_resume_positions();
+        app
     }
 }
 
@@ -159,109 +352,341 @@ impl Musicfile {
             status,
             info,
             num_added: 0,
+            cue_track: None,
+            resume_position: None,
+        }
+    }
+
+    fn resume_key(&self) -> String {
+        match &self.cue_track {
+            Some(track) => resume_key(&track.file, Some(track)),
+            None => resume_key(&self.info, None),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match &self.cue_track {
+            Some(track) => track.name(),
+            None => self.info.file_name().unwrap().to_string_lossy().to_string(),
         }
     }
 }
 
 impl App {
     pub(crate) fn run(mut self, mut terminal: DefaultTerminal) -> Result<()> {
-        while !self.should_exit {
-            terminal.draw(|frame| frame.render_widget(&mut self, frame.area()))?;
+        let stop_input = Arc::new(AtomicBool::new(false));
+        let input = self.read_input(stop_input.clone());
+        let result = self.draw_until_exit(&mut terminal);
+        // the terminal is restored after this, nothing may read from it then
+        stop_input.store(true, Ordering::Relaxed);
+        let _ = input.join();
+        result?;
+        self.remember_playing_position();
+        self.musichandle.fade_out();
+        Ok(())
+    }
 
-            self.handle_events()?;
+    fn draw_until_exit(&mut self, terminal: &mut DefaultTerminal) -> Result<()> {
+        while !self.should_exit {
+            self.update();
+            terminal.draw(|frame| frame.render_widget(&mut *self, frame.area()))?;
+            self.wait_for_events();
         }
         Ok(())
     }
 
-    fn handle_events(&mut self) -> Result<()> {
-        let timeout = Duration::from_secs_f32(1.0 / 2.0);
-        if event::poll(timeout)? {
-            if let Event::Key(key) = event::read()? {
-                if key.kind == KeyEventKind::Press {
-                    match self.inputmode {
-                        InputMode::Playinglist => match key.code {
-                            KeyCode::Char('q') | KeyCode::Esc => self.should_exit = true,
-                            KeyCode::Char('h') | KeyCode::Left => {
-                                self.swith_from_playinglist_to_filelist()
-                            }
-                            KeyCode::Char('j') | KeyCode::Down => {
-                                self.playing_list.state.select_next()
-                            }
-                            KeyCode::Char('k') | KeyCode::Up => {
-                                self.playing_list.state.select_previous()
-                            }
-                            KeyCode::Char('g') => self.playing_list.state.select_first(),
-                            KeyCode::Char('G') => self.playing_list.state.select_last(),
-                            KeyCode::Enter => self.playing_current_music(),
-                            KeyCode::Char('p') => self.swith_playing_and_pause(),
-                            KeyCode::Char('s') => self.stop_playing(),
-                            KeyCode::Char('n') => self.playing_next_music(),
-                            KeyCode::Char('d') => self.remove_slow(),
-                            KeyCode::Char('D') => self.remove_fast(),
-                            KeyCode::Char('m') => self.change_playing_mod(),
-                            KeyCode::Char('-') => self.musichandle.change_volume(-0.05),
-                            KeyCode::Char('+') => self.musichandle.change_volume(0.05),
-                            KeyCode::Tab => {
-                                self.apptab = AppTab::Helper;
-                                self.inputmode = InputMode::Helper;
-                                self.control_table.last_mod = InputMode::Playinglist;
-                            }
-                            _ => {}
-                        },
-                        InputMode::Filelist => match key.code {
-                            KeyCode::Char('q') | KeyCode::Esc => self.should_exit = true,
-                            KeyCode::Char('j') | KeyCode::Down => self
-                                .musicfile_of_dir
-                                .file_lists_of_dir[self.file_list_index_current_display]
-                                .state
-                                .select_next(),
-                            KeyCode::Char('k') | KeyCode::Up => self
-                                .musicfile_of_dir
-                                .file_lists_of_dir[self.file_list_index_current_display]
-                                .state
-                                .select_previous(),
-                            KeyCode::Char('g') => self.musicfile_of_dir.file_lists_of_dir
-                                [self.file_list_index_current_display]
-                                .state
-                                .select_first(),
-                            KeyCode::Char('G') => self.musicfile_of_dir.file_lists_of_dir
-                                [self.file_list_index_current_display]
-                                .state
-                                .select_last(),
-                            KeyCode::Char('l') | KeyCode::Right => {
-                                self.swith_from_filelist_to_playinglist()
-                            }
-                            KeyCode::Char('a') | KeyCode::Enter => self.add_music_to_playlist(),
-                            KeyCode::Char('A') => self.add_all_music_in_current_dir_to_playlist(),
-                            KeyCode::Char('o') => self.opendir(),
-                            KeyCode::Backspace => self.backdir(),
-                            KeyCode::Tab => {
-                                self.apptab = AppTab::Helper;
-                                self.inputmode = InputMode::Helper;
-                                self.control_table.last_mod = InputMode::Filelist;
-                            }
-                            _ => {}
-                        },
-                        InputMode::Helper => match key.code {
-                            KeyCode::Down | KeyCode::Char('j') => {
-                                self.control_table.state.select_next()
-                            }
-                            KeyCode::Up | KeyCode::Char('k') => {
-                                self.control_table.state.select_previous()
-                            }
-
-                            KeyCode::Char('q') | KeyCode::Esc | KeyCode::Tab => {
-                                self.apptab = AppTab::Music;
-                                self.inputmode = self.control_table.last_mod;
-                            }
-
-                            _ => {}
-                        },
-                    }
+    // Keys come in on their own thread, so the loop below can sleep until
+    // there is a key, news from the player or a frame to draw. It looks at
+    // `stop` between waits, a blocking read would never return on quit.
+    fn read_input(&self, stop: Arc<AtomicBool>) -> JoinHandle<()> {
+        let sender = self.event_sender.clone();
+        thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                match event::poll(INPUT_POLL) {
+                    Ok(false) => continue,
+                    Ok(true) => {}
+                    Err(_) => break,
+                }
+                let Ok(event) = event::read() else {
+                    break;
+                };
+                if sender.send(AppEvent::Input(event)).is_err() {
+                    break;
                 }
             }
+        })
+    }
+
+    // Sleeps until something happens, then handles everything that piled up
+    // so it gets drawn once.
+    fn wait_for_events(&mut self) {
+        let first = match self.frame_interval() {
+            Some(interval) => match self.events.recv_timeout(interval) {
+                Ok(event) => Some(event),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => return,
+            },
+            None => self.events.recv().ok(),
+        };
+        let events: Vec<AppEvent> = first.into_iter().chain(self.events.try_iter()).collect();
+        for event in events {
+            match event {
+                AppEvent::Input(Event::Key(key)) if key.kind == KeyEventKind::Press => {
+                    self.handle_key(key)
+                }
+                // a resize only needs the redraw
+                AppEvent::Input(_) => {}
+                AppEvent::Player(event) => self.handle_player_event(event),
+            }
+        }
+    }
+
+    // How often to draw when nothing happens: the meters move while music
+    // plays, the scan and the sleep timer count on their own. The player wakes us
+    // up every second of a song anyway.
+    fn frame_interval(&self) -> Option<Duration> {
+        let playing = !self.musichandle.is_empty() && !self.musichandle.is_paused();
+        if playing
+            && (self.musichandle.visualizer_visible() || self.musichandle.compressor().enabled)
+        {
+            Some(Duration::from_secs_f32(1.0 / 30.0))
+        } else if self.loudness_scan.is_some()
+            || matches!(self.sleep_timer, Some(SleepTimer::At(_)))
+        {
+            Some(Duration::from_millis(500))
+        } else {
+            None
+        }
+    }
+
+    // Everything that follows from the player moving on and time passing,
+    // done before each frame so drawing only has to look.
+    fn update(&mut self) {
+        self.sync_playing_music();
+        // once when the song ends, not on every frame while stopped
+        let stopped = self.is_stop();
+        if stopped && !self.stop_handled {
+            self.handle_stop_music();
+        }
+        self.stop_handled = stopped;
+        self.continue_playing();
+        self.musichandle.update_visualizer();
+        self.update_sleep_timer();
+        self.update_loudness_scan();
+        self.save_resume_position_now_and_then();
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) {
+        match self.inputmode {
+            InputMode::Playinglist => match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => self.should_exit = true,
+                KeyCode::Char('h') | KeyCode::Left => self.swith_from_playinglist_to_filelist(),
+                KeyCode::Char('j') | KeyCode::Down => self.playing_list.state.select_next(),
+                KeyCode::Char('k') | KeyCode::Up => self.playing_list.state.select_previous(),
+                KeyCode::Char('g') => self.playing_list.state.select_first(),
+                KeyCode::Char('G') => self.playing_list.state.select_last(),
+                KeyCode::Enter => self.playing_current_music(),
+                KeyCode::Char('p') => self.swith_playing_and_pause(),
+                KeyCode::Char('s') => self.stop_playing(),
+                KeyCode::Char('S') => self.toggle_stop_after_current(),
+                KeyCode::Char('n') => self.playing_next_music(),
+                KeyCode::Char('d') => self.remove_slow(),
+                KeyCode::Char('D') => self.remove_fast(),
+                KeyCode::Char('m') => self.change_playing_mod(),
+                KeyCode::Char('-') => self.musichandle.change_volume(-0.05),
+                KeyCode::Char('+') => self.musichandle.change_volume(0.05),
+                KeyCode::Char(',') => self.seek_playing_music(-5),
+                KeyCode::Char('.') => self.seek_playing_music(5),
+                KeyCode::Char('<') => self.seek_playing_music(-30),
+                KeyCode::Char('>') => self.seek_playing_music(30),
+                KeyCode::Char(':') => self.start_jump_input(),
+                KeyCode::Char('x') => self.change_crossfade(-1),
+                KeyCode::Char('X') => self.change_crossfade(1),
+                KeyCode::Char('r') => self.change_replay_gain_mode(),
+                KeyCode::Char('e') => self.inputmode = InputMode::Equalizer,
+                KeyCode::Char('[') => self.musichandle.change_speed(-0.1),
+                KeyCode::Char(']') => self.musichandle.change_speed(0.1),
+                KeyCode::Char('\\') => self.musichandle.reset_speed(),
+                KeyCode::Char('a') => self.musichandle.set_loop_marker(LoopMarker::A),
+                KeyCode::Char('b') => self.musichandle.set_loop_marker(LoopMarker::B),
+                KeyCode::Char('c') => self.musichandle.clear_loop(),
+                KeyCode::Char(';') => self.musichandle.change_balance(-0.1),
+                KeyCode::Char('\'') => self.musichandle.change_balance(0.1),
+                KeyCode::Char('"') => self.musichandle.center_balance(),
+                KeyCode::Char('M') => self.musichandle.next_channel_mode(),
+                KeyCode::Char('f') => self.musichandle.toggle_crossfeed(),
+                KeyCode::Char('F') => self.musichandle.next_crossfeed_preset(),
+                KeyCode::Char('L') => self.inputmode = InputMode::Compressor,
+                KeyCode::Char('C') => self.open_chapter_list(),
+                KeyCode::Char('N') => self.next_chapter(),
+                KeyCode::Char('P') => self.previous_chapter(),
+                KeyCode::Char('z') => {
+                    self.sleep_input.clear();
+                    self.inputmode = InputMode::SleepTimer;
+                }
+                KeyCode::Char('v') => {
+                    let visible = self.musichandle.visualizer_visible();
+                    self.musichandle.set_visualizer(!visible)
+                }
+                KeyCode::Char('(') => self
+                    .musichandle
+                    .nudge_loop_marker(LoopMarker::A, -LOOP_NUDGE_MS),
+                KeyCode::Char(')') => self
+                    .musichandle
+                    .nudge_loop_marker(LoopMarker::A, LOOP_NUDGE_MS),
+                KeyCode::Char('{') => self
+                    .musichandle
+                    .nudge_loop_marker(LoopMarker::B, -LOOP_NUDGE_MS),
+                KeyCode::Char('}') => self
+                    .musichandle
+                    .nudge_loop_marker(LoopMarker::B, LOOP_NUDGE_MS),
+                KeyCode::Tab => {
+                    self.apptab = AppTab::Helper;
+                    self.inputmode = InputMode::Helper;
+                    self.control_table.last_mod = InputMode::Playinglist;
+                }
+                _ => {}
+            },
+            InputMode::Filelist => match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => self.should_exit = true,
+                KeyCode::Char('j') | KeyCode::Down => self.musicfile_of_dir.file_lists_of_dir
+                    [self.file_list_index_current_display]
+                    .state
+                    .select_next(),
+                KeyCode::Char('k') | KeyCode::Up => self.musicfile_of_dir.file_lists_of_dir
+                    [self.file_list_index_current_display]
+                    .state
+                    .select_previous(),
+                KeyCode::Char('g') => self.musicfile_of_dir.file_lists_of_dir
+                    [self.file_list_index_current_display]
+                    .state
+                    .select_first(),
+                KeyCode::Char('G') => self.musicfile_of_dir.file_lists_of_dir
+                    [self.file_list_index_current_display]
+                    .state
+                    .select_last(),
+                KeyCode::Char('l') | KeyCode::Right => self.swith_from_filelist_to_playinglist(),
+                KeyCode::Char('a') | KeyCode::Enter => self.add_music_to_playlist(),
+                KeyCode::Char('A') => self.add_all_music_in_current_dir_to_playlist(),
+                KeyCode::Char('o') => self.opendir(),
+                KeyCode::Char('R') => self.scan_loudness_of_selected(),
+                KeyCode::Backspace => self.backdir(),
+                KeyCode::Tab => {
+                    self.apptab = AppTab::Helper;
+                    self.inputmode = InputMode::Helper;
+                    self.control_table.last_mod = InputMode::Filelist;
+                }
+                _ => {}
+            },
+            InputMode::Helper => match key.code {
+                KeyCode::Down | KeyCode::Char('j') => self.control_table.state.select_next(),
+                KeyCode::Up | KeyCode::Char('k') => self.control_table.state.select_previous(),
+
+                KeyCode::Char('q') | KeyCode::Esc | KeyCode::Tab => {
+                    self.apptab = AppTab::Music;
+                    self.inputmode = self.control_table.last_mod;
+                }
+
+                _ => {}
+            },
+            InputMode::Jump => match key.code {
+                KeyCode::Char(c) if c.is_ascii_digit() || c == ':' => self.jump_input.push(c),
+                KeyCode::Backspace => {
+                    self.jump_input.pop();
+                }
+                KeyCode::Enter => {
+                    self.jump_to_typed_position();
+                    self.inputmode = InputMode::Playinglist;
+                }
+                KeyCode::Esc => self.inputmode = InputMode::Playinglist,
+                _ => {}
+            },
+            InputMode::SleepTimer => match key.code {
+                KeyCode::Char(c) if c.is_ascii_digit() || c == 't' || c == 'a' => {
+                    self.sleep_input.push(c)
+                }
+                KeyCode::Backspace => {
+                    self.sleep_input.pop();
+                }
+                KeyCode::Enter => {
+                    self.set_sleep_timer();
+                    self.inputmode = InputMode::Playinglist;
+                }
+                KeyCode::Esc => self.inputmode = InputMode::Playinglist,
+                _ => {}
+            },
+            InputMode::Equalizer => match key.code {
+                KeyCode::Char('q') | KeyCode::Esc | KeyCode::Char('e') => {
+                    self.inputmode = InputMode::Playinglist
+                }
+                KeyCode::Char('j') | KeyCode::Down => self.equalizer.select_band(1),
+                KeyCode::Char('k') | KeyCode::Up => self.equalizer.select_band(-1),
+                KeyCode::Char('l') | KeyCode::Right => self.equalizer.change_gain(1.0),
+                KeyCode::Char('h') | KeyCode::Left => self.equalizer.change_gain(-1.0),
+                KeyCode::Char('0') => self.equalizer.reset_band(),
+                KeyCode::Char('p') => self.equalizer.change_preset(1),
+                KeyCode::Char('P') => self.equalizer.change_preset(-1),
+                KeyCode::Char(' ') => self.equalizer.toggle(),
+                KeyCode::Char('a') => self.toggle_eq_auto_genre(),
+                KeyCode::Char('S') => {
+                    self.preset_name_input.clear();
+                    self.inputmode = InputMode::PresetName;
+                }
+                _ => {}
+            },
+            InputMode::Compressor => match key.code {
+                KeyCode::Char('q') | KeyCode::Esc | KeyCode::Char('L') => {
+                    self.inputmode = InputMode::Playinglist
+                }
+                KeyCode::Char('j') | KeyCode::Down => {
+                    self.compressor_row = (self.compressor_row + 1).min(2)
+                }
+                KeyCode::Char('k') | KeyCode::Up => {
+                    self.compressor_row = self.compressor_row.saturating_sub(1)
+                }
+                KeyCode::Char('l') | KeyCode::Right => self.change_compressor(1.0),
+                KeyCode::Char('h') | KeyCode::Left => self.change_compressor(-1.0),
+                KeyCode::Char('0') => self.reset_compressor_setting(),
+                KeyCode::Char(' ') => {
+                    let mut settings = self.musichandle.compressor();
+                    settings.enabled = !settings.enabled;
+                    self.musichandle.set_compressor(settings);
+                }
+                KeyCode::Char('n') => self
+                    .musichandle
+                    .set_compressor(CompressorSettings::night_mode()),
+                _ => {}
+            },
+            InputMode::Chapters => match key.code {
+                KeyCode::Char('q') | KeyCode::Esc | KeyCode::Char('C') => {
+                    self.inputmode = InputMode::Playinglist
+                }
+                KeyCode::Char('j') | KeyCode::Down => self.chapter_list_state.select_next(),
+                KeyCode::Char('k') | KeyCode::Up => self.chapter_list_state.select_previous(),
+                KeyCode::Char('g') => self.chapter_list_state.select_first(),
+                KeyCode::Char('G') => self.chapter_list_state.select_last(),
+                KeyCode::Enter => {
+                    if let Some(i) = self.chapter_list_state.selected() {
+                        self.seek_to_chapter(i);
+                    }
+                }
+                KeyCode::Char('N') => self.next_chapter(),
+                KeyCode::Char('P') => self.previous_chapter(),
+                _ => {}
+            },
+            InputMode::PresetName => match key.code {
+                KeyCode::Char(c) => self.preset_name_input.push(c),
+                KeyCode::Backspace => {
+                    self.preset_name_input.pop();
+                }
+                KeyCode::Enter => {
+                    self.save_eq_preset();
+                    self.inputmode = InputMode::Equalizer;
+                }
+                KeyCode::Esc => self.inputmode = InputMode::Equalizer,
+                _ => {}
+            },
         }
-        Ok(())
     }
 
     fn opendir(&mut self) {
@@ -270,7 +695,9 @@ impl App {
         if let Some(i) = music_list_display.state.selected() {
             let dir = music_list_display.items[i].info.clone();
 
-            if dir.is_dir() {
+            if is_cue_sheet(&dir) && dir.is_file() {
+                self.open_cue_sheet(dir);
+            } else if dir.is_dir() {
                 let index = self.musicfile_of_dir.map_of_dir_index.get(&dir);
                 match index {
                     Some(idx) => self.file_list_index_current_display = *idx,
@@ -281,13 +708,45 @@ impl App {
                         let index_of_this_list = self.musicfile_of_dir.file_lists_of_dir.len() - 1;
                         self.musicfile_of_dir
                             .map_of_dir_index
-                            .insert(PathBuf::from(dir), index_of_this_list);
+                            .insert(dir, index_of_this_list);
                         self.file_list_index_current_display = index_of_this_list;
+                        self.refresh_resume_positions();
                     }
                 }
             }
         }
     }
+    // Lists the tracks of a cue sheet like the files of a folder. Their
+    // paths are made up below the cue sheet, so going back works the same.
+    fn open_cue_sheet(&mut self, path: PathBuf) {
+        if let Some(idx) = self.musicfile_of_dir.map_of_dir_index.get(&path) {
+            self.file_list_index_current_display = *idx;
+            return;
+        }
+        let tracks = match read_cue_sheet(&path) {
+            Ok(tracks) => tracks,
+            Err(e) => {
+                self.status_message = format!("Can't open {}: {}", path.display(), e);
+                return;
+            }
+        };
+        let mut new_files = MusicFileList::from_iter(
+            tracks
+                .iter()
+                .map(|track| path.join(format!("{:02}", track.number))),
+        );
+        for (item, track) in new_files.items.iter_mut().zip(tracks) {
+            item.cue_track = Some(track);
+        }
+        self.musicfile_of_dir.file_lists_of_dir.push(new_files);
+        let index_of_this_list = self.musicfile_of_dir.file_lists_of_dir.len() - 1;
+        self.musicfile_of_dir
+            .map_of_dir_index
+            .insert(path, index_of_this_list);
+        self.file_list_index_current_display = index_of_this_list;
+        self.refresh_resume_positions();
+    }
+
     fn backdir(&mut self) {
         let music_list_display =
             &self.musicfile_of_dir.file_lists_of_dir[self.file_list_index_current_display];
@@ -300,7 +759,7 @@ impl App {
                 match index {
                     Some(idx) => self.file_list_index_current_display = *idx,
                     None => {
-                        let entrys = get_entrys(&lastdir);
+                        let entrys = get_entrys(lastdir);
                         let new_files = MusicFileList::from_iter(entrys);
                         self.musicfile_of_dir.file_lists_of_dir.push(new_files);
                         let index_of_this_list = self.musicfile_of_dir.file_lists_of_dir.len() - 1;
@@ -308,6 +767,7 @@ impl App {
                             .map_of_dir_index
                             .insert(PathBuf::from(lastdir), index_of_this_list);
                         self.file_list_index_current_display = index_of_this_list;
+                        self.refresh_resume_positions();
                     }
                 }
             }
@@ -315,89 +775,136 @@ impl App {
     }
 
     fn add_all_music_in_current_dir_to_playlist(&mut self) {
-        let len = self.musicfile_of_dir.file_lists_of_dir[self.file_list_index_current_display]
-            .items
-            .len();
+        let list_index = self.file_list_index_current_display;
+        let items = &self.musicfile_of_dir.file_lists_of_dir[list_index].items;
+        // files split up by a cue sheet are added through it, not again whole
+        let split_files: Vec<PathBuf> = items
+            .iter()
+            .filter(|item| item.cue_track.is_none() && is_cue_sheet(&item.info))
+            .filter_map(|item| read_cue_sheet(&item.info).ok())
+            .flatten()
+            .map(|track| track.file)
+            .collect();
+        let len = items.len();
 
         for i in 0..len {
-            let path_of_current_music: PathBuf = self.musicfile_of_dir.file_lists_of_dir
-                [self.file_list_index_current_display]
-                .items[i]
-                .info
-                .clone();
-            match crate::file::check_audio_file(&path_of_current_music) {
-                Ok(val) => {
-                    if !val {
-                        continue;
-                    }
-                }
-                Err(_) => continue,
-            }
-
-            self.musicfile_of_dir.file_lists_of_dir[self.file_list_index_current_display].items
-                [i]
-                .num_added += 1;
-            let play_time_of_current_music = match get_song_length(&path_of_current_music) {
-                Some(value) => value,
-                None => 0,
-            };
-            self.playing_list.items.push(PlayingItem {
-                path_of_music: path_of_current_music,
-                status: StatusOfPlayingItem::Waiting,
-                index_in_dir_and_file: (self.file_list_index_current_display, i),
-                length: play_time_of_current_music,
-            });
-            self.playing_list.total_time += play_time_of_current_music as u64;
-
-            let item_status = &mut self.musicfile_of_dir.file_lists_of_dir
-                [self.file_list_index_current_display]
-                .items[i]
-                .status;
-            *item_status = match *item_status {
-                StatusOfMusicFile::NotAdded => StatusOfMusicFile::Added,
-                StatusOfMusicFile::Added => StatusOfMusicFile::Added,
+            let path = &self.musicfile_of_dir.file_lists_of_dir[list_index].items[i].info;
+            if split_files.contains(path) {
+                continue;
             }
+            self.add_file_to_playlist(list_index, i);
         }
+        self.refresh_resume_positions();
+        self.queue_next_music();
     }
 
     fn add_music_to_playlist(&mut self) {
-        let music_list_display =
-            &self.musicfile_of_dir.file_lists_of_dir[self.file_list_index_current_display];
+        let list_index = self.file_list_index_current_display;
+        let music_list_display = &self.musicfile_of_dir.file_lists_of_dir[list_index];
         if let Some(i) = music_list_display.state.selected() {
-            let path_of_current_music: PathBuf = music_list_display.items[i].info.clone();
-            match crate::file::check_audio_file(&path_of_current_music) {
-                Ok(val) => {
-                    if !val {
-                        return;
+            if self.add_file_to_playlist(list_index, i) {
+                self.refresh_resume_positions();
+                self.queue_next_music();
+            }
+        }
+    }
+
+    // Adds an entry of a file list: an audio file, every track of a cue
+    // sheet or a single track of an opened one. Anything else is skipped.
+    fn add_file_to_playlist(&mut self, list_index: usize, i: usize) -> bool {
+        let music_file = &self.musicfile_of_dir.file_lists_of_dir[list_index].items[i];
+        let path_of_current_music = music_file.info.clone();
+        let cue_tracks = match &music_file.cue_track {
+            Some(track) => vec![track.clone()],
+            None if is_cue_sheet(&path_of_current_music) => {
+                match read_cue_sheet(&path_of_current_music) {
+                    Ok(tracks) => tracks,
+                    Err(e) => {
+                        self.status_message =
+                            format!("Can't add {}: {}", path_of_current_music.display(), e);
+                        return false;
                     }
                 }
-                Err(_) => return,
             }
+            None => {
+                match crate::file::check_audio_file(&path_of_current_music) {
+                    Ok(val) => {
+                        if !val {
+                            return false;
+                        }
+                    }
+                    Err(_) => return false,
+                }
+                let song_info = get_song_info(&path_of_current_music);
+                let play_time_of_current_music = song_info
+                    .as_ref()
+                    .map_or(0, |info| info.duration.as_secs() as u32);
+                // read once here, playing the song doesn't look for them again
+                let mut chapters = read_chapters(&path_of_current_music);
+                if chapters.is_empty() {
+                    chapters = song_info
+                        .as_ref()
+                        .map(|info| info.chapters.clone())
+                        .unwrap_or_default();
+                }
+                self.playing_list.items.push(PlayingItem {
+                    path_of_music: path_of_current_music,
+                    status: StatusOfPlayingItem::Waiting,
+                    index_in_dir_and_file: (list_index, i),
+                    length: play_time_of_current_music,
+                    genre: song_info.as_ref().and_then(|info| info.genre.clone()),
+                    album: song_info.as_ref().and_then(|info| info.album.clone()),
+                    cue_track: None,
+                    chapters,
+                    resume_position: None,
+                });
+                self.playing_list.total_time += play_time_of_current_music as u64;
+                self.mark_added(list_index, i);
+                return true;
+            }
+        };
 
-            self.musicfile_of_dir.file_lists_of_dir[self.file_list_index_current_display].items
-                [i]
-                .num_added += 1;
-            let play_time_of_current_music = match get_song_length(&path_of_current_music) {
-                Some(value) => value,
-                None => 0,
+        let mut added = false;
+        // the tracks of a sheet mostly share one file, it is probed once
+        let mut probed = HashMap::new();
+        for track in cue_tracks {
+            let probe = probed.entry(track.file.clone()).or_insert_with(|| {
+                matches!(crate::file::check_audio_file(&track.file), Ok(true))
+                    .then(|| get_song_info(&track.file))
+            });
+            let Some(song_info) = probe.as_ref().map(Option::as_ref) else {
+                continue;
             };
+            let play_time_of_current_music =
+                song_info.map_or(0, |info| track.length(info.duration).as_secs() as u32);
             self.playing_list.items.push(PlayingItem {
-                path_of_music: path_of_current_music,
+                path_of_music: track.file.clone(),
                 status: StatusOfPlayingItem::Waiting,
-                index_in_dir_and_file: (self.file_list_index_current_display, i),
+                index_in_dir_and_file: (list_index, i),
                 length: play_time_of_current_music,
+                genre: track
+                    .genre
+                    .clone()
+                    .or_else(|| song_info.and_then(|info| info.genre.clone())),
+                album: track
+                    .album
+                    .clone()
+                    .or_else(|| song_info.and_then(|info| info.album.clone())),
+                cue_track: Some(track),
+                chapters: Vec::new(),
+                resume_position: None,
             });
             self.playing_list.total_time += play_time_of_current_music as u64;
-
-            let item_status = &mut self.musicfile_of_dir.file_lists_of_dir
-                [self.file_list_index_current_display]
-                .items[i]
-                .status;
-            *item_status = match *item_status {
-                StatusOfMusicFile::NotAdded => StatusOfMusicFile::Added,
-                StatusOfMusicFile::Added => StatusOfMusicFile::Added,
-            }
+            self.mark_added(list_index, i);
+            added = true;
         }
+        added
+    }
+
+    fn mark_added(&mut self, list_index: usize, i: usize) {
+        let music_file = &mut self.musicfile_of_dir.file_lists_of_dir[list_index].items[i];
+        music_file.num_added += 1;
+        music_file.status = StatusOfMusicFile::Added;
     }
 
     fn swith_from_playinglist_to_filelist(&mut self) {
@@ -433,20 +940,16 @@ impl App {
 
     fn playing_current_music(&mut self) {
         if let Some(i) = self.playing_list.state.selected() {
-            self.musichandle
-                .play_new(self.playing_list.items[i].path_of_music.clone());
-            if self.playing_list.playing_music_index != -1 {
-                self.playing_list.items[self.playing_list.playing_music_index as usize].status =
-                    StatusOfPlayingItem::Waiting;
-            }
-            self.playing_list.items[i].status = StatusOfPlayingItem::Playing;
-            self.playing_list.playing_music_index = i as i64;
+            self.play_music_at(i);
         }
     }
 
     fn swith_playing_and_pause(&mut self) {
         let playing_music_index = self.playing_list.playing_music_index;
         if playing_music_index != -1 {
+            if !self.musichandle.is_paused() {
+                self.remember_playing_position();
+            }
             self.musichandle.play_pause();
             self.playing_list.items[playing_music_index as usize].status =
                 match self.playing_list.items[playing_music_index as usize].status {
@@ -454,6 +957,7 @@ impl App {
                     StatusOfPlayingItem::Pause => StatusOfPlayingItem::Playing,
                     StatusOfPlayingItem::Waiting => StatusOfPlayingItem::Waiting,
                     StatusOfPlayingItem::Stop => StatusOfPlayingItem::Stop,
+                    StatusOfPlayingItem::Error => StatusOfPlayingItem::Error,
                 };
         }
     }
@@ -461,49 +965,33 @@ impl App {
     fn stop_playing(&mut self) {
         let playing_music_index = self.playing_list.playing_music_index;
         if playing_music_index != -1 {
+            self.remember_playing_position();
             self.musichandle.stop();
-            self.musichandle.set_time_played(0);
             self.playing_list.items[playing_music_index as usize].status =
                 StatusOfPlayingItem::Waiting;
         }
         self.playing_list.playing_music_index = -1;
+        self.playing_list.playing_track_id = None;
+        self.playing_list.queued_music = None;
+        self.playing_list.queue_request = None;
         // self.start_time_of_music = None;
     }
 
     fn playing_next_music(&mut self) {
-        let playing_music_index = self.playing_list.playing_music_index;
-        if !self.musichandle.is_empty() {
-            if playing_music_index != -1 {
-                self.musichandle.stop();
-                self.playing_list.items[playing_music_index as usize].status =
-                    StatusOfPlayingItem::Waiting;
-            }
+        let len = self.playing_list.items.len();
+        if len == 0 {
+            return;
         }
-
-        let mut next_index = playing_music_index + 1;
-        if playing_music_index != -1 {
-            if playing_music_index as i64 == self.playing_list.items.len() as i64 - 1 {
-                next_index = 0;
-            }
-
-            self.musichandle.play_new(
-                self.playing_list.items[next_index as usize]
-                    .path_of_music
-                    .clone(),
-            );
-            self.playing_list.items[playing_music_index as usize].status =
-                StatusOfPlayingItem::Waiting;
-            self.playing_list.items[next_index as usize].status = StatusOfPlayingItem::Playing;
-            self.playing_list.playing_music_index = next_index;
+        let playing_music_index = self.playing_list.playing_music_index;
+        // from the top when nothing plays
+        let index = if playing_music_index == -1 {
+            len - 1
         } else {
-            next_index = 0;
-            self.musichandle.play_new(
-                self.playing_list.items[next_index as usize]
-                    .path_of_music
-                    .clone(),
-            );
-            self.playing_list.items[next_index as usize].status = StatusOfPlayingItem::Playing;
-            self.playing_list.playing_music_index = next_index;
+            playing_music_index as usize
+        };
+        match self.next_playable_index(index) {
+            Some(next_index) => self.play_music_at(next_index),
+            None => self.status_message = "No playable song in the list".to_string(),
         }
     }
 
@@ -531,6 +1019,7 @@ impl App {
             if music_item.num_added == 0 {
                 music_item.status = StatusOfMusicFile::NotAdded;
             }
+            self.queue_next_music();
         }
     }
     fn remove_fast(&mut self) {
@@ -560,7 +1049,194 @@ impl App {
             if music_item.num_added == 0 {
                 music_item.status = StatusOfMusicFile::NotAdded;
             }
+            self.queue_next_music();
+        }
+    }
+
+    fn seek_playing_music(&mut self, offset: i64) {
+        if self.playing_list.playing_music_index == -1 {
+            return;
+        }
+        self.musichandle.seek_relative(offset);
+    }
+
+    fn start_jump_input(&mut self) {
+        if self.playing_list.playing_music_index != -1 {
+            self.jump_input.clear();
+            self.inputmode = InputMode::Jump;
+        }
+    }
+
+    fn jump_to_typed_position(&mut self) {
+        let playing_music_index = self.playing_list.playing_music_index;
+        if playing_music_index == -1 {
+            return;
+        }
+        let Some(t) = parse_time(&self.jump_input) else {
+            self.status_message = format!("Invalid position: {}", self.jump_input);
+            return;
+        };
+        let length = self.playing_list.items[playing_music_index as usize].length;
+        if length != 0 && t > length {
+            self.status_message =
+                format!("Position {} is beyond the end of the song", self.jump_input);
+            return;
+        }
+        self.musichandle.seek_to(Duration::from_secs(t as u64));
+    }
+
+    // The chapters of the playing song, empty when it has none.
+    pub fn playing_chapters(&self) -> &[Chapter] {
+        let playing_music_index = self.playing_list.playing_music_index;
+        if playing_music_index == -1 {
+            return &[];
         }
+        &self.playing_list.items[playing_music_index as usize].chapters
+    }
+
+    pub fn current_chapter(&self) -> Option<usize> {
+        chapter_at(self.playing_chapters(), self.musichandle.time_played())
+    }
+
+    fn open_chapter_list(&mut self) {
+        if self.playing_chapters().is_empty() {
+            self.status_message = "The playing song has no chapters".to_string();
+            return;
+        }
+        self.chapter_list_state.select(self.current_chapter());
+        self.inputmode = InputMode::Chapters;
+    }
+
+    fn seek_to_chapter(&mut self, index: usize) {
+        let Some(chapter) = self.playing_chapters().get(index) else {
+            return;
+        };
+        self.musichandle.seek_to(chapter.start);
+        self.chapter_list_state.select(Some(index));
+    }
+
+    fn next_chapter(&mut self) {
+        let next = self.current_chapter().map_or(0, |i| i + 1);
+        if next < self.playing_chapters().len() {
+            self.seek_to_chapter(next);
+        }
+    }
+
+    fn previous_chapter(&mut self) {
+        let Some(current) = self.current_chapter() else {
+            return;
+        };
+        let into_chapter = self
+            .musichandle
+            .time_played()
+            .saturating_sub(self.playing_chapters()[current].start);
+        if into_chapter.as_secs() >= CHAPTER_RESTART_SECS || current == 0 {
+            self.seek_to_chapter(current);
+        } else {
+            self.seek_to_chapter(current - 1);
+        }
+    }
+
+    fn report_seek_result(&mut self, result: Result<(), SeekError>) {
+        self.status_message = match result {
+            Ok(()) => String::new(),
+            Err(SeekError::NotSupported { .. }) => {
+                "Seeking is not supported for this format".to_string()
+            }
+            Err(SeekError::Other(e)) => format!("Seeking failed: {}", e),
+            Err(e) => e.to_string(),
+        };
+    }
+
+    fn change_crossfade(&mut self, seconds: i64) {
+        self.crossfade = self
+            .crossfade
+            .saturating_add_signed(seconds)
+            .min(MAX_CROSSFADE);
+        self.musichandle
+            .set_crossfade(Duration::from_secs(self.crossfade));
+    }
+
+    fn change_replay_gain_mode(&mut self) {
+        let mode = self.musichandle.replay_gain_mode().next();
+        self.musichandle.set_replay_gain_mode(mode);
+    }
+
+    // Scans the selected folder, or the folder of the selected file.
+    fn scan_loudness_of_selected(&mut self) {
+        if self.loudness_scan.is_some() {
+            self.status_message = "A loudness scan is already running".to_string();
+            return;
+        }
+        let music_list_display =
+            &self.musicfile_of_dir.file_lists_of_dir[self.file_list_index_current_display];
+        let Some(i) = music_list_display.state.selected() else {
+            return;
+        };
+        let selected = match &music_list_display.items[i].cue_track {
+            Some(track) => &track.file,
+            None => &music_list_display.items[i].info,
+        };
+        let folder = if selected.is_dir() {
+            selected.clone()
+        } else {
+            match selected.parent() {
+                Some(parent) => parent.to_path_buf(),
+                None => return,
+            }
+        };
+        self.loudness_scan = Some(loudness::spawn_scan(folder, self.scan_group_by));
+    }
+
+    fn update_loudness_scan(&mut self) {
+        let Some(scan) = &self.loudness_scan else {
+            return;
+        };
+        let mut progress = scan.lock().unwrap();
+        // leaves other messages alone while nothing moves
+        if !progress.changed {
+            return;
+        }
+        progress.changed = false;
+        self.status_message = if progress.finished {
+            format!(
+                "Loudness scan done: {} tagged, {} failed",
+                progress.tagged, progress.failed
+            )
+        } else {
+            format!(
+                "Scanning loudness [{}/{}] {}",
+                progress.analyzing, progress.total, progress.current
+            )
+        };
+        let finished = progress.finished;
+        drop(progress);
+        if finished {
+            self.loudness_scan = None;
+        }
+    }
+
+    fn save_eq_preset(&mut self) {
+        self.status_message = match self.equalizer.save_preset(&self.preset_name_input) {
+            Ok(()) => format!("Saved preset \"{}\"", self.equalizer.preset_name()),
+            Err(e) => e,
+        };
+    }
+
+    fn toggle_eq_auto_genre(&mut self) {
+        self.equalizer.toggle_auto_genre();
+        self.apply_genre_preset();
+    }
+
+    fn apply_genre_preset(&mut self) {
+        let playing_music_index = self.playing_list.playing_music_index;
+        if playing_music_index == -1 {
+            return;
+        }
+        let genre = self.playing_list.items[playing_music_index as usize]
+            .genre
+            .as_deref();
+        self.equalizer.apply_genre(genre);
     }
 
     fn change_playing_mod(&mut self) {
@@ -570,130 +1246,564 @@ impl App {
             PlayingMod::Random => PlayingMod::Manual,
             PlayingMod::Manual => PlayingMod::Auto,
         };
+        self.queue_next_music();
     }
 
-    pub fn is_stop(&mut self) -> bool {
-        if self.musichandle.is_empty() && self.playing_list.playing_music_index != -1 {
-            true
-        } else {
-            false
-        }
+    pub fn is_stop(&self) -> bool {
+        self.musichandle.is_empty() && self.playing_list.playing_music_index != -1
     }
 
-    pub fn handle_stop_music(&mut self) {
+    fn handle_stop_music(&mut self) {
         let playing_music_index = self.playing_list.playing_music_index;
         self.playing_list.items[playing_music_index as usize].status = StatusOfPlayingItem::Stop;
+        // played to the end
+        self.forget_position(playing_music_index as usize);
     }
 
-    pub fn song_progress(&mut self) -> f64 {
-        if self.musichandle.is_empty() && self.playing_list.items.len() == 0 {
+    pub fn song_progress(&self) -> f64 {
+        let playing_music_index = self.playing_list.playing_music_index;
+        if self.musichandle.is_empty() || playing_music_index == -1 {
+            return 0.0;
+        }
+        let length = self.playing_list.items[playing_music_index as usize].length;
+        if length == 0 {
             0.0
-        } else if !self.musichandle.is_empty() && self.playing_list.playing_music_index != -1 {
-            let playing_music_index = self.playing_list.playing_music_index;
-            if self.playing_list.items[playing_music_index as usize].length == 0 {
-                0.0
-            } else {
-                f64::clamp(
-                    self.musichandle.time_played() as f64
-                        / self.playing_list.items[playing_music_index as usize].length as f64,
-                    0.0,
-                    1.0,
-                )
-            }
         } else {
-            match self.playing_list.playingmod {
-                PlayingMod::Auto => {
-                    self.auto_play();
-                    0.0
-                }
-                PlayingMod::Manual => 0.0,
-                PlayingMod::Repeat => {
-                    self.repeat_one_song();
-                    0.0
-                }
-                PlayingMod::Random => {
-                    self.random_song();
-                    0.0
-                }
-            }
+            f64::clamp(
+                self.musichandle.time_played().as_secs_f64() / length as f64,
+                0.0,
+                1.0,
+            )
         }
     }
+
+    // Once nothing plays the playing mod picks what comes next.
+    fn continue_playing(&mut self) {
+        if !self.musichandle.is_empty() || self.playing_list.items.is_empty() || self.music_halted()
+        {
+            return;
+        }
+        match self.playing_list.playingmod {
+            PlayingMod::Auto => self.auto_play(),
+            PlayingMod::Manual => {}
+            PlayingMod::Repeat => self.repeat_one_song(),
+            PlayingMod::Random => self.random_song(),
+        }
+    }
+
     fn auto_play(&mut self) {
         // thread::sleep(Duration::from_millis(250));
-        if self.musichandle.is_empty() && self.playing_list.items.len() != 0 {
-            self.musichandle.set_time_played(0);
+        if self.musichandle.is_empty() && self.has_playable_music() {
             self.playing_next_music();
         }
     }
 
     fn repeat_one_song(&mut self) {
-        if self.musichandle.is_empty() && self.playing_list.items.len() != 0 {
-            self.musichandle.set_time_played(0);
+        if self.musichandle.is_empty() && self.has_playable_music() {
             self.playing_same_music();
         }
     }
 
     fn random_song(&mut self) {
-        if self.musichandle.is_empty() && self.playing_list.items.len() != 0 {
-            self.musichandle.set_time_played(0);
+        if self.musichandle.is_empty() && self.has_playable_music() {
             self.playing_random_music();
         }
     }
 
     fn playing_same_music(&mut self) {
         let playing_music_index = self.playing_list.playing_music_index;
-        if !self.musichandle.is_empty() {
-            if playing_music_index != -1 {
-                self.musichandle.stop();
-                self.playing_list.items[playing_music_index as usize].status =
-                    StatusOfPlayingItem::Waiting;
-            }
+        let index = if playing_music_index == -1 {
+            0
+        } else {
+            playing_music_index as usize
+        };
+        self.play_music_at(index);
+    }
+
+    fn playing_random_music(&mut self) {
+        if let Some(next_index) = self.random_playable_index() {
+            self.play_music_at(next_index);
         }
+    }
 
-        let mut next_index = playing_music_index;
+    // Plays the song at `index`. Songs that can't be played are marked once
+    // the music handle found out, see `handle_player_event`.
+    fn play_music_at(&mut self, index: usize) {
+        self.remember_playing_position();
+        if self.halted {
+            self.halted = false;
+            self.musichandle.cancel_sleep_fade();
+        }
+        let playing_music_index = self.playing_list.playing_music_index;
         if playing_music_index != -1 {
-            self.musichandle.play_new(
-                self.playing_list.items[next_index as usize]
-                    .path_of_music
-                    .clone(),
-            );
-            self.playing_list.items[next_index as usize].status = StatusOfPlayingItem::Playing;
-        } else {
-            next_index = 0;
-            self.musichandle.play_new(
-                self.playing_list.items[next_index as usize]
-                    .path_of_music
-                    .clone(),
+            let status = &mut self.playing_list.items[playing_music_index as usize].status;
+            if !matches!(status, StatusOfPlayingItem::Error) {
+                *status = StatusOfPlayingItem::Waiting;
+            }
+        }
+        self.playing_list.playing_music_index = index as i64;
+        let item = &self.playing_list.items[index];
+        let position = self.resume_position_of(index);
+        let id = self
+            .musichandle
+            .play_new(item.path_of_music.clone(), item.section(), position);
+        self.playing_list.playing_track_id = Some(id);
+        self.playing_list.items[index].status = StatusOfPlayingItem::Playing;
+        self.queue_next_music();
+    }
+
+    fn music_started(&mut self) {
+        let index = self.playing_list.playing_music_index as usize;
+        let position = self.resume_position_of(index);
+        if !position.is_zero() {
+            let secs = position.as_secs();
+            self.status_message = format!(
+                "Resuming at {}:{:02}:{:02}",
+                secs / 3600,
+                secs / 60 % 60,
+                secs % 60
             );
-            self.playing_list.items[next_index as usize].status = StatusOfPlayingItem::Playing;
-            self.playing_list.playing_music_index = next_index;
         }
+        let genre = self.playing_list.items[index].genre.as_deref();
+        self.equalizer.apply_genre(genre);
     }
 
-    fn playing_random_music(&mut self) {
+    // Depending on the playing mod, the next playable song is tried instead.
+    fn playing_music_failed(&mut self, error: String) {
+        let index = self.playing_list.playing_music_index as usize;
+        self.mark_unplayable(index, error);
+        // what played before is still playing, it must not be taken for this song
+        self.playing_list.playing_music_index = -1;
+        self.playing_list.playing_track_id = None;
+        self.playing_list.queued_music = None;
+        self.playing_list.queue_request = None;
+        let skip_to = match self.playing_list.playingmod {
+            PlayingMod::Auto => self.next_playable_index(index),
+            PlayingMod::Random => self.random_playable_index(),
+            PlayingMod::Repeat | PlayingMod::Manual => None,
+        };
+        match skip_to {
+            Some(next_index) => self.play_music_at(next_index),
+            None => self.musichandle.stop(),
+        }
+    }
+
+    // Track changes and ticks need nothing here, `update` follows every event.
+    fn handle_player_event(&mut self, event: PlayerEvent) {
+        let is_playing = |id| self.playing_list.playing_track_id == Some(id);
+        let is_requested = |id| matches!(self.playing_list.queue_request, Some((r, _)) if r == id);
+        match event {
+            PlayerEvent::Started(id) if is_playing(id) => self.music_started(),
+            PlayerEvent::Failed(id, e) if is_playing(id) => self.playing_music_failed(e),
+            PlayerEvent::Queued(id) if is_requested(id) => {
+                self.playing_list.queued_music = self.playing_list.queue_request.take();
+            }
+            PlayerEvent::NotQueued(id) if is_requested(id) => {
+                self.playing_list.queue_request = None;
+            }
+            PlayerEvent::Failed(id, e) if is_requested(id) => {
+                let Some((_, index)) = self.playing_list.queue_request.take() else {
+                    return;
+                };
+                // the playing song or one already given up on would just fail
+                // again, give up on queueing
+                if index as i64 == self.playing_list.playing_music_index || !self.is_playable(index)
+                {
+                    let name = self.playing_list.items[index].name();
+                    self.status_message = format!("Can't queue {}: {}", name, e);
+                    return;
+                }
+                // queue the one after it instead
+                self.mark_unplayable(index, e);
+                self.queue_next_music();
+            }
+            PlayerEvent::QueueCleared(after) if is_playing(after) => {
+                self.playing_list.queued_music = None;
+            }
+            PlayerEvent::Seeked(result) => self.report_seek_result(result),
+            _ => {}
+        }
+    }
+
+    // Where the song at `index` was left off, zero plays it from the start.
+    fn resume_position_of(&self, index: usize) -> Duration {
+        let key = self.playing_list.items[index].resume_key();
+        self.resume.get(&key).unwrap_or_default()
+    }
+
+    // Writes down how far the playing song got, if it is long enough to be
+    // worth resuming.
+    fn remember_playing_position(&mut self) {
         let playing_music_index = self.playing_list.playing_music_index;
-        if !self.musichandle.is_empty() {
-            if playing_music_index != -1 {
-                self.musichandle.stop();
-                self.playing_list.items[playing_music_index as usize].status =
-                    StatusOfPlayingItem::Waiting;
+        // nothing to remember while it is still being opened
+        if playing_music_index == -1
+            || self.musichandle.current_track_id() != self.playing_list.playing_track_id
+        {
+            return;
+        }
+        let item = &self.playing_list.items[playing_music_index as usize];
+        let length = Duration::from_secs(item.length as u64);
+        if !self.resume.applies_to(&item.path_of_music, length) {
+            return;
+        }
+        let position = self.musichandle.time_played();
+        if self.resume.update(item.resume_key(), position, length) {
+            self.save_resume_positions();
+        }
+    }
+
+    fn forget_position(&mut self, index: usize) {
+        if self
+            .resume
+            .forget(&self.playing_list.items[index].resume_key())
+        {
+            self.save_resume_positions();
+        }
+    }
+
+    // Keeps the position up to date while playing, in case the player is
+    // not quit the normal way.
+    fn save_resume_position_now_and_then(&mut self) {
+        if self.last_resume_save.elapsed() < RESUME_SAVE_INTERVAL {
+            return;
+        }
+        self.last_resume_save = Instant::now();
+        if !self.musichandle.is_paused() {
+            self.remember_playing_position();
+        }
+    }
+
+    fn save_resume_positions(&mut self) {
+        if let Err(e) = self.resume.save() {
+            self.status_message = format!("Can't save resume positions: {}", e);
+        }
+        self.refresh_resume_positions();
+    }
+
+    // Marks what was left off in the file lists and the playing list.
+    fn refresh_resume_positions(&mut self) {
+        for list in self.musicfile_of_dir.file_lists_of_dir.iter_mut() {
+            for item in list.items.iter_mut() {
+                item.resume_position = self.resume.get(&item.resume_key());
             }
         }
+        for item in self.playing_list.items.iter_mut() {
+            item.resume_position = self.resume.get(&item.resume_key());
+        }
+    }
+
+    fn mark_unplayable(&mut self, index: usize, error: String) {
+        let item = &mut self.playing_list.items[index];
+        item.status = StatusOfPlayingItem::Error;
+        self.status_message = format!("Can't play {}: {}", item.name(), error);
+    }
+
+    fn is_playable(&self, index: usize) -> bool {
+        !matches!(
+            self.playing_list.items[index].status,
+            StatusOfPlayingItem::Error
+        )
+    }
+
+    // Keeps the automatic modes from trying a list of broken files forever.
+    fn has_playable_music(&self) -> bool {
+        (0..self.playing_list.items.len()).any(|i| self.is_playable(i))
+    }
+
+    fn next_playable_index(&self, index: usize) -> Option<usize> {
+        let len = self.playing_list.items.len();
+        (1..=len)
+            .map(|offset| (index + offset) % len)
+            .find(|&i| self.is_playable(i))
+    }
 
-        let upper_bound = self.playing_list.items.len();
+    fn random_playable_index(&self) -> Option<usize> {
+        let playable: Vec<usize> = (0..self.playing_list.items.len())
+            .filter(|&i| self.is_playable(i))
+            .collect();
+        if playable.is_empty() {
+            return None;
+        }
         let mut rng = rand::thread_rng();
-        let next_index = rand::Rng::gen_range(&mut rng, 0..upper_bound);
+        Some(playable[rand::Rng::gen_range(&mut rng, 0..playable.len())])
+    }
+
+    fn next_music_index(&self) -> Option<usize> {
+        if self.stop_after_current || self.sleep_after_current() {
+            return None;
+        }
+        self.following_music_index()
+    }
+
+    // The song the playing mod goes on with, the sleep timer aside.
+    fn following_music_index(&self) -> Option<usize> {
+        let playing_music_index = self.playing_list.playing_music_index;
+        let len = self.playing_list.items.len();
+        if playing_music_index == -1 || len == 0 {
+            return None;
+        }
+        match self.playing_list.playingmod {
+            PlayingMod::Auto => self.next_playable_index(playing_music_index as usize),
+            // a song that just failed would fail again
+            PlayingMod::Repeat => {
+                Some(playing_music_index as usize).filter(|&index| self.is_playable(index))
+            }
+            PlayingMod::Random => self.random_playable_index(),
+            PlayingMod::Manual => None,
+        }
+    }
+
+    // Hands the song that should follow the playing one to the music handle,
+    // so it starts without a gap. Needs to be called again whenever the
+    // playing list or the playing mod changes.
+    fn queue_next_music(&mut self) {
+        let Some(playing_track_id) = self.playing_list.playing_track_id else {
+            return;
+        };
+        match self.next_music_index() {
+            Some(next_index) => {
+                let playing_item =
+                    &self.playing_list.items[self.playing_list.playing_music_index as usize];
+                let next_item = &self.playing_list.items[next_index];
+                // fading between the songs of one album would break gapless albums
+                let same_album =
+                    playing_item.album.is_some() && playing_item.album == next_item.album;
+                let path = next_item.path_of_music.clone();
+                let section = next_item.section();
+                // repeating a song starts it over
+                let position = if next_item.resume_key() == playing_item.resume_key() {
+                    Duration::ZERO
+                } else {
+                    self.resume_position_of(next_index)
+                };
+                let id = self.musichandle.enqueue(
+                    path,
+                    section,
+                    position,
+                    playing_track_id,
+                    !same_album,
+                );
+                self.playing_list.queue_request = Some((id, next_index));
+            }
+            None => {
+                self.playing_list.queue_request = None;
+                self.musichandle.clear_queue(playing_track_id);
+            }
+        }
+    }
+
+    // Follows the music handle when it moved on to the queued song by itself.
+    fn sync_playing_music(&mut self) {
+        let current_track_id = self.musichandle.current_track_id();
+        if current_track_id.is_none() || current_track_id == self.playing_list.playing_track_id {
+            return;
+        }
+        let Some((queued_id, queued_index)) = self.playing_list.queued_music.take() else {
+            return;
+        };
+        if Some(queued_id) != current_track_id || queued_index >= self.playing_list.items.len() {
+            return;
+        }
 
+        let playing_music_index = self.playing_list.playing_music_index;
         if playing_music_index != -1 {
             self.playing_list.items[playing_music_index as usize].status =
                 StatusOfPlayingItem::Waiting;
+            // played to the end
+            self.forget_position(playing_music_index as usize);
         }
-        self.musichandle.play_new(
-            self.playing_list.items[next_index as usize]
-                .path_of_music
-                .clone(),
-        );
-        self.playing_list.items[next_index as usize].status = StatusOfPlayingItem::Playing;
-        self.playing_list.playing_music_index = next_index as i64;
+        if let Some(SleepTimer::Songs(count)) = &mut self.sleep_timer {
+            *count = count.saturating_sub(1);
+        }
+        self.playing_list.items[queued_index].status = StatusOfPlayingItem::Playing;
+        self.playing_list.playing_music_index = queued_index as i64;
+        self.playing_list.playing_track_id = current_track_id;
+        self.apply_genre_preset();
+        self.queue_next_music();
+    }
+
+    // Reads the sleep timer prompt: minutes, `t` for the end of the playing
+    // song, `3t` for after three songs, `a` for the end of the album. Empty
+    // or `0` turns the timer off.
+    fn set_sleep_timer(&mut self) {
+        let input = self.sleep_input.trim().to_string();
+        let timer = if input.is_empty() || input == "0" {
+            None
+        } else if input == "a" {
+            let playing_music_index = self.playing_list.playing_music_index;
+            if playing_music_index == -1 {
+                self.status_message = "Nothing is playing".to_string();
+                return;
+            }
+            let album = self.playing_list.items[playing_music_index as usize]
+                .album
+                .clone();
+            Some(SleepTimer::EndOfAlbum(album))
+        } else if let Some(count) = input.strip_suffix('t') {
+            match count.parse::<u32>() {
+                Ok(count) if count > 0 => Some(SleepTimer::Songs(count)),
+                Err(_) if count.is_empty() => Some(SleepTimer::Songs(1)),
+                _ => {
+                    self.status_message = format!("Invalid sleep timer: {}", input);
+                    return;
+                }
+            }
+        } else {
+            let at = input
+                .parse::<u64>()
+                .ok()
+                .filter(|&minutes| minutes <= MAX_SLEEP_MINUTES)
+                .and_then(|minutes| Instant::now().checked_add(Duration::from_secs(minutes * 60)));
+            match at {
+                Some(at) => Some(SleepTimer::At(at)),
+                None => {
+                    self.status_message = format!("Invalid sleep timer: {}", input);
+                    return;
+                }
+            }
+        };
+        self.status_message = match timer {
+            Some(_) => "Sleep timer set".to_string(),
+            None => "Sleep timer off".to_string(),
+        };
+        self.sleep_timer = timer;
+        self.musichandle.cancel_sleep_fade();
+        // the last song must not be followed by another one
+        self.queue_next_music();
+    }
+
+    // Whether the sleep timer stops the music when the playing song ends.
+    fn sleep_after_current(&self) -> bool {
+        match &self.sleep_timer {
+            Some(SleepTimer::Songs(count)) => *count <= 1,
+            Some(SleepTimer::EndOfAlbum(album)) => {
+                album.is_none()
+                    || self
+                        .following_music_index()
+                        .is_none_or(|i| self.playing_list.items[i].album != *album)
+            }
+            _ => false,
+        }
+    }
+
+    // How long until the sleep timer stops the music, if that is known.
+    pub fn sleep_time_left(&self) -> Option<Duration> {
+        match &self.sleep_timer {
+            Some(SleepTimer::At(end)) => Some(end.saturating_duration_since(Instant::now())),
+            Some(SleepTimer::Songs(_) | SleepTimer::EndOfAlbum(_))
+                if self.sleep_after_current() =>
+            {
+                let playing_music_index = self.playing_list.playing_music_index;
+                if playing_music_index == -1 || self.musichandle.is_empty() {
+                    return None;
+                }
+                let length = self.playing_list.items[playing_music_index as usize].length;
+                if length == 0 {
+                    return None;
+                }
+                let left = Duration::from_secs(length as u64)
+                    .saturating_sub(self.musichandle.time_played());
+                Some(left.div_f32(self.musichandle.speed()))
+            }
+            _ => None,
+        }
+    }
+
+    // Fades the music out over the last minute and stops it when the time
+    // is up. Songs ending stop it in `music_halted`.
+    fn update_sleep_timer(&mut self) {
+        let Some(left) = self.sleep_time_left() else {
+            return;
+        };
+        if left.is_zero() && matches!(self.sleep_timer, Some(SleepTimer::At(_))) {
+            self.stop_playing();
+            self.expire_sleep_timer();
+            return;
+        }
+        if left <= SLEEP_FADE {
+            self.musichandle.fade_to_sleep(left);
+        } else {
+            // a seek back out of the last minute
+            self.musichandle.cancel_sleep_fade();
+        }
+    }
+
+    // Whether the music was stopped on purpose, then nothing starts by
+    // itself. Called once the playing song ended without a next one.
+    fn music_halted(&mut self) -> bool {
+        if self.playing_list.playing_music_index != -1 {
+            if self.stop_after_current {
+                self.stop_after_current = false;
+                self.halted = true;
+                self.status_message = "Stopped after the song".to_string();
+            }
+            if self.sleep_after_current() {
+                self.expire_sleep_timer();
+            }
+        }
+        self.halted
+    }
+
+    fn expire_sleep_timer(&mut self) {
+        // the fade stays until something is played again
+        self.sleep_timer = None;
+        self.halted = true;
+        self.status_message = "Sleep timer stopped the music".to_string();
+    }
+
+    // Threshold and makeup move by 1 dB, the ratio by 0.5.
+    fn change_compressor(&mut self, step: f32) {
+        let mut settings = self.musichandle.compressor();
+        match self.compressor_row {
+            0 => settings.threshold += step,
+            1 => settings.ratio += step * 0.5,
+            _ => settings.makeup += step,
+        }
+        self.musichandle.set_compressor(settings.clamped());
+    }
+
+    fn reset_compressor_setting(&mut self) {
+        let mut settings = self.musichandle.compressor();
+        let default = CompressorSettings::default();
+        match self.compressor_row {
+            0 => settings.threshold = default.threshold,
+            1 => settings.ratio = default.ratio,
+            _ => settings.makeup = default.makeup,
+        }
+        self.musichandle.set_compressor(settings);
+    }
+
+    fn toggle_stop_after_current(&mut self) {
+        self.stop_after_current = !self.stop_after_current;
+        self.queue_next_music();
+    }
+}
+
+// ss, mm:ss or hh:mm:ss
+fn parse_time(input: &str) -> Option<u32> {
+    if input.split(':').count() > 3 {
+        return None;
+    }
+    input.split(':').try_fold(0u32, |acc, part| {
+        acc.checked_mul(60)?.checked_add(part.parse::<u32>().ok()?)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typed_positions_are_seconds_minutes_and_hours() {
+        assert_eq!(parse_time("42"), Some(42));
+        assert_eq!(parse_time("1:02"), Some(62));
+        assert_eq!(parse_time("1:00:05"), Some(3605));
+        // minutes and seconds may run over
+        assert_eq!(parse_time("90"), Some(90));
+        assert_eq!(parse_time("1:0:0:0"), None);
+        assert_eq!(parse_time("1:0:0:0:0:0:0"), None);
+        assert_eq!(parse_time("4294967295:00"), None);
+        assert_eq!(parse_time("1:-2"), None);
+        assert_eq!(parse_time(""), None);
     }
 }
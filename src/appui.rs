@@ -8,8 +8,12 @@ use ratatui::{
     },
 };
 
+use crate::analyzer::{Spectrum, FLOOR_DB, SPECTRUM_BANDS};
 use crate::app::Musicfile;
-use crate::app::{App, PlayingItem, StatusOfMusicFile, StatusOfPlayingItem};
+use crate::channels::ChannelMode;
+use crate::compressor::{CompressorSettings, MAX_MAKEUP, MAX_RATIO, MIN_THRESHOLD};
+use crate::equalizer::{BAND_FREQUENCIES, MAX_GAIN};
+use crate::app::{App, InputMode, PlayingItem, SleepTimer, StatusOfMusicFile, StatusOfPlayingItem};
 
 const SELECTED_STYLE: Style = Style::new()
     .bg(Color::Rgb(143, 188, 187))
@@ -17,10 +21,11 @@ const SELECTED_STYLE: Style = Style::new()
     .add_modifier(Modifier::BOLD);
 
 const TODO_COLRO: ratatui::prelude::Color = Color::Rgb(143, 188, 187);
+const LOOP_COLOR: Color = Color::Rgb(235, 203, 139);
 
 impl Widget for &mut App {
     fn render(self, area: Rect, buf: &mut Buffer) {
-        let [_, main_area, _] = Layout::vertical([
+        let [_, main_area, status_area] = Layout::vertical([
             Constraint::Length(1),
             Constraint::Fill(1),
             Constraint::Length(1),
@@ -29,17 +34,31 @@ impl Widget for &mut App {
 
         let [left_area, right_area] =
             Layout::horizontal([Constraint::Fill(1), Constraint::Fill(3)]).areas(main_area);
+        let playing_height = if self.musichandle.visualizer_visible() {
+            9
+        } else {
+            5
+        };
         let [top_right_area, bottom_right_area] =
-            Layout::vertical([Constraint::Fill(3), Constraint::Length(5)]).areas(right_area);
+            Layout::vertical([Constraint::Fill(3), Constraint::Length(playing_height)])
+                .areas(right_area);
 
         match self.apptab {
             crate::app::AppTab::Music => {
                 self.render_music_list(left_area, buf);
-                self.render_playing_list(top_right_area, buf);
+                match self.inputmode {
+                    InputMode::Equalizer | InputMode::PresetName => {
+                        self.render_equalizer(top_right_area, buf)
+                    }
+                    InputMode::Chapters => self.render_chapters(top_right_area, buf),
+                    InputMode::Compressor => self.render_compressor(top_right_area, buf),
+                    _ => self.render_playing_list(top_right_area, buf),
+                }
                 self.draw_playing_music(bottom_right_area, buf);
             }
             crate::app::AppTab::Helper => self.helper(main_area, buf),
         }
+        self.render_status_line(status_area, buf);
     }
 }
 
@@ -59,8 +78,7 @@ impl App {
         let items: Vec<ListItem> = music_list_display
             .items
             .iter()
-            .enumerate()
-            .map(|(_, todo_item)| ListItem::from(todo_item))
+            .map(ListItem::from)
             .collect();
 
         let list = List::new(items)
@@ -85,16 +103,177 @@ impl App {
             .border_type(BorderType::Rounded)
             .fg(Color::Rgb(143, 188, 187));
 
-        if self.is_stop() {
-            self.handle_stop_music();
+        let items: Vec<ListItem> = self.playing_list.items.iter().map(ListItem::from).collect();
+
+        let list = List::new(items)
+            .block(block)
+            .highlight_style(SELECTED_STYLE)
+            .highlight_spacing(HighlightSpacing::Always);
+
+        StatefulWidget::render(list, area, buf, &mut self.playing_list.state);
+
+        // let inner_rect = Rect::new(area.x + 1, area.y + 1, area.width - 2, area.height - 2);
+    }
+
+    fn render_equalizer(&mut self, area: Rect, buf: &mut Buffer) {
+        let equalizer = &self.equalizer;
+        let title = format!(
+            "Equalizer | {} | {} | Auto Genre {} ",
+            if equalizer.enabled { "On" } else { "Off" },
+            equalizer.preset_name(),
+            if equalizer.auto_genre { "On" } else { "Off" },
+        );
+        let block = Block::new()
+            .title(Line::raw(title).centered())
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded)
+            .fg(Color::Rgb(143, 188, 187));
+        let inner = block.inner(area);
+        block.render(area, buf);
+
+        // one horizontal slider per band with 0 dB in the middle
+        let half_width = (inner.width.saturating_sub(20) / 2) as f32;
+        let lines: Vec<Line> = BAND_FREQUENCIES
+            .iter()
+            .zip(equalizer.gains)
+            .enumerate()
+            .map(|(band, (freq, gain))| {
+                let label = if *freq >= 1000.0 {
+                    format!("{:>3}kHz", freq / 1000.0)
+                } else {
+                    format!("{:>4}Hz", freq)
+                };
+                let filled = (gain.abs() / MAX_GAIN * half_width).round() as usize;
+                let empty = half_width as usize - filled;
+                let slider = if gain < 0.0 {
+                    format!(
+                        "{}{}│{}",
+                        " ".repeat(empty),
+                        "━".repeat(filled),
+                        " ".repeat(half_width as usize)
+                    )
+                } else {
+                    format!(
+                        "{}│{}{}",
+                        " ".repeat(half_width as usize),
+                        "━".repeat(filled),
+                        " ".repeat(empty)
+                    )
+                };
+                let style = if band == equalizer.selected_band {
+                    SELECTED_STYLE
+                } else {
+                    Style::default().fg(Color::Rgb(216, 222, 233))
+                };
+                Line::styled(format!(" {} {} {:+3.0} dB", label, slider, gain), style)
+            })
+            .collect();
+        for (line, y) in lines.into_iter().zip(inner.y..inner.bottom()) {
+            line.render(Rect::new(inner.x, y, inner.width, 1), buf);
         }
+    }
 
-        let items: Vec<ListItem> = self
-            .playing_list
-            .items
+    fn render_compressor(&mut self, area: Rect, buf: &mut Buffer) {
+        let settings = self.musichandle.compressor();
+        let title = format!(
+            "Compressor | {} | {} ",
+            if settings.enabled { "On" } else { "Off" },
+            compressor_name(&settings),
+        );
+        let block = Block::new()
+            .title(Line::raw(title).centered())
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded)
+            .fg(Color::Rgb(143, 188, 187));
+        let inner = block.inner(area);
+        block.render(area, buf);
+
+        // sliders fill from the left, the threshold from its lowest setting
+        let width = inner.width.saturating_sub(30) as usize;
+        let slider = |fraction: f32| {
+            let filled = (fraction.clamp(0.0, 1.0) * width as f32).round() as usize;
+            format!("{}{}", "━".repeat(filled), " ".repeat(width - filled))
+        };
+        let rows = [
+            (
+                "Threshold",
+                slider(1.0 - settings.threshold / MIN_THRESHOLD),
+                format!("{:+3.0} dB", settings.threshold),
+            ),
+            (
+                "Ratio",
+                slider((settings.ratio - 1.0) / (MAX_RATIO - 1.0)),
+                format!("{:.1}:1", settings.ratio),
+            ),
+            (
+                "Makeup",
+                slider(settings.makeup / MAX_MAKEUP),
+                format!("{:+3.0} dB", settings.makeup),
+            ),
+        ];
+        let mut lines: Vec<Line> = rows
+            .into_iter()
+            .enumerate()
+            .map(|(row, (label, slider, value))| {
+                let style = if row == self.compressor_row {
+                    SELECTED_STYLE
+                } else {
+                    Style::default().fg(Color::Rgb(216, 222, 233))
+                };
+                Line::styled(format!(" {:<10} {} {}", label, slider, value), style)
+            })
+            .collect();
+        let reduction = self.musichandle.gain_reduction();
+        lines.push(Line::raw(""));
+        lines.push(Line::styled(
+            format!(
+                " {:<10} {} {:5.1} dB",
+                "Reduction",
+                slider(reduction / MAX_MAKEUP),
+                reduction
+            ),
+            Style::default().fg(LOOP_COLOR),
+        ));
+        for (line, y) in lines.into_iter().zip(inner.y..inner.bottom()) {
+            line.render(Rect::new(inner.x, y, inner.width, 1), buf);
+        }
+    }
+
+    fn render_chapters(&mut self, area: Rect, buf: &mut Buffer) {
+        let chapters = self.playing_chapters();
+        let current = self.current_chapter();
+        let title = format!("Chapters | {} Chapters ", chapters.len());
+        let block = Block::new()
+            .title(Line::raw(title).centered())
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded)
+            .fg(Color::Rgb(143, 188, 187));
+
+        let items: Vec<ListItem> = chapters
             .iter()
             .enumerate()
-            .map(|(_i, todo_item)| ListItem::from(todo_item))
+            .map(|(i, chapter)| {
+                let start = chapter.start.as_secs();
+                let text = format!(
+                    "{:>3} {:>2}:{:02}:{:02} {}",
+                    i + 1,
+                    start / 3600,
+                    start / 60 % 60,
+                    start % 60,
+                    chapter.title
+                );
+                if current == Some(i) {
+                    ListItem::new(Line::styled(
+                        format!(" {}", text),
+                        Color::Rgb(143, 188, 187),
+                    ))
+                } else {
+                    ListItem::new(Line::styled(
+                        format!("  {}", text),
+                        Color::Rgb(216, 222, 233),
+                    ))
+                }
+            })
             .collect();
 
         let list = List::new(items)
@@ -102,9 +281,7 @@ impl App {
             .highlight_style(SELECTED_STYLE)
             .highlight_spacing(HighlightSpacing::Always);
 
-        StatefulWidget::render(list, area, buf, &mut self.playing_list.state);
-
-        // let inner_rect = Rect::new(area.x + 1, area.y + 1, area.width - 2, area.height - 2);
+        StatefulWidget::render(list, area, buf, &mut self.chapter_list_state);
     }
 
     fn draw_playing_music(&mut self, area: Rect, buf: &mut Buffer) {
@@ -132,12 +309,7 @@ impl App {
 
         let playing_music_index = self.playing_list.playing_music_index;
         let playing_music_name = if playing_music_index != -1 {
-            self.playing_list.items[playing_music_index as usize]
-                .path_of_music
-                .file_name()
-                .unwrap()
-                .to_string_lossy()
-                .to_string()
+            self.playing_list.items[playing_music_index as usize].name()
         } else {
             "".to_string()
         };
@@ -147,12 +319,12 @@ impl App {
             Style::default().fg(TODO_COLRO).add_modifier(Modifier::BOLD),
         ));
 
-        let play_dur = self.musichandle.time_played();
+        let play_dur = self.musichandle.time_played().as_secs();
 
         let total_dur = if playing_music_index == -1 {
             0
         } else {
-            self.playing_list.items[playing_music_index as usize].length
+            self.playing_list.items[playing_music_index as usize].length as u64
         };
         gauge_title.push(Span::styled(
             format!(
@@ -165,6 +337,47 @@ impl App {
             ),
             Style::default().fg(TODO_COLRO),
         ));
+        if self.stop_after_current {
+            gauge_title.push(Span::styled(
+                "Stop After This Song ",
+                Style::default().fg(LOOP_COLOR),
+            ));
+        }
+        let speed = self.musichandle.speed();
+        if speed != 1.0 {
+            // what is left of the song in real time at this speed
+            let remaining = (total_dur.saturating_sub(play_dur) as f32 / speed).round() as u64;
+            gauge_title.push(Span::styled(
+                format!("{:.1}x -{}m {}s ", speed, remaining / 60, remaining % 60),
+                Style::default().fg(TODO_COLRO),
+            ));
+        }
+        if let Some(current) = self.current_chapter() {
+            let chapters = self.playing_chapters();
+            gauge_title.push(Span::styled(
+                format!(
+                    "Ch {}/{} {} ",
+                    current + 1,
+                    chapters.len(),
+                    chapters[current].title
+                ),
+                Style::default().fg(TODO_COLRO),
+            ));
+        }
+        let (loop_start, loop_end) = self.musichandle.loop_markers();
+        if let Some(start) = loop_start {
+            let range = match loop_end {
+                Some(end) => format!("A-B {}-{} ", loop_time(start), loop_time(end)),
+                None => format!("A {} ", loop_time(start)),
+            };
+            gauge_title.push(Span::styled(range, Style::default().fg(LOOP_COLOR)));
+        }
+        if self.crossfade > 0 {
+            gauge_title.push(Span::styled(
+                format!("Crossfade {}s ", self.crossfade),
+                Style::default().fg(TODO_COLRO),
+            ));
+        }
         let volume = self.musichandle.get_volume();
         block_title.push(Span::styled(
             match volume {
@@ -180,6 +393,75 @@ impl App {
             format!("{:3.0}% ", volume * 100.0),
             Style::default().fg(TODO_COLRO),
         ));
+        block_title.push(Span::styled(
+            format!("RG {} ", self.musichandle.replay_gain_mode().name()),
+            Style::default().fg(TODO_COLRO),
+        ));
+        let channels = self.musichandle.channel_settings();
+        let mut channel_info = Vec::new();
+        if channels.mode != ChannelMode::Stereo {
+            channel_info.push(channels.mode.name().to_string());
+        }
+        if channels.balance != 0.0 {
+            let side = if channels.balance < 0.0 { "L" } else { "R" };
+            let percent = channels.balance.abs() * 100.0;
+            channel_info.push(format!("Bal {}{:.0}%", side, percent));
+        }
+        match self.musichandle.current_file_channels() {
+            Some(6) => channel_info.push("5.1 Downmix".to_string()),
+            Some(8) => channel_info.push("7.1 Downmix".to_string()),
+            Some(n) if n > 2 => channel_info.push(format!("{}ch Downmix", n)),
+            _ => {}
+        }
+        let crossfeed = self.musichandle.crossfeed();
+        if crossfeed.enabled {
+            channel_info.push(format!("Crossfeed {}", crossfeed.preset.name()));
+        }
+        if !channel_info.is_empty() {
+            block_title.push(Span::styled(
+                format!("{} ", channel_info.join(" ")),
+                Style::default().fg(TODO_COLRO),
+            ));
+        }
+        if self.equalizer.enabled {
+            block_title.push(Span::styled(
+                format!("EQ {} ", self.equalizer.preset_name()),
+                Style::default().fg(TODO_COLRO),
+            ));
+        }
+        let compressor = self.musichandle.compressor();
+        if compressor.enabled {
+            // a cell for every 2 dB the gain is lowered by
+            let reduction = self.musichandle.gain_reduction();
+            let cells = ((reduction / 2.0).round() as usize).min(8);
+            block_title.push(Span::styled(
+                format!(
+                    "{} {}{} ",
+                    compressor_name(&compressor),
+                    "■".repeat(cells),
+                    "·".repeat(8 - cells)
+                ),
+                Style::default().fg(TODO_COLRO),
+            ));
+        }
+        let sleep = match (&self.sleep_timer, self.sleep_time_left()) {
+            (None, _) => None,
+            (_, Some(left)) => Some(format!(
+                "Sleep {}:{:02}",
+                left.as_secs() / 60,
+                left.as_secs() % 60
+            )),
+            (Some(SleepTimer::Songs(1)), None) => Some("Sleep After This Song".to_string()),
+            (Some(SleepTimer::Songs(count)), None) => Some(format!("Sleep After {} Songs", count)),
+            (Some(SleepTimer::EndOfAlbum(_)), None) => Some("Sleep End Of Album".to_string()),
+            (Some(SleepTimer::At(_)), None) => None,
+        };
+        if let Some(sleep) = sleep {
+            block_title.push(Span::styled(
+                format!("{} ", sleep),
+                Style::default().fg(LOOP_COLOR),
+            ));
+        }
 
         let block = Block::default()
             .borders(Borders::ALL)
@@ -189,7 +471,17 @@ impl App {
             .title_alignment(Alignment::Center);
         block.render(area, buf);
 
-        let inner_rect = Rect::new(area.x + 1, area.y + 1, area.width - 2, area.height - 2);
+        let mut inner_rect = Rect::new(area.x + 1, area.y + 1, area.width - 2, area.height - 2);
+        if self.musichandle.visualizer_visible() {
+            let [left, spectrum_area] =
+                Layout::horizontal([Constraint::Fill(3), Constraint::Fill(2)]).areas(inner_rect);
+            let [gauge_area, levels_area] =
+                Layout::vertical([Constraint::Length(3), Constraint::Fill(1)]).areas(left);
+            let spectrum = self.musichandle.spectrum();
+            render_spectrum(&spectrum, spectrum_area, buf);
+            render_levels(&spectrum, levels_area, buf);
+            inner_rect = gauge_area;
+        }
         let gauge = Gauge::default()
             .block(
                 Block::default()
@@ -201,22 +493,78 @@ impl App {
             .gauge_style(Style::default().fg(TODO_COLRO))
             .label(Span::styled(label, Style::default().fg(TODO_COLRO)))
             .ratio(self.song_progress());
-        gauge.render(inner_rect, buf)
+        gauge.render(inner_rect, buf);
+
+        // the loop markers go on top of the bar, where their position is
+        let bar = Rect::new(
+            inner_rect.x + 1,
+            inner_rect.y + 1,
+            inner_rect.width.saturating_sub(2),
+            1,
+        );
+        if total_dur > 0 && bar.width > 0 {
+            // chapter ticks first, the loop markers win where they meet
+            for chapter in self.playing_chapters().iter().skip(1) {
+                let ratio = (chapter.start.as_secs_f64() / total_dur as f64).min(1.0);
+                let x = bar.x + ((bar.width - 1) as f64 * ratio).round() as u16;
+                let style = Style::default().fg(Color::Rgb(216, 222, 233));
+                buf.set_string(x, bar.y, "│", style);
+            }
+            for (marker, symbol) in [(loop_start, "A"), (loop_end, "B")] {
+                if let Some(t) = marker {
+                    let ratio = (t.as_secs_f64() / total_dur as f64).min(1.0);
+                    let x = bar.x + ((bar.width - 1) as f64 * ratio).round() as u16;
+                    buf.set_string(
+                        x,
+                        bar.y,
+                        symbol,
+                        Style::default()
+                            .fg(Color::Rgb(46, 52, 64))
+                            .bg(LOOP_COLOR)
+                            .add_modifier(Modifier::BOLD),
+                    );
+                }
+            }
+        }
+    }
+
+    fn render_status_line(&mut self, area: Rect, buf: &mut Buffer) {
+        let line = match self.inputmode {
+            InputMode::Jump => Line::styled(
+                format!(" Jump to (mm:ss): {}", self.jump_input),
+                Style::default().fg(TODO_COLRO).add_modifier(Modifier::BOLD),
+            ),
+            InputMode::PresetName => Line::styled(
+                format!(" Save preset as: {}", self.preset_name_input),
+                Style::default().fg(TODO_COLRO).add_modifier(Modifier::BOLD),
+            ),
+            InputMode::SleepTimer => Line::styled(
+                format!(
+                    " Sleep in (minutes, t = after this song, 3t = after 3 songs, a = end of album, empty = off): {}",
+                    self.sleep_input
+                ),
+                Style::default().fg(TODO_COLRO).add_modifier(Modifier::BOLD),
+            ),
+            _ => Line::styled(
+                format!(" {}", self.status_message),
+                Style::default().fg(Color::Rgb(191, 97, 106)),
+            ),
+        };
+        line.render(area, buf);
     }
 
     fn helper(&mut self, area: Rect, buf: &mut Buffer) {
         let help_table = &mut self.control_table;
         let rows = help_table.items.iter().map(|item| {
             let cells = item.iter().map(|c| ratatui::widgets::Cell::from(c.clone()));
-            ratatui::widgets::Row::new(cells)
-                .bottom_margin(1)
+            ratatui::widgets::Row::new(cells).bottom_margin(1)
         });
 
         let widths = [Constraint::Length(2), Constraint::Length(2)];
         let table = ratatui::widgets::Table::new(rows, widths)
             .block(Block::default().borders(Borders::ALL).title("Helper"))
             .highlight_style(Style::default().add_modifier(Modifier::BOLD))
-            .widths(&[
+            .widths([
                 Constraint::Percentage(50),
                 Constraint::Length(60),
                 Constraint::Min(10),
@@ -228,13 +576,8 @@ impl App {
 
 impl From<&Musicfile> for ListItem<'_> {
     fn from(value: &Musicfile) -> Self {
-        let path_str = value
-            .info
-            .file_name()
-            .unwrap()
-            .to_string_lossy()
-            .to_string();
-        if value.info.is_file() {
+        let path_str = with_resume_position(value.name(), value.resume_position);
+        if value.info.is_file() || value.cue_track.is_some() {
             let line = match value.status {
                 StatusOfMusicFile::Added => {
                     let pre = if value.num_added > 1 {
@@ -262,12 +605,7 @@ impl From<&Musicfile> for ListItem<'_> {
 
 impl From<&PlayingItem> for ListItem<'_> {
     fn from(value: &PlayingItem) -> Self {
-        let path_str = value
-            .path_of_music
-            .file_name()
-            .unwrap()
-            .to_string_lossy()
-            .to_string();
+        let path_str = with_resume_position(value.name(), value.resume_position);
         let line = match value.status {
             StatusOfPlayingItem::Playing => {
                 Line::styled(format!(" {}", path_str), Color::Rgb(143, 188, 187))
@@ -275,17 +613,26 @@ impl From<&PlayingItem> for ListItem<'_> {
             StatusOfPlayingItem::Pause => {
                 Line::styled(format!(" {}", path_str), Color::Rgb(143, 188, 187))
             }
-            StatusOfPlayingItem::Waiting => {
-                Line::styled(format!("{}", path_str), Color::Rgb(216, 222, 233))
-            }
+            StatusOfPlayingItem::Waiting => Line::styled(path_str, Color::Rgb(216, 222, 233)),
             StatusOfPlayingItem::Stop => {
                 Line::styled(format!("󰓛 {}", path_str), Color::Rgb(143, 188, 187))
             }
+            StatusOfPlayingItem::Error => {
+                Line::styled(format!(" {}", path_str), Color::Rgb(191, 97, 106))
+            }
         };
         ListItem::new(line)
     }
 }
 
+// Partly played files show where they were left off.
+fn with_resume_position(name: String, position: Option<std::time::Duration>) -> String {
+    match position {
+        Some(position) => format!("{} ◔ {}", name, display_time(position.as_secs())),
+        None => name,
+    }
+}
+
 fn display_time(number_seconds: u64) -> String {
     let hours = if number_seconds > 3600 {
         let hours_pre = (number_seconds / 60 / 60) % 24;
@@ -306,3 +653,90 @@ fn display_time(number_seconds: u64) -> String {
         .trim_start()
         .to_owned()
 }
+
+fn loop_time(t: std::time::Duration) -> String {
+    let tenths = t.as_millis() / 100;
+    format!("{}:{:02}.{}", tenths / 600, tenths / 10 % 60, tenths % 10)
+}
+
+fn visualizer_block(title: &str) -> Block<'_> {
+    Block::default()
+        .borders(Borders::ALL)
+        .border_type(BorderType::Thick)
+        .border_style(Style::default().fg(TODO_COLRO))
+        .title(Span::styled(title, Style::default().fg(TODO_COLRO)))
+}
+
+// 0.0 for the floor of the display up to 1.0 for full scale
+fn db_ratio(db: f32) -> f64 {
+    ((db - FLOOR_DB) / -FLOOR_DB).clamp(0.0, 1.0) as f64
+}
+
+fn compressor_name(settings: &CompressorSettings) -> &'static str {
+    if settings.is_night_mode() {
+        "Night Mode"
+    } else {
+        "Compressor"
+    }
+}
+
+fn render_spectrum(spectrum: &Spectrum, area: Rect, buf: &mut Buffer) {
+    const BARS: [&str; 9] = [" ", "▁", "▂", "▃", "▄", "▅", "▆", "▇", "█"];
+    let block = visualizer_block(" Spectrum ");
+    let inner = block.inner(area);
+    block.render(area, buf);
+    let width = inner.width as usize;
+    for column in 0..width {
+        // a column shows the loudest of the bands it covers
+        let first = column * SPECTRUM_BANDS / width;
+        let last = ((column + 1) * SPECTRUM_BANDS / width).max(first + 1);
+        let db = spectrum.bands[first..last.min(SPECTRUM_BANDS)]
+            .iter()
+            .fold(FLOOR_DB, |max, &db| max.max(db));
+        let mut eighths = (db_ratio(db) * inner.height as f64 * 8.0).round() as usize;
+        for row in (0..inner.height).rev() {
+            let symbol = BARS[eighths.min(8)];
+            eighths = eighths.saturating_sub(8);
+            buf.set_string(
+                inner.x + column as u16,
+                inner.y + row,
+                symbol,
+                Style::default().fg(TODO_COLRO),
+            );
+        }
+    }
+}
+
+fn render_levels(spectrum: &Spectrum, area: Rect, buf: &mut Buffer) {
+    let block = visualizer_block(" Level ");
+    let inner = block.inner(area);
+    block.render(area, buf);
+    // "L " in front, the peak in dB behind
+    let bar_width = inner.width.saturating_sub(9);
+    if bar_width == 0 {
+        return;
+    }
+    let rows = (inner.height as usize).min(2);
+    for (row, name) in ["L", "R"].into_iter().enumerate().take(rows) {
+        let y = inner.y + row as u16;
+        let (rms, peak) = (spectrum.rms[row], spectrum.peak[row]);
+        buf.set_string(inner.x, y, name, Style::default().fg(TODO_COLRO));
+        let filled = (db_ratio(rms) * bar_width as f64).round() as u16;
+        buf.set_string(
+            inner.x + 2,
+            y,
+            "█".repeat(filled as usize),
+            Style::default().fg(TODO_COLRO),
+        );
+        if peak > FLOOR_DB {
+            let x = (db_ratio(peak) * (bar_width - 1) as f64).round() as u16;
+            buf.set_string(inner.x + 2 + x, y, "▌", Style::default().fg(LOOP_COLOR));
+        }
+        buf.set_string(
+            inner.x + 2 + bar_width,
+            y,
+            format!("{:>6.1}", peak.max(FLOOR_DB)),
+            Style::default().fg(TODO_COLRO),
+        );
+    }
+}
@@ -0,0 +1,95 @@
+// Second order IIR section in transposed direct form II. Coefficients are
+// normalized so that a0 is 1.
+#[derive(Clone, Copy, Default)]
+pub struct Biquad {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+    z1: f64,
+    z2: f64,
+}
+
+impl Biquad {
+    pub fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+        Self {
+            b0: b[0] / a[0],
+            b1: b[1] / a[0],
+            b2: b[2] / a[0],
+            a1: a[1] / a[0],
+            a2: a[2] / a[0],
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+
+    // Peaking filter from the Audio EQ Cookbook (Robert Bristow-Johnson).
+    pub fn peaking(sample_rate: u32, freq: f64, q: f64, gain_db: f64) -> Self {
+        let a = 10f64.powf(gain_db / 40.0);
+        let w0 = 2.0 * std::f64::consts::PI * freq / sample_rate as f64;
+        let alpha = w0.sin() / (2.0 * q);
+        let cos_w0 = w0.cos();
+        Self::new(
+            [1.0 + alpha * a, -2.0 * cos_w0, 1.0 - alpha * a],
+            [1.0 + alpha / a, -2.0 * cos_w0, 1.0 - alpha / a],
+        )
+    }
+
+    // Takes over the coefficients of `other` but keeps the filter state, so
+    // the response can change while audio is running without a click.
+    pub fn set_coefficients(&mut self, other: &Biquad) {
+        self.b0 = other.b0;
+        self.b1 = other.b1;
+        self.b2 = other.b2;
+        self.a1 = other.a1;
+        self.a2 = other.a2;
+    }
+
+    #[inline]
+    pub fn process(&mut self, x: f64) -> f64 {
+        let y = self.b0 * x + self.z1;
+        self.z1 = self.b1 * x - self.a1 * y + self.z2;
+        self.z2 = self.b2 * x - self.a2 * y;
+        y
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    // peak of a sine at `freq` once the filter settled
+    fn peak_gain(filter: &mut Biquad, freq: f64) -> f64 {
+        let w = 2.0 * std::f64::consts::PI * freq / RATE as f64;
+        let mut peak = 0f64;
+        for i in 0..RATE as usize {
+            let y = filter.process((w * i as f64).sin());
+            if i > RATE as usize / 2 {
+                peak = peak.max(y.abs());
+            }
+        }
+        peak
+    }
+
+    #[test]
+    fn peaking_boosts_the_centre_frequency() {
+        let mut filter = Biquad::peaking(RATE, 1000.0, 1.41, 6.0);
+        let gain = peak_gain(&mut filter, 1000.0);
+        assert!((gain - 10f64.powf(6.0 / 20.0)).abs() < 0.01, "{}", gain);
+        // and leaves what is far away alone
+        let mut filter = Biquad::peaking(RATE, 1000.0, 1.41, 6.0);
+        let gain = peak_gain(&mut filter, 50.0);
+        assert!((gain - 1.0).abs() < 0.02, "{}", gain);
+    }
+
+    #[test]
+    fn zero_gain_is_a_passthrough() {
+        let mut filter = Biquad::peaking(RATE, 1000.0, 1.41, 0.0);
+        for x in [1.0, -0.5, 0.25, 0.0, 0.75] {
+            assert!((filter.process(x) - x).abs() < 1e-12);
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rodio::Source;
+use serde::Deserialize;
+
+// frames between two looks at the settings
+const UPDATE_INTERVAL: usize = 512;
+// how much of the way to new settings is made per frame, ~10ms at 44.1kHz
+const SMOOTHING: f32 = 0.002;
+
+#[derive(Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChannelMode {
+    #[default]
+    Stereo,
+    // both sides get the sum, for one earbud or a single speaker
+    Mono,
+    Swap,
+}
+
+impl ChannelMode {
+    pub fn next(&self) -> Self {
+        match self {
+            Self::Stereo => Self::Mono,
+            Self::Mono => Self::Swap,
+            Self::Swap => Self::Stereo,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Stereo => "Stereo",
+            Self::Mono => "Mono",
+            Self::Swap => "Swap",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct ChannelSettings {
+    pub mode: ChannelMode,
+    // -1.0 is only the left side, 1.0 only the right one
+    pub balance: f32,
+}
+
+impl ChannelSettings {
+    // How much of the left and right input ends up on each side.
+    fn matrix(&self) -> [[f32; 2]; 2] {
+        let [left, right] = match self.mode {
+            ChannelMode::Stereo => [[1.0, 0.0], [0.0, 1.0]],
+            ChannelMode::Mono => [[0.5, 0.5], [0.5, 0.5]],
+            ChannelMode::Swap => [[0.0, 1.0], [1.0, 0.0]],
+        };
+        let left_gain = (1.0 - self.balance).min(1.0);
+        let right_gain = (1.0 + self.balance).min(1.0);
+        [left.map(|v| v * left_gain), right.map(|v| v * right_gain)]
+    }
+}
+
+// Balance, mono and channel swap on the stereo output. Changes glide over a
+// few milliseconds instead of jumping, which would click.
+pub struct ChannelMix<S> {
+    input: S,
+    settings: Arc<Mutex<ChannelSettings>>,
+    matrix: [[f32; 2]; 2],
+    target: [[f32; 2]; 2],
+    right: Option<f32>,
+    countdown: usize,
+}
+
+impl<S: Source<Item = f32>> ChannelMix<S> {
+    pub fn new(input: S, settings: Arc<Mutex<ChannelSettings>>) -> Self {
+        let matrix = settings.lock().unwrap().matrix();
+        Self {
+            input,
+            settings,
+            matrix,
+            target: matrix,
+            right: None,
+            countdown: 0,
+        }
+    }
+}
+
+impl<S: Source<Item = f32>> Iterator for ChannelMix<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if let Some(right) = self.right.take() {
+            return Some(right);
+        }
+        let left = self.input.next()?;
+        let right = self.input.next().unwrap_or(0.0);
+
+        if self.countdown == 0 {
+            self.target = self.settings.lock().unwrap().matrix();
+            self.countdown = UPDATE_INTERVAL;
+        }
+        self.countdown -= 1;
+        if self.matrix != self.target {
+            for (row, target) in self.matrix.iter_mut().zip(self.target) {
+                for (value, target) in row.iter_mut().zip(target) {
+                    let step = (target - *value) * SMOOTHING;
+                    *value = if step.abs() < 1e-6 {
+                        target
+                    } else {
+                        *value + step
+                    };
+                }
+            }
+        }
+
+        let [l, r] = self.matrix;
+        self.right = Some(r[0] * left + r[1] * right);
+        Some(l[0] * left + l[1] * right)
+    }
+}
+
+impl<S: Source<Item = f32>> Source for ChannelMix<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rodio::buffer::SamplesBuffer;
+
+    use super::*;
+
+    fn mix(mode: ChannelMode, samples: Vec<f32>) -> Vec<f32> {
+        let settings = ChannelSettings { mode, balance: 0.0 };
+        let input = SamplesBuffer::new(2, 44100, samples);
+        ChannelMix::new(input, Arc::new(Mutex::new(settings))).collect()
+    }
+
+    #[test]
+    fn stereo_passes_swap_exchanges_and_mono_averages() {
+        let samples = vec![1.0, 0.25, -0.5, 0.5];
+        assert_eq!(mix(ChannelMode::Stereo, samples.clone()), samples);
+        assert_eq!(
+            mix(ChannelMode::Swap, samples.clone()),
+            vec![0.25, 1.0, 0.5, -0.5]
+        );
+        assert_eq!(
+            mix(ChannelMode::Mono, samples),
+            vec![0.625, 0.625, 0.0, 0.0]
+        );
+    }
+}
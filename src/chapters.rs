@@ -0,0 +1,652 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+    time::Duration,
+};
+
+use lofty::tag::{ItemKey, Tag};
+
+// moov and the chapter elements are small, anything bigger is a broken file
+const MAX_BOX_SIZE: u64 = 64 << 20;
+// more would be a broken file too, and not of much use
+const MAX_CHAPTERS: usize = 10_000;
+
+#[derive(Clone)]
+pub struct Chapter {
+    pub title: String,
+    pub start: Duration,
+}
+
+// The index of the chapter that is playing at `position`.
+pub fn chapter_at(chapters: &[Chapter], position: Duration) -> Option<usize> {
+    chapters
+        .iter()
+        .rposition(|chapter| chapter.start <= position)
+}
+
+// Reads the chapter table of MP4/M4B and Matroska files, other files may
+// have theirs in the tags, see `chapters_from_tag`.
+pub fn read_chapters(path: &Path) -> Vec<Chapter> {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    let chapters = match extension.as_deref() {
+        Some("m4a" | "m4b" | "mp4" | "m4v" | "mov") => File::open(path)
+            .ok()
+            .and_then(|mut file| mp4_chapters(&mut file)),
+        Some("mka" | "mkv" | "webm") => File::open(path)
+            .ok()
+            .and_then(|mut file| matroska_chapters(&mut file)),
+        _ => None,
+    };
+    sorted(chapters.unwrap_or_default())
+}
+
+// Vorbis comment chapters as used in Ogg and FLAC files:
+// CHAPTER001=00:00:00.000 and CHAPTER001NAME=Title.
+pub fn chapters_from_tag(tag: &Tag) -> Vec<Chapter> {
+    let mut chapters = Vec::new();
+    for item in tag.items() {
+        let ItemKey::Unknown(key) = item.key() else {
+            continue;
+        };
+        let key = key.to_ascii_uppercase();
+        let Some(number) = key.strip_prefix("CHAPTER") else {
+            continue;
+        };
+        if number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+            continue;
+        }
+        let Some(start) = item.value().text().and_then(parse_timestamp) else {
+            continue;
+        };
+        let title = tag
+            .items()
+            .find(|name| {
+                matches!(name.key(), ItemKey::Unknown(name_key)
+                    if name_key.eq_ignore_ascii_case(&format!("{}NAME", key)))
+            })
+            .and_then(|name| name.value().text())
+            .map_or_else(|| format!("Chapter {}", number), str::to_string);
+        chapters.push(Chapter { title, start });
+    }
+    sorted(chapters)
+}
+
+fn sorted(mut chapters: Vec<Chapter>) -> Vec<Chapter> {
+    chapters.sort_by_key(|chapter| chapter.start);
+    chapters
+}
+
+// HH:MM:SS.mmm
+fn parse_timestamp(text: &str) -> Option<Duration> {
+    let mut parts = text.trim().splitn(3, ':');
+    let hours: u64 = parts.next()?.parse().ok()?;
+    let minutes: u64 = parts.next()?.parse().ok()?;
+    let seconds: f64 = parts.next()?.parse().ok()?;
+    if !(0.0..60.0).contains(&seconds) {
+        return None;
+    }
+    Some(Duration::from_secs(hours * 3600 + minutes * 60) + Duration::from_secs_f64(seconds))
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(pos..pos + 2)?.try_into().ok()?))
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+fn read_u64(data: &[u8], pos: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(data.get(pos..pos + 8)?.try_into().ok()?))
+}
+
+// MP4 boxes of `data`, as (type, content) pairs.
+fn mp4_boxes(data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut pos = 0;
+    std::iter::from_fn(move || {
+        let size = read_u32(data, pos)? as usize;
+        let kind = data.get(pos + 4..pos + 8)?;
+        let (header, size) = match size {
+            0 => (8, data.len() - pos),
+            1 => (16, read_u64(data, pos + 8)? as usize),
+            size => (8, size),
+        };
+        let content = data.get(pos + header..pos.checked_add(size)?)?;
+        pos += size.max(header);
+        Some((kind, content))
+    })
+}
+
+fn mp4_child<'a>(data: &'a [u8], kind: &[u8]) -> Option<&'a [u8]> {
+    mp4_boxes(data).find(|(k, _)| *k == kind).map(|(_, c)| c)
+}
+
+fn mp4_path<'a>(data: &'a [u8], path: &[&[u8]]) -> Option<&'a [u8]> {
+    path.iter()
+        .try_fold(data, |data, kind| mp4_child(data, kind))
+}
+
+fn mp4_chapters(file: &mut (impl Read + Seek)) -> Option<Vec<Chapter>> {
+    // only the top level is walked on disk, mdat can be huge
+    let mut moov = None;
+    let mut pos = 0;
+    loop {
+        let mut header = [0u8; 16];
+        file.seek(SeekFrom::Start(pos)).ok()?;
+        file.read_exact(&mut header[..8]).ok()?;
+        let (header_len, size) = match read_u32(&header, 0)? {
+            0 => break,
+            1 => {
+                file.read_exact(&mut header[8..]).ok()?;
+                (16, read_u64(&header, 8)?)
+            }
+            size => (8, size as u64),
+        };
+        if size < header_len {
+            return None;
+        }
+        if &header[4..8] == b"moov" {
+            if size > MAX_BOX_SIZE {
+                return None;
+            }
+            let mut data = vec![0; (size - header_len) as usize];
+            file.read_exact(&mut data).ok()?;
+            moov = Some(data);
+            break;
+        }
+        pos += size;
+    }
+    let moov = moov?;
+    quicktime_chapters(&moov, file)
+        .filter(|chapters| !chapters.is_empty())
+        .or_else(|| nero_chapters(&moov))
+}
+
+// Nero chapters in moov/udta/chpl, start times are in 100ns units.
+fn nero_chapters(moov: &[u8]) -> Option<Vec<Chapter>> {
+    let chpl = mp4_path(moov, &[b"udta", b"chpl"])?;
+    let mut pos = if chpl.first()? > &0 { 8 } else { 4 };
+    let count = *chpl.get(pos)?;
+    pos += 1;
+    let mut chapters = Vec::new();
+    for _ in 0..count {
+        let start = read_u64(chpl, pos)?;
+        let len = *chpl.get(pos + 8)? as usize;
+        let title = chpl.get(pos + 9..pos + 9 + len)?;
+        pos += 9 + len;
+        chapters.push(Chapter {
+            title: String::from_utf8_lossy(title).to_string(),
+            start: Duration::from_nanos(start * 100),
+        });
+    }
+    Some(chapters)
+}
+
+// QuickTime chapters, as in audiobooks from iTunes: a text track, referred
+// to by a `chap` reference of the audio track, whose samples are the titles.
+fn quicktime_chapters(moov: &[u8], file: &mut (impl Read + Seek)) -> Option<Vec<Chapter>> {
+    let traks: Vec<&[u8]> = mp4_boxes(moov)
+        .filter(|(kind, _)| *kind == b"trak")
+        .map(|(_, content)| content)
+        .collect();
+    let chapter_id = traks
+        .iter()
+        .find_map(|trak| mp4_path(trak, &[b"tref", b"chap"]))
+        .and_then(|chap| read_u32(chap, 0))?;
+    let trak = traks.iter().find(|trak| {
+        mp4_child(trak, b"tkhd").and_then(|tkhd| {
+            // the track id follows two dates, which are 64 bit in version 1
+            let offset = if tkhd.first()? == &1 { 20 } else { 12 };
+            read_u32(tkhd, offset)
+        }) == Some(chapter_id)
+    })?;
+    let mdia = mp4_child(trak, b"mdia")?;
+    let mdhd = mp4_child(mdia, b"mdhd")?;
+    let timescale = read_u32(mdhd, if mdhd.first()? == &1 { 20 } else { 12 })?;
+    if timescale == 0 {
+        return None;
+    }
+    let stbl = mp4_path(mdia, &[b"minf", b"stbl"])?;
+
+    // the duration of every sample gives its start
+    let stts = mp4_child(stbl, b"stts")?;
+    let mut starts = Vec::new();
+    let mut time = 0u64;
+    for entry in 0..read_u32(stts, 4)? as usize {
+        let count = read_u32(stts, 8 + entry * 8)?;
+        let delta = read_u32(stts, 12 + entry * 8)? as u64;
+        for _ in 0..(count as usize).min(MAX_CHAPTERS - starts.len()) {
+            starts.push(time);
+            time += delta;
+        }
+    }
+
+    let chunk_offsets = match mp4_child(stbl, b"stco") {
+        Some(stco) => (0..read_u32(stco, 4)? as usize)
+            .map(|i| read_u32(stco, 8 + i * 4).map(u64::from))
+            .collect::<Option<Vec<u64>>>()?,
+        None => {
+            let co64 = mp4_child(stbl, b"co64")?;
+            (0..read_u32(co64, 4)? as usize)
+                .map(|i| read_u64(co64, 8 + i * 8))
+                .collect::<Option<Vec<u64>>>()?
+        }
+    };
+    // runs of chunks with the same number of samples each, as (first chunk,
+    // last chunk, samples per chunk)
+    let stsc = mp4_child(stbl, b"stsc")?;
+    let entries = (0..read_u32(stsc, 4)? as usize)
+        .map(|i| Some((read_u32(stsc, 8 + i * 12)?, read_u32(stsc, 12 + i * 12)?)))
+        .collect::<Option<Vec<(u32, u32)>>>()?;
+    let runs: Vec<(u32, u32, u32)> = entries
+        .iter()
+        .enumerate()
+        .map(|(run, &(first_chunk, samples_per_chunk))| {
+            let last_chunk = entries
+                .get(run + 1)
+                .map_or(chunk_offsets.len() as u32, |next| next.0.saturating_sub(1));
+            (first_chunk, last_chunk, samples_per_chunk)
+        })
+        .collect();
+
+    // the count is not trusted further than the atom and the chunks go, and
+    // only the samples with a start are needed
+    let stsz = mp4_child(stbl, b"stsz")?;
+    let fixed_size = read_u32(stsz, 4)?;
+    let mut count = (read_u32(stsz, 8)? as usize).min(starts.len());
+    if fixed_size == 0 {
+        count = count.min(stsz.len().saturating_sub(12) / 4);
+    }
+    let chunk_samples = runs.iter().fold(0u64, |total, &(first, last, per_chunk)| {
+        let chunks = (last as u64 + 1).saturating_sub(first as u64);
+        total.saturating_add(chunks * per_chunk as u64)
+    });
+    count = count.min(chunk_samples as usize);
+    let sizes = (0..count)
+        .map(|i| match fixed_size {
+            0 => read_u32(stsz, 12 + i * 4),
+            size => Some(size),
+        })
+        .collect::<Option<Vec<u32>>>()?;
+
+    let mut offsets = Vec::with_capacity(count);
+    'runs: for &(first_chunk, last_chunk, samples_per_chunk) in &runs {
+        for chunk in first_chunk..=last_chunk {
+            let mut offset = *chunk_offsets.get((chunk as usize).checked_sub(1)?)?;
+            for _ in 0..samples_per_chunk {
+                let Some(&size) = sizes.get(offsets.len()) else {
+                    break 'runs;
+                };
+                offsets.push((offset, size));
+                offset += size as u64;
+            }
+        }
+    }
+
+    let mut chapters = Vec::new();
+    for (&start, (offset, size)) in starts.iter().zip(offsets) {
+        let mut sample = vec![0; size.min(4096) as usize];
+        file.seek(SeekFrom::Start(offset)).ok()?;
+        file.read_exact(&mut sample).ok()?;
+        let len = read_u16(&sample, 0)? as usize;
+        let text = sample.get(2..2 + len)?;
+        chapters.push(Chapter {
+            title: decode_text(text),
+            start: Duration::from_secs_f64(start as f64 / timescale as f64),
+        });
+    }
+    Some(chapters)
+}
+
+// Text samples are UTF-8, or UTF-16 when they start with a byte order mark.
+fn decode_text(text: &[u8]) -> String {
+    let utf16 = |bytes: &[u8], from: fn([u8; 2]) -> u16| {
+        let units: Vec<u16> = bytes.chunks_exact(2).map(|b| from([b[0], b[1]])).collect();
+        String::from_utf16_lossy(&units)
+    };
+    match text {
+        [0xFE, 0xFF, rest @ ..] => utf16(rest, u16::from_be_bytes),
+        [0xFF, 0xFE, rest @ ..] => utf16(rest, u16::from_le_bytes),
+        _ => String::from_utf8_lossy(text).to_string(),
+    }
+}
+
+const EBML_SEGMENT: u32 = 0x18538067;
+const EBML_SEEK_HEAD: u32 = 0x114D9B74;
+const EBML_SEEK: u32 = 0x4DBB;
+const EBML_SEEK_ID: u32 = 0x53AB;
+const EBML_SEEK_POSITION: u32 = 0x53AC;
+const EBML_CLUSTER: u32 = 0x1F43B675;
+const EBML_CHAPTERS: u32 = 0x1043A770;
+const EBML_EDITION_ENTRY: u32 = 0x45B9;
+const EBML_CHAPTER_ATOM: u32 = 0xB6;
+const EBML_CHAPTER_TIME_START: u32 = 0x91;
+const EBML_CHAPTER_FLAG_HIDDEN: u32 = 0x98;
+const EBML_CHAPTER_DISPLAY: u32 = 0x80;
+const EBML_CHAP_STRING: u32 = 0x85;
+
+// A variable length integer of EBML, the id keeps its length marker.
+fn read_vint(reader: &mut impl Read, keep_marker: bool) -> Option<(u64, bool)> {
+    let mut first = [0u8];
+    reader.read_exact(&mut first).ok()?;
+    let len = first[0].leading_zeros() as usize + 1;
+    if len > 8 {
+        return None;
+    }
+    let mut value = if keep_marker {
+        first[0] as u64
+    } else {
+        (first[0] as u64) & (0xFF >> len)
+    };
+    let mut all_ones = value == (0xFF >> len);
+    let mut rest = [0u8; 7];
+    reader.read_exact(&mut rest[..len - 1]).ok()?;
+    for &byte in &rest[..len - 1] {
+        value = value << 8 | byte as u64;
+        all_ones &= byte == 0xFF;
+    }
+    Some((value, all_ones))
+}
+
+// Element header: id and size, `None` for an unknown size.
+fn read_element(reader: &mut impl Read) -> Option<(u32, Option<u64>)> {
+    let (id, _) = read_vint(reader, true)?;
+    let (size, unknown) = read_vint(reader, false)?;
+    Some((id as u32, (!unknown).then_some(size)))
+}
+
+// Child elements of an element read into memory.
+fn ebml_children(mut data: &[u8]) -> impl Iterator<Item = (u32, &[u8])> {
+    std::iter::from_fn(move || {
+        let (id, size) = read_element(&mut data)?;
+        let size = size? as usize;
+        let content = data.get(..size)?;
+        data = &data[size..];
+        Some((id, content))
+    })
+}
+
+fn ebml_uint(data: &[u8]) -> u64 {
+    data.iter().fold(0, |value, &byte| value << 8 | byte as u64)
+}
+
+fn matroska_chapters(file: &mut (impl Read + Seek)) -> Option<Vec<Chapter>> {
+    // skip the EBML header
+    let (_, size) = read_element(file)?;
+    file.seek(SeekFrom::Current(size? as i64)).ok()?;
+    let (id, _) = read_element(file)?;
+    if id != EBML_SEGMENT {
+        return None;
+    }
+    let segment_start = file.stream_position().ok()?;
+
+    // the chapters come before the clusters, or the seek head says where
+    let mut chapters_position = None;
+    loop {
+        let position = file.stream_position().ok()?;
+        let (id, size) = read_element(file)?;
+        match id {
+            EBML_CHAPTERS => {
+                chapters_position = Some(position);
+                break;
+            }
+            EBML_SEEK_HEAD => {
+                let data = read_content(file, size?)?;
+                for (_, seek) in ebml_children(&data).filter(|(id, _)| *id == EBML_SEEK) {
+                    let mut seek_id = None;
+                    let mut seek_position = None;
+                    for (id, value) in ebml_children(seek) {
+                        match id {
+                            EBML_SEEK_ID => seek_id = Some(ebml_uint(value)),
+                            EBML_SEEK_POSITION => seek_position = Some(ebml_uint(value)),
+                            _ => {}
+                        }
+                    }
+                    if seek_id == Some(EBML_CHAPTERS as u64) {
+                        chapters_position = seek_position.map(|p| segment_start + p);
+                    }
+                }
+            }
+            EBML_CLUSTER => break,
+            _ => {
+                file.seek(SeekFrom::Current(size? as i64)).ok()?;
+            }
+        }
+    }
+
+    file.seek(SeekFrom::Start(chapters_position?)).ok()?;
+    let (id, size) = read_element(file)?;
+    if id != EBML_CHAPTERS {
+        return None;
+    }
+    let data = read_content(file, size?)?;
+    // only the first edition, the others are alternatives to it
+    let (_, edition) = ebml_children(&data).find(|(id, _)| *id == EBML_EDITION_ENTRY)?;
+    let mut chapters = Vec::new();
+    for (_, atom) in ebml_children(edition).filter(|(id, _)| *id == EBML_CHAPTER_ATOM) {
+        let mut start = None;
+        let mut title = None;
+        let mut hidden = false;
+        for (id, value) in ebml_children(atom) {
+            match id {
+                EBML_CHAPTER_TIME_START => start = Some(Duration::from_nanos(ebml_uint(value))),
+                EBML_CHAPTER_FLAG_HIDDEN => hidden = ebml_uint(value) != 0,
+                EBML_CHAPTER_DISPLAY if title.is_none() => {
+                    title = ebml_children(value)
+                        .find(|(id, _)| *id == EBML_CHAP_STRING)
+                        .map(|(_, text)| String::from_utf8_lossy(text).to_string());
+                }
+                _ => {}
+            }
+        }
+        if let (Some(start), false) = (start, hidden) {
+            let title = title.unwrap_or_else(|| format!("Chapter {}", chapters.len() + 1));
+            chapters.push(Chapter { title, start });
+        }
+    }
+    Some(chapters)
+}
+
+fn read_content(file: &mut impl Read, size: u64) -> Option<Vec<u8>> {
+    if size > MAX_BOX_SIZE {
+        return None;
+    }
+    let mut data = vec![0; size as usize];
+    file.read_exact(&mut data).ok()?;
+    Some(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use lofty::tag::{ItemValue, TagItem, TagType};
+
+    use super::*;
+
+    fn mp4_box(kind: &[u8], content: &[u8]) -> Vec<u8> {
+        let mut data = (content.len() as u32 + 8).to_be_bytes().to_vec();
+        data.extend_from_slice(kind);
+        data.extend_from_slice(content);
+        data
+    }
+
+    // A box with version and flags in front, all of it numbers.
+    fn full_box(kind: &[u8], values: &[u32]) -> Vec<u8> {
+        let content: Vec<u8> = [0]
+            .iter()
+            .chain(values)
+            .flat_map(|v| v.to_be_bytes())
+            .collect();
+        mp4_box(kind, &content)
+    }
+
+    // An audio track pointing at a text track with chapter titles, 600
+    // units a second, the titles in one chunk at `offset`.
+    fn quicktime_moov(durations: &[u32], sizes: &[u32], offset: u32) -> Vec<u8> {
+        let tref = mp4_box(b"tref", &mp4_box(b"chap", &2u32.to_be_bytes()));
+        let audio = mp4_box(b"trak", &[full_box(b"tkhd", &[0, 0, 1]), tref].concat());
+
+        let stts: Vec<u32> = [durations.len() as u32]
+            .into_iter()
+            .chain(durations.iter().flat_map(|&d| [1, d]))
+            .collect();
+        let stsz: Vec<u32> = [0, sizes.len() as u32]
+            .into_iter()
+            .chain(sizes.iter().copied())
+            .collect();
+        let stbl = [
+            full_box(b"stts", &stts),
+            full_box(b"stsz", &stsz),
+            full_box(b"stco", &[1, offset]),
+            full_box(b"stsc", &[1, 1, sizes.len() as u32, 1]),
+        ]
+        .concat();
+        let minf = mp4_box(b"minf", &mp4_box(b"stbl", &stbl));
+        let mdia = mp4_box(b"mdia", &[full_box(b"mdhd", &[0, 0, 600]), minf].concat());
+        let text = mp4_box(b"trak", &[full_box(b"tkhd", &[0, 0, 2]), mdia].concat());
+        mp4_box(b"moov", &[audio, text].concat())
+    }
+
+    fn text_sample(title: &str) -> Vec<u8> {
+        let mut sample = (title.len() as u16).to_be_bytes().to_vec();
+        sample.extend_from_slice(title.as_bytes());
+        sample
+    }
+
+    fn quicktime_file(titles: &[&str], durations: &[u32]) -> Vec<u8> {
+        let samples: Vec<Vec<u8>> = titles.iter().map(|title| text_sample(title)).collect();
+        let sizes: Vec<u32> = samples.iter().map(|sample| sample.len() as u32).collect();
+        let ftyp = mp4_box(b"ftyp", b"M4B \0\0\0\0");
+        // the offset doesn't change the size of moov
+        let moov_len = quicktime_moov(durations, &sizes, 0).len();
+        let offset = (ftyp.len() + moov_len + 8) as u32;
+        let moov = quicktime_moov(durations, &sizes, offset);
+        [ftyp, moov, mp4_box(b"mdat", &samples.concat())].concat()
+    }
+
+    fn titles_and_starts(chapters: &[Chapter]) -> Vec<(&str, f64)> {
+        chapters
+            .iter()
+            .map(|chapter| (chapter.title.as_str(), chapter.start.as_secs_f64()))
+            .collect()
+    }
+
+    #[test]
+    fn quicktime_chapters_are_the_samples_of_the_text_track() {
+        let file = quicktime_file(&["Intro", "Part one", "End"], &[600, 1800, 300]);
+        let chapters = mp4_chapters(&mut Cursor::new(file)).unwrap();
+        assert_eq!(
+            titles_and_starts(&chapters),
+            [("Intro", 0.0), ("Part one", 1.0), ("End", 4.0)]
+        );
+    }
+
+    #[test]
+    fn a_huge_sample_count_is_not_believed() {
+        let file = quicktime_file(&["One", "Two"], &[600, 600]);
+        // every sample of the same size and four billion of them
+        let mut broken = file.clone();
+        let stsz = find(&broken, b"stsz") + 4;
+        broken[stsz + 4..stsz + 8].copy_from_slice(&5u32.to_be_bytes());
+        broken[stsz + 8..stsz + 12].copy_from_slice(&u32::MAX.to_be_bytes());
+        let chapters = mp4_chapters(&mut Cursor::new(broken)).unwrap();
+        assert_eq!(titles_and_starts(&chapters), [("One", 0.0), ("Two", 1.0)]);
+
+        // or more of different sizes than the atom holds
+        let mut broken = file;
+        broken[stsz + 8..stsz + 12].copy_from_slice(&u32::MAX.to_be_bytes());
+        let chapters = mp4_chapters(&mut Cursor::new(broken)).unwrap();
+        assert_eq!(chapters.len(), 2);
+    }
+
+    fn find(data: &[u8], kind: &[u8]) -> usize {
+        data.windows(4).position(|window| window == kind).unwrap()
+    }
+
+    #[test]
+    fn nero_chapters_are_read_without_a_chapter_track() {
+        let mut chpl = vec![1, 0, 0, 0, 0, 0, 0, 0, 2];
+        for (start, title) in [(0u64, "A"), (15_000_000u64, "B side")] {
+            chpl.extend_from_slice(&start.to_be_bytes());
+            chpl.push(title.len() as u8);
+            chpl.extend_from_slice(title.as_bytes());
+        }
+        let moov = mp4_box(b"moov", &mp4_box(b"udta", &mp4_box(b"chpl", &chpl)));
+        let file = [mp4_box(b"ftyp", b"M4A \0\0\0\0"), moov].concat();
+        let chapters = mp4_chapters(&mut Cursor::new(file)).unwrap();
+        assert_eq!(titles_and_starts(&chapters), [("A", 0.0), ("B side", 1.5)]);
+    }
+
+    // An element with a one byte id or a longer one with its marker, and
+    // a one byte size.
+    fn element(id: u32, content: &[u8]) -> Vec<u8> {
+        let mut data: Vec<u8> = id
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|&b| b == 0)
+            .collect();
+        data.push(0x80 | content.len() as u8);
+        data.extend_from_slice(content);
+        data
+    }
+
+    fn atom(start_ns: u64, title: &str, hidden: bool) -> Vec<u8> {
+        let start = element(EBML_CHAPTER_TIME_START, &start_ns.to_be_bytes());
+        let display = element(
+            EBML_CHAPTER_DISPLAY,
+            &element(EBML_CHAP_STRING, title.as_bytes()),
+        );
+        let hidden = element(EBML_CHAPTER_FLAG_HIDDEN, &[hidden as u8]);
+        element(EBML_CHAPTER_ATOM, &[start, display, hidden].concat())
+    }
+
+    #[test]
+    fn matroska_chapters_skip_hidden_ones() {
+        let edition = element(
+            EBML_EDITION_ENTRY,
+            &[
+                atom(0, "Opening", false),
+                atom(1_000_000_000, "Hidden", true),
+                atom(2_500_000_000, "Main", false),
+            ]
+            .concat(),
+        );
+        let segment = [
+            element(0x1549A966, &[]),
+            element(EBML_CHAPTERS, &edition),
+            element(EBML_CLUSTER, &[]),
+        ]
+        .concat();
+        let file = [element(0x1A45DFA3, &[]), element(EBML_SEGMENT, &segment)].concat();
+        let chapters = matroska_chapters(&mut Cursor::new(file)).unwrap();
+        assert_eq!(
+            titles_and_starts(&chapters),
+            [("Opening", 0.0), ("Main", 2.5)]
+        );
+    }
+
+    #[test]
+    fn vorbis_comment_chapters_are_sorted() {
+        let mut tag = Tag::new(TagType::VorbisComments);
+        for (key, value) in [
+            ("CHAPTER002", "00:01:30.500"),
+            ("CHAPTER002NAME", "Second"),
+            ("CHAPTER001", "00:00:00.000"),
+            ("CHAPTER003", "bad"),
+        ] {
+            tag.push_unchecked(TagItem::new(
+                ItemKey::Unknown(key.to_string()),
+                ItemValue::Text(value.to_string()),
+            ));
+        }
+        let chapters = chapters_from_tag(&tag);
+        assert_eq!(
+            titles_and_starts(&chapters),
+            [("Chapter 001", 0.0), ("Second", 90.5)]
+        );
+    }
+}
@@ -0,0 +1,286 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rodio::Source;
+
+// frames between two looks at the settings
+const UPDATE_INTERVAL: usize = 512;
+// seconds for the gain to follow a rise and a fall of the level
+const ATTACK: f32 = 0.010;
+const RELEASE: f32 = 0.300;
+const LIMITER_RELEASE: f32 = 0.100;
+// the limiter keeps the peaks below this, in dBFS
+const CEILING_DB: f32 = -1.0;
+// the curve bends over this many dB around the threshold
+const KNEE_DB: f32 = 6.0;
+
+pub const MIN_THRESHOLD: f32 = -60.0;
+pub const MAX_RATIO: f32 = 20.0;
+pub const MAX_MAKEUP: f32 = 24.0;
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct CompressorSettings {
+    pub enabled: bool,
+    // dBFS where compressing begins
+    pub threshold: f32,
+    // dB over the threshold going in for every dB coming out
+    pub ratio: f32,
+    // dB added after compressing, brings the quiet passages up
+    pub makeup: f32,
+}
+
+impl Default for CompressorSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            threshold: -20.0,
+            ratio: 3.0,
+            makeup: 4.0,
+        }
+    }
+}
+
+impl CompressorSettings {
+    // Squeezes classical and film music into a few dB, so the quiet
+    // passages can be heard without the loud ones waking anyone.
+    pub fn night_mode() -> Self {
+        Self {
+            enabled: true,
+            threshold: -30.0,
+            ratio: 6.0,
+            makeup: 12.0,
+        }
+    }
+
+    pub fn clamped(self) -> Self {
+        Self {
+            threshold: self.threshold.clamp(MIN_THRESHOLD, 0.0),
+            ratio: self.ratio.clamp(1.0, MAX_RATIO),
+            makeup: self.makeup.clamp(0.0, MAX_MAKEUP),
+            ..self
+        }
+    }
+
+    pub fn is_night_mode(&self) -> bool {
+        let night = Self::night_mode();
+        (self.threshold, self.ratio, self.makeup) == (night.threshold, night.ratio, night.makeup)
+    }
+
+    // dB the gain is lowered by at `level` dBFS, with a soft knee.
+    fn reduction(&self, level: f32) -> f32 {
+        let over = level - self.threshold;
+        let slope = 1.0 - 1.0 / self.ratio;
+        if over <= -KNEE_DB / 2.0 {
+            0.0
+        } else if over >= KNEE_DB / 2.0 {
+            over * slope
+        } else {
+            slope * (over + KNEE_DB / 2.0).powi(2) / (2.0 * KNEE_DB)
+        }
+    }
+}
+
+// Feed-forward compressor with a limiter behind it. Both channels are
+// lowered together so the stereo image stays put. `meter` gets the most the
+// gain was lowered by since the last update, in dB. The gain is worked out
+// once per frame, the level in dB only when it can be over the knee.
+pub struct Compressor<S> {
+    input: S,
+    settings: Arc<Mutex<CompressorSettings>>,
+    meter: Arc<Mutex<f32>>,
+    current: CompressorSettings,
+    attack: f32,
+    release: f32,
+    limiter_release: f32,
+    // linear peak where the knee begins, quieter frames aren't compressed
+    knee_start: f32,
+    ceiling: f32,
+    // dB, smoothed
+    reduction: f32,
+    makeup: f32,
+    // linear, 1.0 leaves the signal alone
+    limiter: f32,
+    // since the last update, the limiter is turned into dB only then
+    most_reduction: f32,
+    least_limiter: f32,
+    right: Option<f32>,
+    countdown: usize,
+}
+
+impl<S: Source<Item = f32>> Compressor<S> {
+    pub fn new(input: S, settings: Arc<Mutex<CompressorSettings>>, meter: Arc<Mutex<f32>>) -> Self {
+        let sample_rate = input.sample_rate() as f32;
+        let coefficient = |seconds: f32| (-1.0 / (seconds * sample_rate)).exp();
+        Self {
+            input,
+            settings,
+            meter,
+            current: CompressorSettings::default(),
+            attack: coefficient(ATTACK),
+            release: coefficient(RELEASE),
+            limiter_release: coefficient(LIMITER_RELEASE),
+            knee_start: 0.0,
+            ceiling: 10f32.powf(CEILING_DB / 20.0),
+            reduction: 0.0,
+            makeup: 0.0,
+            limiter: 1.0,
+            most_reduction: 0.0,
+            least_limiter: 1.0,
+            right: None,
+            countdown: 0,
+        }
+    }
+
+    fn update(&mut self) {
+        self.current = *self.settings.lock().unwrap();
+        self.knee_start = 10f32.powf((self.current.threshold - KNEE_DB / 2.0) / 20.0);
+        *self.meter.lock().unwrap() = self.most_reduction - 20.0 * self.least_limiter.log10();
+        self.most_reduction = 0.0;
+        self.least_limiter = 1.0;
+    }
+
+    fn is_settled(&self) -> bool {
+        self.reduction < 0.01 && self.makeup.abs() < 0.01 && self.limiter > 0.999
+    }
+}
+
+impl<S: Source<Item = f32>> Iterator for Compressor<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if let Some(right) = self.right.take() {
+            return Some(right);
+        }
+        let left = self.input.next()?;
+        let right = self.input.next().unwrap_or(0.0);
+
+        if self.countdown == 0 {
+            self.update();
+            self.countdown = UPDATE_INTERVAL;
+        }
+        self.countdown -= 1;
+        let settings = self.current;
+        if !settings.enabled && self.is_settled() {
+            self.reduction = 0.0;
+            self.makeup = 0.0;
+            self.limiter = 1.0;
+            self.right = Some(right);
+            return Some(left);
+        }
+
+        // switching off lets the gain glide back instead of jumping
+        let (target, makeup) = if settings.enabled {
+            let peak = left.abs().max(right.abs());
+            let target = if peak > self.knee_start {
+                settings.reduction(20.0 * peak.log10())
+            } else {
+                0.0
+            };
+            (target, settings.makeup)
+        } else {
+            (0.0, 0.0)
+        };
+        let coefficient = if target > self.reduction {
+            self.attack
+        } else {
+            self.release
+        };
+        self.reduction = target + coefficient * (self.reduction - target);
+        self.makeup = makeup + self.release * (self.makeup - makeup);
+
+        let gain = 10f32.powf((self.makeup - self.reduction) / 20.0);
+        let (left, right) = (left * gain, right * gain);
+
+        // no look-ahead, the limiter drops at once and recovers slowly
+        let peak = left.abs().max(right.abs());
+        let ceiling = self.ceiling;
+        let released = 1.0 - (1.0 - self.limiter) * self.limiter_release;
+        self.limiter = if settings.enabled && peak > ceiling {
+            released.min(ceiling / peak)
+        } else {
+            released
+        };
+
+        self.most_reduction = self.most_reduction.max(self.reduction);
+        self.least_limiter = self.least_limiter.min(self.limiter);
+        self.right = Some(right * self.limiter);
+        Some(left * self.limiter)
+    }
+}
+
+impl<S: Source<Item = f32>> Source for Compressor<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rodio::buffer::SamplesBuffer;
+
+    use super::*;
+
+    fn compress(settings: CompressorSettings, level: f32, seconds: usize) -> (Vec<f32>, f32) {
+        let input = SamplesBuffer::new(2, 44100, vec![level; 2 * 44100 * seconds]);
+        let meter = Arc::new(Mutex::new(0.0));
+        let settings = Arc::new(Mutex::new(settings));
+        let output = Compressor::new(input, settings, meter.clone()).collect();
+        let meter = *meter.lock().unwrap();
+        (output, meter)
+    }
+
+    fn to_db(level: f32) -> f32 {
+        20.0 * level.abs().log10()
+    }
+
+    #[test]
+    fn above_the_threshold_the_level_follows_the_ratio() {
+        let settings = CompressorSettings {
+            enabled: true,
+            threshold: -20.0,
+            ratio: 4.0,
+            makeup: 0.0,
+        };
+        // 14dB over comes out 3.5dB over
+        let level = 10f32.powf(-6.0 / 20.0);
+        let (output, meter) = compress(settings, level, 2);
+        let settled = *output.last().unwrap();
+        assert!((to_db(settled) - -16.5).abs() < 0.01);
+        assert!((meter - 10.5).abs() < 0.01);
+        // below the knee nothing happens
+        let (output, meter) = compress(settings, 10f32.powf(-30.0 / 20.0), 1);
+        assert!((to_db(*output.last().unwrap()) - -30.0).abs() < 1e-3);
+        assert_eq!(meter, 0.0);
+    }
+
+    #[test]
+    fn the_limiter_holds_the_ceiling() {
+        let settings = CompressorSettings {
+            enabled: true,
+            threshold: 0.0,
+            ratio: 1.0,
+            makeup: 12.0,
+        };
+        let (output, meter) = compress(settings, 0.5, 2);
+        let ceiling = 10f32.powf(CEILING_DB / 20.0);
+        assert!(output.iter().all(|s| *s <= ceiling + 1e-6));
+        assert!((output.last().unwrap() - ceiling).abs() < 1e-4);
+        // 0.5 made 12dB louder is 7dB over the ceiling, the makeup is still
+        // gliding in the last bit
+        assert!((meter - (to_db(0.5) + 12.0 - CEILING_DB)).abs() < 0.05);
+    }
+}
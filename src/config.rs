@@ -0,0 +1,109 @@
+use std::{collections::HashMap, env, fs, path::PathBuf};
+
+use serde::Deserialize;
+
+use crate::channels::ChannelMode;
+use crate::compressor::CompressorSettings;
+use crate::crossfeed::CrossfeedPreset;
+use crate::loudness::AlbumGrouping;
+use crate::output::OutputKind;
+use crate::replaygain::ReplayGainMode;
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub crossfade: f32,
+    // milliseconds of the fades on pause, resume, stop and skip
+    pub fade_ms: u64,
+    pub replaygain: ReplayGainMode,
+    pub scan_group_by: AlbumGrouping,
+    // name of the preset the equalizer starts with, empty keeps it off
+    pub eq_preset: String,
+    pub eq_auto_genre: bool,
+    // genre tag -> preset name
+    pub eq_genres: HashMap<String, String>,
+    pub output: OutputKind,
+    // start with the spectrum and level meters shown
+    pub visualizer: bool,
+    pub channel_mode: ChannelMode,
+    // -1.0 (left only) to 1.0 (right only)
+    pub balance: f32,
+    pub crossfeed: bool,
+    pub crossfeed_preset: CrossfeedPreset,
+    pub skip_silence: bool,
+    // dBFS, anything quieter counts as silence
+    pub silence_threshold: f32,
+    // remember where files were left off and go on from there
+    pub resume: bool,
+    // files at least this long are remembered
+    pub resume_min_minutes: u64,
+    // files below these are remembered whatever their length
+    pub resume_dirs: Vec<PathBuf>,
+    pub compressor: bool,
+    // dBFS
+    pub compressor_threshold: f32,
+    pub compressor_ratio: f32,
+    // dB
+    pub compressor_makeup: f32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            crossfade: 0.0,
+            fade_ms: 100,
+            replaygain: ReplayGainMode::Off,
+            scan_group_by: AlbumGrouping::Directory,
+            eq_preset: String::new(),
+            eq_auto_genre: false,
+            eq_genres: HashMap::new(),
+            output: OutputKind::Device,
+            visualizer: false,
+            channel_mode: ChannelMode::Stereo,
+            balance: 0.0,
+            crossfeed: false,
+            crossfeed_preset: CrossfeedPreset::Default,
+            skip_silence: false,
+            silence_threshold: -60.0,
+            resume: true,
+            resume_min_minutes: 20,
+            resume_dirs: Vec::new(),
+            compressor: false,
+            compressor_threshold: CompressorSettings::default().threshold,
+            compressor_ratio: CompressorSettings::default().ratio,
+            compressor_makeup: CompressorSettings::default().makeup,
+        }
+    }
+}
+
+impl Config {
+    // A missing file means the defaults, a broken one is reported to the
+    // user but should never keep the player from starting.
+    pub fn load() -> (Self, Option<String>) {
+        let Some(path) = config_dir().map(|dir| dir.join("config.toml")) else {
+            return (Self::default(), None);
+        };
+        let Ok(content) = fs::read_to_string(&path) else {
+            return (Self::default(), None);
+        };
+        match toml::from_str(&content) {
+            Ok(config) => (config, None),
+            Err(e) => (
+                Self::default(),
+                Some(format!(
+                    "Invalid config {}: {}",
+                    path.display(),
+                    e.message()
+                )),
+            ),
+        }
+    }
+}
+
+pub fn config_dir() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("term_music_rs"))
+}
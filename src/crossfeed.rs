@@ -0,0 +1,253 @@
+use std::{
+    f64::consts::PI,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rodio::Source;
+use serde::Deserialize;
+
+// frames between two looks at the settings
+const UPDATE_INTERVAL: usize = 512;
+// how much of the way between dry and filtered is made per frame
+const MIX_STEP: f32 = 0.002;
+
+// The presets of libbs2b: cut frequency in Hz and feed level in dB.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CrossfeedPreset {
+    // 700Hz, 4.5dB, close to a virtual speaker placement
+    #[default]
+    Default,
+    // 700Hz, 6dB, Chu Moy's circuit
+    Cmoy,
+    // 650Hz, 9.5dB, Jan Meier's circuit, the strongest
+    Jmeier,
+}
+
+impl CrossfeedPreset {
+    pub fn next(&self) -> Self {
+        match self {
+            Self::Default => Self::Cmoy,
+            Self::Cmoy => Self::Jmeier,
+            Self::Jmeier => Self::Default,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Default => "Default",
+            Self::Cmoy => "Chu Moy",
+            Self::Jmeier => "Jan Meier",
+        }
+    }
+
+    fn parameters(&self) -> (f64, f64) {
+        match self {
+            Self::Default => (700.0, 4.5),
+            Self::Cmoy => (700.0, 6.0),
+            Self::Jmeier => (650.0, 9.5),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct CrossfeedSettings {
+    pub enabled: bool,
+    pub preset: CrossfeedPreset,
+}
+
+#[derive(Clone, Copy)]
+struct Coefficients {
+    a0_lo: f64,
+    b1_lo: f64,
+    a0_hi: f64,
+    a1_hi: f64,
+    b1_hi: f64,
+    gain: f64,
+}
+
+impl Coefficients {
+    // As in libbs2b: a low pass feeds the other side, a high shelf keeps the
+    // overall response flat on the direct side.
+    fn new(preset: CrossfeedPreset, sample_rate: u32) -> Self {
+        let (cut, feed) = preset.parameters();
+        let gain_lo_db = feed * -5.0 / 6.0 - 3.0;
+        let gain_hi_db = feed / 6.0 - 3.0;
+        let gain_lo = 10f64.powf(gain_lo_db / 20.0);
+        let gain_hi = 1.0 - 10f64.powf(gain_hi_db / 20.0);
+        let cut_hi = cut * 2f64.powf((gain_lo_db - 20.0 * gain_hi.log10()) / 12.0);
+
+        let x = (-2.0 * PI * cut / sample_rate as f64).exp();
+        let (a0_lo, b1_lo) = (gain_lo * (1.0 - x), x);
+        let x = (-2.0 * PI * cut_hi / sample_rate as f64).exp();
+        let (a0_hi, a1_hi, b1_hi) = (1.0 - gain_hi * (1.0 - x), -x, x);
+        Self {
+            a0_lo,
+            b1_lo,
+            a0_hi,
+            a1_hi,
+            b1_hi,
+            gain: 1.0 / (1.0 - gain_hi + gain_lo),
+        }
+    }
+}
+
+// Bauer stereophonic-to-binaural crossfeed: lets some of each side leak into
+// the other ear, delayed and dulled the way it would be from speakers, so
+// hard panned recordings are less tiring on headphones. Switching it on and
+// off glides between the dry and the filtered signal.
+pub struct Crossfeed<S> {
+    input: S,
+    settings: Arc<Mutex<CrossfeedSettings>>,
+    preset: Option<CrossfeedPreset>,
+    coefficients: Coefficients,
+    enabled: bool,
+    mix: f32,
+    low: [f64; 2],
+    high: [f64; 2],
+    previous: [f64; 2],
+    right: Option<f32>,
+    countdown: usize,
+}
+
+impl<S: Source<Item = f32>> Crossfeed<S> {
+    pub fn new(input: S, settings: Arc<Mutex<CrossfeedSettings>>) -> Self {
+        let coefficients = Coefficients::new(CrossfeedPreset::Default, input.sample_rate());
+        Self {
+            input,
+            settings,
+            preset: None,
+            coefficients,
+            enabled: false,
+            mix: 0.0,
+            low: [0.0; 2],
+            high: [0.0; 2],
+            previous: [0.0; 2],
+            right: None,
+            countdown: 0,
+        }
+    }
+
+    fn update(&mut self) {
+        let settings = *self.settings.lock().unwrap();
+        self.enabled = settings.enabled;
+        if self.preset != Some(settings.preset) {
+            self.preset = Some(settings.preset);
+            self.coefficients = Coefficients::new(settings.preset, self.input.sample_rate());
+        }
+    }
+
+    fn filter(&mut self, input: [f64; 2]) -> [f64; 2] {
+        let c = self.coefficients;
+        for (ch, x) in input.into_iter().enumerate() {
+            self.low[ch] = c.a0_lo * x + c.b1_lo * self.low[ch];
+            self.high[ch] = c.a0_hi * x + c.a1_hi * self.previous[ch] + c.b1_hi * self.high[ch];
+        }
+        self.previous = input;
+        [
+            (self.high[0] + self.low[1]) * c.gain,
+            (self.high[1] + self.low[0]) * c.gain,
+        ]
+    }
+}
+
+impl<S: Source<Item = f32>> Iterator for Crossfeed<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if let Some(right) = self.right.take() {
+            return Some(right);
+        }
+        let left = self.input.next()?;
+        let right = self.input.next().unwrap_or(0.0);
+
+        if self.countdown == 0 {
+            self.update();
+            self.countdown = UPDATE_INTERVAL;
+        }
+        self.countdown -= 1;
+        let target = if self.enabled { 1.0 } else { 0.0 };
+        self.mix = if self.mix < target {
+            (self.mix + MIX_STEP).min(target)
+        } else {
+            (self.mix - MIX_STEP).max(target)
+        };
+        if self.mix == 0.0 {
+            // off, the filters start from silence when it comes back
+            self.low = [0.0; 2];
+            self.high = [0.0; 2];
+            self.previous = [0.0; 2];
+            self.right = Some(right);
+            return Some(left);
+        }
+
+        let [wet_left, wet_right] = self.filter([left as f64, right as f64]);
+        let mix = self.mix;
+        self.right = Some(right * (1.0 - mix) + wet_right as f32 * mix);
+        Some(left * (1.0 - mix) + wet_left as f32 * mix)
+    }
+}
+
+impl<S: Source<Item = f32>> Source for Crossfeed<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rodio::buffer::SamplesBuffer;
+
+    use super::*;
+
+    fn crossfeed(enabled: bool, samples: Vec<f32>) -> Crossfeed<SamplesBuffer<f32>> {
+        let settings = CrossfeedSettings {
+            enabled,
+            preset: CrossfeedPreset::Default,
+        };
+        let input = SamplesBuffer::new(2, 44100, samples);
+        Crossfeed::new(input, Arc::new(Mutex::new(settings)))
+    }
+
+    #[test]
+    fn hard_left_bleeds_low_passed_into_the_right() {
+        let mut crossfeed = crossfeed(true, vec![]);
+        crossfeed.update();
+        let response: Vec<[f64; 2]> = (0..44100)
+            .map(|i| Crossfeed::filter(&mut crossfeed, [if i == 0 { 1.0 } else { 0.0 }, 0.0]))
+            .collect();
+        // a one pole low pass at 700Hz: the bleed only decays, never rings
+        let pole = (-2.0 * PI * 700.0 / 44100.0).exp();
+        for pair in response.windows(2).take(1000) {
+            assert!((pair[1][1] - pair[0][1] * pole).abs() < 1e-12);
+        }
+        // and at low frequencies it is the feed level below the direct side
+        let left: f64 = response.iter().map(|[l, _]| l).sum();
+        let right: f64 = response.iter().map(|[_, r]| r).sum();
+        assert!((20.0 * (right / left).log10() + 4.5).abs() < 1e-3);
+        // the sum of both sides keeps the level of the input at DC
+        assert!((left + right - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn off_passes_through() {
+        let samples = vec![1.0, 0.0, 0.0, 0.0, -0.5, 0.25];
+        assert_eq!(
+            crossfeed(false, samples.clone()).collect::<Vec<_>>(),
+            samples
+        );
+    }
+}
@@ -0,0 +1,296 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+// INDEX times count frames of a CD, 75 to the second
+const FRAMES_PER_SECOND: u64 = 75;
+
+// One track of a cue sheet: a section of the audio file it refers to.
+#[derive(Clone)]
+pub struct CueTrack {
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    pub file: PathBuf,
+    pub start: Duration,
+    // `None` plays to the end of the file
+    pub end: Option<Duration>,
+}
+
+impl CueTrack {
+    pub fn name(&self) -> String {
+        let title = self
+            .title
+            .clone()
+            .unwrap_or_else(|| format!("Track {:02}", self.number));
+        match &self.performer {
+            Some(performer) => format!("{:02} {} - {}", self.number, title, performer),
+            None => format!("{:02} {}", self.number, title),
+        }
+    }
+
+    // Length of the section, `file_length` is needed for the last track.
+    pub fn length(&self, file_length: Duration) -> Duration {
+        self.end.unwrap_or(file_length).saturating_sub(self.start)
+    }
+}
+
+pub fn is_cue_sheet(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("cue"))
+}
+
+// Reads the tracks of a cue sheet. Tracks whose audio file is missing are
+// left out.
+pub fn read_cue_sheet(path: &Path) -> Result<Vec<CueTrack>, String> {
+    let bytes = fs::read(path).map_err(|e| e.to_string())?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut tracks = parse(&decode_text(&bytes), dir);
+    tracks.retain(|track| track.file.is_file());
+    if tracks.is_empty() {
+        return Err("No playable tracks in cue sheet".to_string());
+    }
+    Ok(tracks)
+}
+
+// Cue sheets from older rippers are often Latin-1 instead of UTF-8.
+fn decode_text(bytes: &[u8]) -> String {
+    let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(_) => bytes.iter().map(|&b| b as char).collect(),
+    }
+}
+
+fn parse(text: &str, dir: &Path) -> Vec<CueTrack> {
+    let mut album = None;
+    let mut album_performer = None;
+    let mut genre = None;
+    let mut file: Option<PathBuf> = None;
+    let mut tracks: Vec<CueTrack> = Vec::new();
+    // the track being read has no INDEX 01 yet
+    let mut pending: Option<CueTrack> = None;
+    // past the first TRACK line nothing is about the album any more
+    let mut in_tracks = false;
+    // the last of `tracks` is the one being read, its INDEX 01 came already
+    let mut indexed = false;
+
+    for line in text.lines() {
+        let (command, rest) = split_word(line.trim());
+        match command.to_ascii_uppercase().as_str() {
+            "REM" => {
+                let (key, value) = split_word(rest);
+                if key.eq_ignore_ascii_case("GENRE") {
+                    genre = Some(unquote(value));
+                }
+            }
+            "FILE" => {
+                // FILE "name.flac" WAVE, the type after the name is ignored
+                let name = match rest.strip_prefix('"') {
+                    Some(quoted) => quoted.split('"').next().unwrap_or_default(),
+                    None => rest.rsplit_once(' ').map_or(rest, |(name, _)| name),
+                };
+                file = Some(dir.join(name));
+            }
+            "TRACK" => {
+                let number = split_word(rest)
+                    .0
+                    .parse()
+                    .unwrap_or(tracks.len() as u32 + 1);
+                in_tracks = true;
+                indexed = false;
+                pending = file.clone().map(|file| CueTrack {
+                    number,
+                    title: None,
+                    performer: album_performer.clone(),
+                    album: album.clone(),
+                    genre: genre.clone(),
+                    file,
+                    start: Duration::ZERO,
+                    end: None,
+                });
+            }
+            "TITLE" | "PERFORMER" => {
+                let value = Some(unquote(rest));
+                let is_title = command.eq_ignore_ascii_case("TITLE");
+                // the lines of a track may come after its INDEX too
+                let track = match pending.as_mut() {
+                    Some(track) => Some(track),
+                    None if indexed => tracks.last_mut(),
+                    None => None,
+                };
+                match track {
+                    Some(track) if is_title => track.title = value,
+                    Some(track) => track.performer = value,
+                    None if in_tracks => {}
+                    None if is_title => album = value,
+                    None => album_performer = value,
+                }
+            }
+            "INDEX" => {
+                let (index, time) = split_word(rest);
+                if index.parse::<u32>() != Ok(1) {
+                    continue;
+                }
+                let (Some(mut track), Some(start)) = (pending.take(), parse_time(time)) else {
+                    continue;
+                };
+                track.start = start;
+                // the previous track of the same file ends where this one starts
+                if let Some(previous) = tracks.last_mut() {
+                    if previous.file == track.file {
+                        previous.end = Some(start);
+                    }
+                }
+                tracks.push(track);
+                indexed = true;
+            }
+            _ => {}
+        }
+    }
+    tracks
+}
+
+fn split_word(text: &str) -> (&str, &str) {
+    match text.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim()),
+        None => (text, ""),
+    }
+}
+
+fn unquote(text: &str) -> String {
+    let text = text.trim();
+    text.strip_prefix('"')
+        .and_then(|text| text.strip_suffix('"'))
+        .unwrap_or(text)
+        .to_string()
+}
+
+// mm:ss:ff
+fn parse_time(text: &str) -> Option<Duration> {
+    let mut parts = text.split(':').map(|part| part.trim().parse::<u64>().ok());
+    let (minutes, seconds, frames) = (parts.next()??, parts.next()??, parts.next()??);
+    // a broken sheet can have any number there
+    let frames = minutes
+        .checked_mul(60)?
+        .checked_add(seconds)?
+        .checked_mul(FRAMES_PER_SECOND)?
+        .checked_add(frames)?;
+    Some(Duration::from_nanos(
+        frames.checked_mul(1_000_000_000)? / FRAMES_PER_SECOND,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_sheet(text: &str) -> Vec<CueTrack> {
+        parse(text, Path::new("/music"))
+    }
+
+    #[test]
+    fn times_are_minutes_seconds_and_cd_frames() {
+        assert_eq!(parse_time("00:00:00"), Some(Duration::ZERO));
+        assert_eq!(parse_time("01:02:00"), Some(Duration::from_secs(62)));
+        assert_eq!(parse_time("00:01:15"), Some(Duration::from_millis(1200)));
+        // longer than an hour is still written in minutes
+        assert_eq!(parse_time("75:00:00"), Some(Duration::from_secs(4500)));
+        assert_eq!(parse_time("01:02"), None);
+        assert_eq!(parse_time("aa:02:03"), None);
+        assert_eq!(parse_time(""), None);
+        assert_eq!(parse_time("9999999:00:00"), None);
+        assert_eq!(parse_time("00:00:18446744073709551615"), None);
+    }
+
+    #[test]
+    fn tracks_end_where_the_next_one_starts() {
+        let tracks = parse_sheet(
+            "REM GENRE Jazz
+PERFORMER \"Band\"
+TITLE \"Album\"
+FILE \"album.flac\" WAVE
+  TRACK 01 AUDIO
+    TITLE \"One\"
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE \"Two\"
+    PERFORMER \"Guest\"
+    INDEX 00 03:00:00
+    INDEX 01 03:02:00
+",
+        );
+        assert_eq!(tracks.len(), 2);
+        let [one, two] = &tracks[..] else {
+            unreachable!()
+        };
+        assert_eq!(one.number, 1);
+        assert_eq!(one.title.as_deref(), Some("One"));
+        assert_eq!(one.performer.as_deref(), Some("Band"));
+        assert_eq!(one.album.as_deref(), Some("Album"));
+        assert_eq!(one.genre.as_deref(), Some("Jazz"));
+        assert_eq!(one.file, Path::new("/music/album.flac"));
+        // INDEX 00 is the pregap, the track starts at INDEX 01
+        assert_eq!(one.end, Some(Duration::from_secs(182)));
+        assert_eq!(two.start, Duration::from_secs(182));
+        assert_eq!(two.end, None);
+        assert_eq!(two.performer.as_deref(), Some("Guest"));
+        assert_eq!(two.name(), "02 Two - Guest");
+    }
+
+    #[test]
+    fn track_lines_after_the_index_stay_with_the_track() {
+        let tracks = parse_sheet(
+            "TITLE \"Album\"
+PERFORMER \"Band\"
+FILE album.wav WAVE
+TRACK 01 AUDIO
+INDEX 01 00:00:00
+TITLE \"One\"
+PERFORMER \"Singer\"
+TRACK 02 AUDIO
+INDEX 01 01:00:00
+",
+        );
+        assert_eq!(tracks[0].title.as_deref(), Some("One"));
+        assert_eq!(tracks[0].performer.as_deref(), Some("Singer"));
+        assert_eq!(tracks[0].album.as_deref(), Some("Album"));
+        assert_eq!(tracks[1].title, None);
+        assert_eq!(tracks[1].performer.as_deref(), Some("Band"));
+        assert_eq!(tracks[1].album.as_deref(), Some("Album"));
+        assert_eq!(tracks[0].file, Path::new("/music/album.wav"));
+    }
+
+    #[test]
+    fn a_new_file_starts_over() {
+        let tracks = parse_sheet(
+            "FILE \"one.flac\" WAVE
+TRACK 01 AUDIO
+INDEX 01 00:00:00
+TRACK 02 AUDIO
+TITLE \"No index\"
+FILE \"two.flac\" WAVE
+TRACK 03 AUDIO
+INDEX 01 00:00:00
+",
+        );
+        let numbers: Vec<u32> = tracks.iter().map(|track| track.number).collect();
+        assert_eq!(numbers, [1, 3]);
+        // the first file plays to its end
+        assert_eq!(tracks[0].end, None);
+        assert_eq!(tracks[1].file, Path::new("/music/two.flac"));
+    }
+
+    #[test]
+    fn latin1_sheets_are_read() {
+        assert_eq!(decode_text(b"TITLE \"Caf\xe9\""), "TITLE \"Caf\u{e9}\"");
+        assert_eq!(
+            decode_text("\u{feff}TITLE \"Café\"".as_bytes()),
+            "TITLE \"Café\""
+        );
+    }
+}
@@ -0,0 +1,373 @@
+use std::{fs::File, path::Path, sync::LazyLock, time::Duration};
+
+use rodio::{source::SeekError, Source};
+use symphonia::core::{
+    audio::{Channels, SampleBuffer},
+    codecs::{CodecRegistry, Decoder, DecoderOptions, CODEC_TYPE_NULL},
+    errors::Error,
+    formats::{FormatOptions, FormatReader, SeekMode, SeekTo},
+    io::MediaSourceStream,
+    meta::MetadataOptions,
+    probe::{Hint, Probe},
+    units::{Time, TimeBase},
+};
+
+use crate::wavpack::{WavPackDecoder, WavPackReader};
+
+// Decodes everything symphonia knows: MP3, AAC, ALAC, FLAC, Vorbis, WAV,
+// AIFF and ADPCM in MP4, MKV/WebM, Ogg, CAF, WAV and AIFF containers, plus
+// our own lossless WavPack and Opus through libopus.
+pub struct SymphoniaDecoder {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    time_base: Option<TimeBase>,
+    total_duration: Option<Duration>,
+    samples: Vec<f32>,
+    index: usize,
+    channels: u16,
+    sample_rate: u32,
+    // frames still to drop after an accurate seek landed before its target
+    skip_frames: u64,
+    // channels in the file, more than two are mixed down to stereo
+    file_channels: u16,
+    layout: Channels,
+    mix_down: bool,
+    downmix: Option<(Channels, Vec<(f32, f32)>)>,
+}
+
+static PROBE: LazyLock<Probe> = LazyLock::new(|| {
+    let mut probe = Probe::default();
+    symphonia::default::register_enabled_formats(&mut probe);
+    probe.register_all::<WavPackReader>();
+    probe
+});
+
+static CODECS: LazyLock<CodecRegistry> = LazyLock::new(|| {
+    let mut codecs = CodecRegistry::new();
+    symphonia::default::register_enabled_codecs(&mut codecs);
+    codecs.register_all::<WavPackDecoder>();
+    codecs.register_all::<crate::opus::OpusDecoder>();
+    codecs
+});
+
+struct OpenedFile {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+}
+
+// Probes the container and picks the first track there is a decoder for,
+// so a video or cover art track in front of the audio doesn't matter.
+fn open_file(path: &Path) -> Result<OpenedFile, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(extension);
+    }
+    let format_options = FormatOptions {
+        // trims encoder delay and padding, which gapless playback relies on
+        enable_gapless: true,
+        ..Default::default()
+    };
+    let probed = PROBE
+        .format(&hint, stream, &format_options, &MetadataOptions::default())
+        .map_err(|e| match e {
+            Error::Unsupported(_) => "Unsupported format".to_string(),
+            e => e.to_string(),
+        })?;
+    let format = probed.format;
+
+    let (track_id, decoder) = format
+        .tracks()
+        .iter()
+        .filter(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+        .find_map(|track| {
+            let decoder = CODECS
+                .make(&track.codec_params, &DecoderOptions::default())
+                .ok()?;
+            Some((track.id, decoder))
+        })
+        .ok_or("Unsupported codec")?;
+    Ok(OpenedFile {
+        format,
+        decoder,
+        track_id,
+    })
+}
+
+// Whether the file can be played. Only reads the headers.
+pub fn can_decode(path: &Path) -> bool {
+    open_file(path).is_ok()
+}
+
+impl SymphoniaDecoder {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        Self::open_with(path, true)
+    }
+
+    // Keeps every channel of the file, for measuring rather than playing.
+    pub fn open_unmixed(path: &Path) -> Result<Self, String> {
+        Self::open_with(path, false)
+    }
+
+    fn open_with(path: &Path, mix_down: bool) -> Result<Self, String> {
+        let OpenedFile {
+            format,
+            decoder,
+            track_id,
+        } = open_file(path)?;
+        let params = decoder.codec_params();
+        let time_base = params.time_base;
+        let total_duration = match (params.time_base, params.n_frames) {
+            (Some(time_base), Some(n_frames)) => Some(time_base.calc_time(n_frames).into()),
+            (None, Some(n_frames)) => params
+                .sample_rate
+                .map(|rate| Duration::from_secs_f64(n_frames as f64 / rate as f64)),
+            _ => None,
+        };
+        let mut decoder = Self {
+            format,
+            decoder,
+            track_id,
+            time_base,
+            total_duration,
+            samples: Vec::new(),
+            index: 0,
+            channels: 2,
+            sample_rate: 44100,
+            skip_frames: 0,
+            file_channels: 2,
+            layout: Channels::FRONT_LEFT | Channels::FRONT_RIGHT,
+            mix_down,
+            downmix: None,
+        };
+        // the first packet tells the real channel count and sample rate
+        if !decoder.decode_packet() {
+            return Err("No audio found".to_string());
+        }
+        Ok(decoder)
+    }
+
+    pub fn file_channels(&self) -> u16 {
+        self.file_channels
+    }
+
+    pub fn layout(&self) -> Channels {
+        self.layout
+    }
+
+    // Decodes the next packet of our track into `samples`. Returns false at
+    // the end of the stream. Always called as soon as the previous packet is
+    // used up, so `current_frame_len` is only 0 at the very end.
+    fn decode_packet(&mut self) -> bool {
+        self.samples.clear();
+        self.index = 0;
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(Error::ResetRequired) => {
+                    self.decoder.reset();
+                    continue;
+                }
+                Err(_) => return false,
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                // a damaged frame is skipped, not the rest of the song
+                Err(Error::DecodeError(_)) | Err(Error::IoError(_)) => continue,
+                Err(_) => return false,
+            };
+            let spec = *decoded.spec();
+            let frames = decoded.frames();
+            let mut buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+            buffer.copy_interleaved_ref(decoded);
+
+            let channels = spec.channels.count();
+            let trim_start = packet.trim_start() as usize;
+            let trim_end = packet.trim_end() as usize;
+            let skip = (self.skip_frames as usize).min(frames.saturating_sub(trim_start));
+            self.skip_frames -= skip as u64;
+            let start = (trim_start + skip).min(frames);
+            let end = frames.saturating_sub(trim_end).max(start);
+
+            let samples = &buffer.samples()[start * channels..end * channels];
+            self.file_channels = channels as u16;
+            self.layout = spec.channels;
+            if channels > 2 && self.mix_down {
+                let matrix = match &self.downmix {
+                    Some((layout, matrix)) if *layout == spec.channels => matrix,
+                    _ => {
+                        let matrix = downmix_matrix(spec.channels);
+                        &self.downmix.insert((spec.channels, matrix)).1
+                    }
+                };
+                for frame in samples.chunks_exact(channels) {
+                    let (left, right) = mix_down(frame, matrix);
+                    self.samples.push(left);
+                    self.samples.push(right);
+                }
+                self.channels = 2;
+            } else {
+                self.samples.extend_from_slice(samples);
+                self.channels = channels as u16;
+            }
+            self.sample_rate = spec.rate;
+            if !self.samples.is_empty() {
+                return true;
+            }
+        }
+    }
+}
+
+// How much of every channel goes to the left and right side, with the
+// usual -3dB for the centre and the surrounds (ITU-R BS.775). The LFE is
+// left out. Scaled so a signal on all channels at once can't clip.
+fn downmix_matrix(channels: Channels) -> Vec<(f32, f32)> {
+    const HALF_POWER: f32 = std::f32::consts::FRAC_1_SQRT_2;
+    let left = Channels::REAR_LEFT
+        | Channels::SIDE_LEFT
+        | Channels::FRONT_LEFT_CENTRE
+        | Channels::REAR_LEFT_CENTRE
+        | Channels::FRONT_LEFT_WIDE
+        | Channels::FRONT_LEFT_HIGH
+        | Channels::TOP_FRONT_LEFT
+        | Channels::TOP_REAR_LEFT;
+    let right = Channels::REAR_RIGHT
+        | Channels::SIDE_RIGHT
+        | Channels::FRONT_RIGHT_CENTRE
+        | Channels::REAR_RIGHT_CENTRE
+        | Channels::FRONT_RIGHT_WIDE
+        | Channels::FRONT_RIGHT_HIGH
+        | Channels::TOP_FRONT_RIGHT
+        | Channels::TOP_REAR_RIGHT;
+    let mut matrix: Vec<(f32, f32)> = channels
+        .iter()
+        .map(|channel| match channel {
+            Channels::FRONT_LEFT => (1.0, 0.0),
+            Channels::FRONT_RIGHT => (0.0, 1.0),
+            Channels::LFE1 | Channels::LFE2 => (0.0, 0.0),
+            c if left.contains(c) => (HALF_POWER, 0.0),
+            c if right.contains(c) => (0.0, HALF_POWER),
+            _ => (HALF_POWER, HALF_POWER),
+        })
+        .collect();
+    let left_sum: f32 = matrix.iter().map(|(l, _)| l).sum();
+    let right_sum: f32 = matrix.iter().map(|(_, r)| r).sum();
+    let scale = left_sum.max(right_sum).max(1.0);
+    for (l, r) in matrix.iter_mut() {
+        *l /= scale;
+        *r /= scale;
+    }
+    matrix
+}
+
+fn mix_down(frame: &[f32], matrix: &[(f32, f32)]) -> (f32, f32) {
+    let (mut left, mut right) = (0.0, 0.0);
+    for (sample, (to_left, to_right)) in frame.iter().zip(matrix) {
+        left += sample * to_left;
+        right += sample * to_right;
+    }
+    (left, right)
+}
+
+impl Iterator for SymphoniaDecoder {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let sample = *self.samples.get(self.index)?;
+        self.index += 1;
+        if self.index == self.samples.len() {
+            self.decode_packet();
+        }
+        Some(sample)
+    }
+}
+
+impl Source for SymphoniaDecoder {
+    // channel count and rate may change between packets
+    fn current_frame_len(&self) -> Option<usize> {
+        Some(self.samples.len() - self.index)
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.total_duration
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        let pos = match self.total_duration {
+            Some(total) => pos.min(total),
+            None => pos,
+        };
+        let seeked_to = self
+            .format
+            .seek(
+                SeekMode::Accurate,
+                SeekTo::Time {
+                    time: Time::new(pos.as_secs(), pos.subsec_nanos() as f64 / 1e9),
+                    track_id: Some(self.track_id),
+                },
+            )
+            .map_err(|e| match e {
+                Error::Unsupported(_) => SeekError::NotSupported {
+                    underlying_source: "SymphoniaDecoder",
+                },
+                e => SeekError::Other(Box::new(e)),
+            })?;
+        self.decoder.reset();
+        let skip = seeked_to.required_ts.saturating_sub(seeked_to.actual_ts);
+        self.skip_frames = match self.time_base {
+            Some(time_base) => {
+                let time = time_base.calc_time(skip);
+                time.seconds * self.sample_rate as u64
+                    + (time.frac * self.sample_rate as f64) as u64
+            }
+            None => skip,
+        };
+        self.decode_packet();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn surround_mixes_down_with_the_centre_and_rears_at_minus_3db() {
+        let layout = Channels::FRONT_LEFT
+            | Channels::FRONT_RIGHT
+            | Channels::FRONT_CENTRE
+            | Channels::LFE1
+            | Channels::REAR_LEFT
+            | Channels::REAR_RIGHT;
+        let matrix = downmix_matrix(layout);
+        let half = std::f32::consts::FRAC_1_SQRT_2;
+        // one front, the centre and one rear at -3dB add up on each side
+        let scale = 1.0 + 2.0 * half;
+        // FL FR FC LFE RL RR
+        let (left, right) = mix_down(&[0.1, 0.3, 0.2, 0.6, 0.4, 0.5], &matrix);
+        assert!((left - (0.1 + half * 0.2 + half * 0.4) / scale).abs() < 1e-6);
+        assert!((right - (0.3 + half * 0.2 + half * 0.5) / scale).abs() < 1e-6);
+        // the LFE is left out
+        assert_eq!(
+            mix_down(&[0.0, 0.0, 0.0, 1.0, 0.0, 0.0], &matrix),
+            (0.0, 0.0)
+        );
+        // full scale everywhere still fits
+        let (left, right) = mix_down(&[1.0; 6], &matrix);
+        assert!((left - 1.0).abs() < 1e-6 && (right - 1.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,482 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rodio::Source;
+use serde::{Deserialize, Serialize};
+
+use crate::biquad::Biquad;
+use crate::config::config_dir;
+
+pub const BAND_FREQUENCIES: [f64; 10] = [
+    31.0, 62.0, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16000.0,
+];
+pub const MAX_GAIN: f32 = 12.0;
+// one octave per band
+const BAND_Q: f64 = 1.41;
+// samples between two looks at the shared settings
+const UPDATE_INTERVAL: usize = 1024;
+const PRESETS_FILE: &str = "eq_presets.toml";
+
+pub type Gains = [f32; BAND_FREQUENCIES.len()];
+
+const BUILTIN_PRESETS: [(&str, Gains); 4] = [
+    ("Flat", [0.0; 10]),
+    (
+        "Bass Boost",
+        [6.0, 5.0, 4.0, 2.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+    ),
+    (
+        "Vocal",
+        [-3.0, -2.0, -1.0, 1.0, 3.0, 4.0, 3.0, 1.0, 0.0, -1.0],
+    ),
+    (
+        "Loudness",
+        [5.0, 4.0, 2.0, 0.0, -1.0, -1.0, 0.0, 2.0, 4.0, 5.0],
+    ),
+];
+
+// Used by the automatic selection when the config has no entry for a genre.
+const BUILTIN_GENRE_PRESETS: [(&str, &str); 8] = [
+    ("hip-hop", "Bass Boost"),
+    ("rap", "Bass Boost"),
+    ("electronic", "Bass Boost"),
+    ("dance", "Bass Boost"),
+    ("speech", "Vocal"),
+    ("audiobook", "Vocal"),
+    ("podcast", "Vocal"),
+    ("classical", "Flat"),
+];
+
+// Shared between the ui and the audio thread. Every change bumps
+// `generation` so the audio thread only recomputes filters when needed.
+#[derive(Default)]
+pub struct EqSettings {
+    enabled: bool,
+    gains: Gains,
+    generation: u64,
+}
+
+pub struct EqPreset {
+    pub name: String,
+    pub gains: Gains,
+    pub builtin: bool,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct PresetsFile {
+    presets: BTreeMap<String, Gains>,
+}
+
+// The equalizer as the user sees it in the eq panel.
+pub struct Equalizer {
+    settings: Arc<Mutex<EqSettings>>,
+    pub enabled: bool,
+    pub gains: Gains,
+    pub presets: Vec<EqPreset>,
+    // `None` once a band was changed by hand
+    pub preset_index: Option<usize>,
+    pub selected_band: usize,
+    pub auto_genre: bool,
+    genre_presets: HashMap<String, String>,
+    // preset to go back to when the genre of a track is unknown
+    manual_preset_index: Option<usize>,
+    manual_gains: Gains,
+}
+
+impl Equalizer {
+    pub fn new(
+        settings: Arc<Mutex<EqSettings>>,
+        preset: &str,
+        auto_genre: bool,
+        genre_presets: &HashMap<String, String>,
+    ) -> (Self, Option<String>) {
+        let mut presets: Vec<EqPreset> = BUILTIN_PRESETS
+            .iter()
+            .map(|(name, gains)| EqPreset {
+                name: name.to_string(),
+                gains: *gains,
+                builtin: true,
+            })
+            .collect();
+        let (user_presets, error) = load_user_presets();
+        presets.extend(user_presets);
+
+        let mut equalizer = Self {
+            settings,
+            enabled: false,
+            gains: [0.0; 10],
+            presets,
+            preset_index: Some(0),
+            selected_band: 0,
+            auto_genre,
+            genre_presets: genre_presets
+                .iter()
+                .map(|(genre, preset)| (genre.to_lowercase(), preset.clone()))
+                .collect(),
+            manual_preset_index: Some(0),
+            manual_gains: [0.0; 10],
+        };
+        if let Some(index) = equalizer.find_preset(preset) {
+            equalizer.enabled = true;
+            equalizer.select_preset(index);
+        }
+        (equalizer, error)
+    }
+
+    fn find_preset(&self, name: &str) -> Option<usize> {
+        self.presets
+            .iter()
+            .position(|preset| preset.name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn preset_name(&self) -> &str {
+        match self.preset_index {
+            Some(index) => &self.presets[index].name,
+            None => "Custom",
+        }
+    }
+
+    fn select_preset(&mut self, index: usize) {
+        self.preset_index = Some(index);
+        self.gains = self.presets[index].gains;
+        self.manual_preset_index = self.preset_index;
+        self.manual_gains = self.gains;
+        self.apply();
+    }
+
+    pub fn change_preset(&mut self, step: isize) {
+        let len = self.presets.len() as isize;
+        let index = match self.preset_index {
+            Some(index) => (index as isize + step).rem_euclid(len),
+            None => 0,
+        };
+        self.enabled = true;
+        self.select_preset(index as usize);
+    }
+
+    pub fn select_band(&mut self, step: isize) {
+        let len = BAND_FREQUENCIES.len() as isize;
+        self.selected_band = (self.selected_band as isize + step).clamp(0, len - 1) as usize;
+    }
+
+    pub fn change_gain(&mut self, db: f32) {
+        let gain = &mut self.gains[self.selected_band];
+        *gain = (*gain + db).clamp(-MAX_GAIN, MAX_GAIN);
+        self.enabled = true;
+        self.preset_index = None;
+        self.manual_preset_index = None;
+        self.manual_gains = self.gains;
+        self.apply();
+    }
+
+    pub fn reset_band(&mut self) {
+        let gain = self.gains[self.selected_band];
+        self.change_gain(-gain);
+    }
+
+    pub fn toggle(&mut self) {
+        self.enabled = !self.enabled;
+        self.apply();
+    }
+
+    // Turning it off goes back to what the user chose by hand.
+    pub fn toggle_auto_genre(&mut self) {
+        self.auto_genre = !self.auto_genre;
+        if !self.auto_genre {
+            self.restore_manual_preset();
+        }
+    }
+
+    fn restore_manual_preset(&mut self) {
+        self.preset_index = self.manual_preset_index;
+        self.gains = self.manual_gains;
+        self.apply();
+    }
+
+    // Picks the preset for the genre of a track that started to play. A
+    // genre without a preset brings back what the user chose by hand.
+    pub fn apply_genre(&mut self, genre: Option<&str>) {
+        if !self.auto_genre || !self.enabled {
+            return;
+        }
+        let preset = genre.and_then(|genre| {
+            let genre = genre.to_lowercase();
+            let name = self
+                .genre_presets
+                .get(&genre)
+                .map(String::as_str)
+                .or_else(|| {
+                    BUILTIN_GENRE_PRESETS
+                        .iter()
+                        .find(|(builtin, _)| *builtin == genre)
+                        .map(|(_, name)| *name)
+                })?;
+            self.find_preset(name)
+        });
+        match preset {
+            Some(index) => {
+                self.preset_index = Some(index);
+                self.gains = self.presets[index].gains;
+                self.apply();
+            }
+            None => self.restore_manual_preset(),
+        }
+    }
+
+    // Saves the current gains as a user preset, replacing one with the same
+    // name. Built-in presets can't be overwritten.
+    pub fn save_preset(&mut self, name: &str) -> Result<(), String> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err("A preset needs a name".to_string());
+        }
+        let index = match self.find_preset(name) {
+            Some(index) if self.presets[index].builtin => {
+                return Err(format!(
+                    "\"{}\" is a built-in preset",
+                    self.presets[index].name
+                ));
+            }
+            Some(index) => {
+                self.presets[index].gains = self.gains;
+                index
+            }
+            None => {
+                self.presets.push(EqPreset {
+                    name: name.to_string(),
+                    gains: self.gains,
+                    builtin: false,
+                });
+                self.presets.len() - 1
+            }
+        };
+        self.select_preset(index);
+        self.write_user_presets()
+    }
+
+    fn write_user_presets(&self) -> Result<(), String> {
+        let dir = config_dir().ok_or("No config directory found")?;
+        let file = PresetsFile {
+            presets: self
+                .presets
+                .iter()
+                .filter(|preset| !preset.builtin)
+                .map(|preset| (preset.name.clone(), preset.gains))
+                .collect(),
+        };
+        let content = toml::to_string(&file).map_err(|e| e.to_string())?;
+        fs::create_dir_all(&dir)
+            .and_then(|_| fs::write(dir.join(PRESETS_FILE), content))
+            .map_err(|e| e.to_string())
+    }
+
+    fn apply(&self) {
+        let mut settings = self.settings.lock().unwrap();
+        settings.enabled = self.enabled;
+        settings.gains = self.gains;
+        settings.generation += 1;
+    }
+}
+
+fn load_user_presets() -> (Vec<EqPreset>, Option<String>) {
+    let Some(path) = config_dir().map(|dir| dir.join(PRESETS_FILE)) else {
+        return (Vec::new(), None);
+    };
+    let Ok(content) = fs::read_to_string(&path) else {
+        return (Vec::new(), None);
+    };
+    match toml::from_str::<PresetsFile>(&content) {
+        Ok(file) => (
+            file.presets
+                .into_iter()
+                .filter(|(name, _)| {
+                    !BUILTIN_PRESETS
+                        .iter()
+                        .any(|(builtin, _)| builtin.eq_ignore_ascii_case(name))
+                })
+                .map(|(name, gains)| EqPreset {
+                    name,
+                    gains: gains.map(|gain| gain.clamp(-MAX_GAIN, MAX_GAIN)),
+                    builtin: false,
+                })
+                .collect(),
+            None,
+        ),
+        Err(e) => (
+            Vec::new(),
+            Some(format!(
+                "Invalid presets {}: {}",
+                path.display(),
+                e.message()
+            )),
+        ),
+    }
+}
+
+// Runs the output of `input` through one peaking filter per band.
+pub struct EqualizerSource<S> {
+    input: S,
+    settings: Arc<Mutex<EqSettings>>,
+    generation: u64,
+    active: bool,
+    // one filter per band and channel, bands at 0 dB are left out
+    filters: Vec<(usize, Vec<Biquad>)>,
+    // lowers everything by the largest boost so boosted bands can't clip
+    preamp: f32,
+    channel: usize,
+    countdown: usize,
+}
+
+impl<S: Source<Item = f32>> EqualizerSource<S> {
+    pub fn new(input: S, settings: Arc<Mutex<EqSettings>>) -> Self {
+        Self {
+            input,
+            settings,
+            generation: u64::MAX,
+            active: false,
+            filters: Vec::new(),
+            preamp: 1.0,
+            channel: 0,
+            countdown: 0,
+        }
+    }
+
+    fn update(&mut self) {
+        let settings = self.settings.lock().unwrap();
+        if settings.generation == self.generation {
+            return;
+        }
+        self.generation = settings.generation;
+        self.active = settings.enabled && settings.gains.iter().any(|&gain| gain != 0.0);
+
+        let sample_rate = self.input.sample_rate();
+        let channels = self.input.channels() as usize;
+        let mut filters = Vec::new();
+        for (band, &gain) in settings.gains.iter().enumerate() {
+            let freq = BAND_FREQUENCIES[band];
+            if gain == 0.0 || freq >= sample_rate as f64 / 2.0 {
+                continue;
+            }
+            let coefficients = Biquad::peaking(sample_rate, freq, BAND_Q, gain as f64);
+            // bands that were already active keep their state
+            let mut channel_filters = self
+                .filters
+                .iter()
+                .find(|(b, _)| *b == band)
+                .map(|(_, f)| f.clone())
+                .unwrap_or_else(|| vec![Biquad::default(); channels]);
+            for filter in channel_filters.iter_mut() {
+                filter.set_coefficients(&coefficients);
+            }
+            filters.push((band, channel_filters));
+        }
+        self.filters = filters;
+
+        let max_boost = settings.gains.iter().fold(0f32, |max, &gain| max.max(gain));
+        self.preamp = 10f32.powf(-max_boost / 20.0);
+    }
+}
+
+impl<S: Source<Item = f32>> Iterator for EqualizerSource<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.countdown == 0 && self.channel == 0 {
+            self.update();
+            self.countdown = UPDATE_INTERVAL;
+        }
+        self.countdown = self.countdown.saturating_sub(1);
+
+        let sample = self.input.next()?;
+        let channel = self.channel;
+        self.channel = (self.channel + 1) % self.input.channels() as usize;
+        if !self.active {
+            return Some(sample);
+        }
+        let mut y = (sample * self.preamp) as f64;
+        for (_, filters) in self.filters.iter_mut() {
+            y = filters[channel].process(y);
+        }
+        Some(y as f32)
+    }
+}
+
+impl<S: Source<Item = f32>> Source for EqualizerSource<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rodio::buffer::SamplesBuffer;
+
+    use super::*;
+
+    fn equalizer(preset: &str, genres: &[(&str, &str)]) -> Equalizer {
+        let genres = genres
+            .iter()
+            .map(|(genre, preset)| (genre.to_string(), preset.to_string()))
+            .collect();
+        let settings = Arc::new(Mutex::new(EqSettings::default()));
+        Equalizer::new(settings, preset, true, &genres).0
+    }
+
+    #[test]
+    fn flat_preset_passes_the_signal_through() {
+        let equalizer = equalizer("Flat", &[]);
+        assert!(equalizer.enabled);
+        let samples: Vec<f32> = (0..4000).map(|i| (i as f32 * 0.37).sin() * 0.8).collect();
+        let source = SamplesBuffer::new(2, 44100, samples.clone());
+        let output: Vec<f32> = EqualizerSource::new(source, equalizer.settings.clone()).collect();
+        assert_eq!(output, samples);
+    }
+
+    #[test]
+    fn genre_tags_pick_the_preset() {
+        let mut equalizer = equalizer("Loudness", &[("Rock", "Vocal")]);
+        // the config wins and case doesn't matter
+        equalizer.apply_genre(Some("ROCK"));
+        assert_eq!(equalizer.preset_name(), "Vocal");
+        equalizer.apply_genre(Some("Hip-Hop"));
+        assert_eq!(equalizer.preset_name(), "Bass Boost");
+        equalizer.apply_genre(Some("polka"));
+        assert_eq!(equalizer.preset_name(), "Loudness");
+        equalizer.apply_genre(Some("podcast"));
+        assert_eq!(equalizer.preset_name(), "Vocal");
+        equalizer.apply_genre(None);
+        assert_eq!(equalizer.preset_name(), "Loudness");
+    }
+
+    #[test]
+    fn turning_auto_genre_off_restores_the_manual_preset() {
+        let mut equalizer = equalizer("Flat", &[]);
+        equalizer.select_band(3);
+        equalizer.change_gain(2.0);
+        equalizer.apply_genre(Some("rap"));
+        assert_eq!(equalizer.preset_name(), "Bass Boost");
+        equalizer.toggle_auto_genre();
+        assert_eq!(equalizer.preset_name(), "Custom");
+        assert_eq!(equalizer.gains[3], 2.0);
+        assert_eq!(equalizer.settings.lock().unwrap().gains[3], 2.0);
+        // and stays there
+        equalizer.apply_genre(Some("rap"));
+        assert_eq!(equalizer.preset_name(), "Custom");
+    }
+}
@@ -1,13 +1,12 @@
 use std::{fs, io, path::{Path, PathBuf}};
 
-pub fn check_audio_file(path: &PathBuf) -> Result<bool, io::Error> {
-    if let Some(t) = infer::get_from_path(path.to_str().unwrap())? {
-        let mime_type = t.mime_type();
+use crate::decoder::can_decode;
 
-        return Ok(mime_type.contains("audio"));
-    }
-
-    Ok(false)
+// Probes the file the same way playing it does, so whatever is accepted
+// here can also be played.
+pub fn check_audio_file(path: &Path) -> Result<bool, io::Error> {
+    fs::metadata(path)?;
+    Ok(can_decode(path))
 }
 
 pub fn get_entrys(folder_path: &Path) -> Vec<PathBuf> {
@@ -28,8 +28,9 @@ impl HelpTable{
                 vec!["G".to_string(), "Select Last Item".to_string()],
                 vec!["a | Enter".to_string(), "Add Music To Playing List".to_string()],
                 vec!["A".to_string(), "Add All The Music In This Folder To Playing List".to_string()],
-                vec!["o".to_string(), "Open Folder".to_string()],
+                vec!["o".to_string(), "Open Folder Or Cue Sheet".to_string()],
                 vec!["Backspace".to_string(), "Close Folder".to_string()],
+                vec!["R".to_string(), "Scan Loudness And Write ReplayGain Tags For This Folder".to_string()],
                 vec!["Tab".to_string(), "Helper".to_string()],
                 vec!["".to_string(), "".to_string()],
 
@@ -44,16 +45,77 @@ impl HelpTable{
                 vec!["Enter".to_string(), "Play Current Music".to_string()],
                 vec!["p".to_string(), "Play / Pause".to_string()],
                 vec!["s".to_string(), "Stop Playing".to_string()],
+                vec!["S".to_string(), "Stop After This Song".to_string()],
                 vec!["n".to_string(), "Play Next Music".to_string()],
                 vec!["d".to_string(), "Remove from Playing List(slow)".to_string()],
                 vec!["D".to_string(), "Remove from Playing List(fast, but may change order)".to_string()],
                 vec!["m".to_string(), "Change Playing Mod (Auto|Repeat|Random|Manual)".to_string()],
                 vec!["+".to_string(), "Volume Up".to_string()],
                 vec!["-".to_string(), "Volume Down".to_string()],
+                vec![", | .".to_string(), "Seek Backward / Forward 5s".to_string()],
+                vec!["< | >".to_string(), "Seek Backward / Forward 30s".to_string()],
+                vec![":".to_string(), "Jump To Position (mm:ss)".to_string()],
+                vec!["x | X".to_string(), "Crossfade Shorter / Longer (0-12s)".to_string()],
+                vec!["r".to_string(), "Change ReplayGain Mode (Off|Track|Album)".to_string()],
+                vec!["e".to_string(), "Equalizer".to_string()],
+                vec!["[ | ]".to_string(), "Speed Down / Up, Pitch Unchanged (0.5x-3x)".to_string()],
+                vec!["\\".to_string(), "Normal Speed".to_string()],
+                vec!["a | b".to_string(), "Set Loop Point A / B".to_string()],
+                vec!["c".to_string(), "Clear A-B Loop".to_string()],
+                vec!["; | '".to_string(), "Balance Left / Right".to_string()],
+                vec!["\"".to_string(), "Center Balance".to_string()],
+                vec!["M".to_string(), "Change Channels (Stereo,Mono,Swap)".to_string()],
+                vec!["f".to_string(), "Headphone Crossfeed On / Off".to_string()],
+                vec!["F".to_string(), "Crossfeed Strength (Default,Chu Moy,Jan Meier)".to_string()],
+                vec!["L".to_string(), "Compressor".to_string()],
+                vec!["v".to_string(), "Spectrum And Level Meters On / Off".to_string()],
+                vec!["C".to_string(), "Chapter List".to_string()],
+                vec!["N | P".to_string(), "Next / Previous Chapter".to_string()],
+                vec!["z".to_string(), "Sleep Timer".to_string()],
+                vec!["( | )".to_string(), "Move Point A Back / Forward 0.1s".to_string()],
+                vec!["{ | }".to_string(), "Move Point B Back / Forward 0.1s".to_string()],
                 vec!["Tab".to_string(), "Helper".to_string()],
                 vec!["".to_string(), "".to_string()],
 
 
+                vec![">>>Equalizer<<<".to_string(), "".to_string()],
+                vec!["q | ESC | e".to_string(), "Back To Playing List".to_string()],
+                vec!["j | Down".to_string(), "Select Next Band".to_string()],
+                vec!["k | Up".to_string(), "Select Previous Band".to_string()],
+                vec!["l | Right".to_string(), "Raise Band By 1 dB".to_string()],
+                vec!["h | Left".to_string(), "Lower Band By 1 dB".to_string()],
+                vec!["0".to_string(), "Reset Band".to_string()],
+                vec!["p | P".to_string(), "Next / Previous Preset".to_string()],
+                vec!["Space".to_string(), "Equalizer On / Off".to_string()],
+                vec!["a".to_string(), "Automatic Preset By Genre On / Off".to_string()],
+                vec!["S".to_string(), "Save As Preset".to_string()],
+                vec!["".to_string(), "".to_string()],
+
+
+
+                vec![">>>Chapters<<<".to_string(), "".to_string()],
+                vec!["q | ESC | C".to_string(), "Back To Playing List".to_string()],
+                vec!["j | Down".to_string(), "Select Next Chapter".to_string()],
+                vec!["k | Up".to_string(), "Select Previous Chapter".to_string()],
+                vec!["g | G".to_string(), "Select First / Last Chapter".to_string()],
+                vec!["Enter".to_string(), "Jump To Chapter".to_string()],
+                vec!["N | P".to_string(), "Next / Previous Chapter".to_string()],
+                vec!["".to_string(), "".to_string()],
+
+
+
+                vec![">>>Compressor<<<".to_string(), "".to_string()],
+                vec!["q | ESC | L".to_string(), "Back To Playing List".to_string()],
+                vec!["j | Down".to_string(), "Select Next Setting".to_string()],
+                vec!["k | Up".to_string(), "Select Previous Setting".to_string()],
+                vec!["l | Right".to_string(), "Raise Setting".to_string()],
+                vec!["h | Left".to_string(), "Lower Setting".to_string()],
+                vec!["0".to_string(), "Reset Setting".to_string()],
+                vec!["n".to_string(), "Night Mode".to_string()],
+                vec!["Space".to_string(), "Compressor On / Off".to_string()],
+                vec!["".to_string(), "".to_string()],
+
+
 
                 vec![">>>Helper<<<".to_string(), "".to_string()],
                 vec!["j | Down".to_string(), "Select Next Item".to_string()],
@@ -0,0 +1,601 @@
+use std::{
+    collections::BTreeMap,
+    f64::consts::PI,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use color_eyre::{eyre::eyre, Result};
+use lofty::{
+    config::WriteOptions,
+    file::TaggedFileExt,
+    tag::{ItemKey, Tag, TagExt},
+};
+use rodio::Source;
+use serde::Deserialize;
+
+use symphonia::core::audio::Channels;
+
+use crate::biquad::Biquad;
+use crate::decoder::SymphoniaDecoder;
+use crate::file::check_audio_file;
+use crate::music::get_song_info;
+
+// ReplayGain 2.0 reference level
+const REFERENCE_LOUDNESS: f64 = -18.0;
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+const OVERSAMPLING: usize = 4;
+const TAPS_PER_PHASE: usize = 12;
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlbumGrouping {
+    #[default]
+    Directory,
+    Tag,
+}
+
+// Integrated loudness and true peak as specified by EBU R128 / ITU-R BS.1770.
+struct LoudnessMeter {
+    filters: Vec<(Biquad, Biquad)>,
+    weights: Vec<f64>,
+    sub_block_frames: usize,
+    frames_in_sub_block: usize,
+    sub_block_energy: f64,
+    recent_sub_blocks: [f64; 4],
+    sub_blocks_seen: usize,
+    blocks: Vec<f64>,
+    true_peak: TruePeak,
+}
+
+struct TrackLoudness {
+    blocks: Vec<f64>,
+    peak: f64,
+}
+
+impl LoudnessMeter {
+    fn new(weights: Vec<f64>, sample_rate: u32) -> Self {
+        let channels = weights.len();
+        Self {
+            filters: vec![k_weighting(sample_rate); channels],
+            weights,
+            // 400ms gating blocks overlapping by 75% are built from 100ms parts
+            sub_block_frames: (sample_rate as usize / 10).max(1),
+            frames_in_sub_block: 0,
+            sub_block_energy: 0.0,
+            recent_sub_blocks: [0.0; 4],
+            sub_blocks_seen: 0,
+            blocks: Vec::new(),
+            true_peak: TruePeak::new(channels, sample_rate),
+        }
+    }
+
+    fn push_frame(&mut self, frame: &[f32]) {
+        for (c, &x) in frame.iter().enumerate() {
+            let (pre_filter, rlb_filter) = &mut self.filters[c];
+            let y = rlb_filter.process(pre_filter.process(x as f64));
+            self.sub_block_energy += self.weights[c] * y * y;
+        }
+        self.true_peak.push_frame(frame);
+
+        self.frames_in_sub_block += 1;
+        if self.frames_in_sub_block < self.sub_block_frames {
+            return;
+        }
+        self.recent_sub_blocks[self.sub_blocks_seen % 4] = self.sub_block_energy;
+        self.sub_blocks_seen += 1;
+        self.sub_block_energy = 0.0;
+        self.frames_in_sub_block = 0;
+        if self.sub_blocks_seen >= 4 {
+            let energy: f64 = self.recent_sub_blocks.iter().sum();
+            self.blocks
+                .push(energy / (4 * self.sub_block_frames) as f64);
+        }
+    }
+
+    fn finish(self) -> TrackLoudness {
+        TrackLoudness {
+            blocks: self.blocks,
+            peak: self.true_peak.peak as f64,
+        }
+    }
+}
+
+// The two stage K-weighting filter, coefficients as in libebur128 so that
+// they are correct for any sample rate and not only for 48kHz.
+fn k_weighting(sample_rate: u32) -> (Biquad, Biquad) {
+    let rate = sample_rate as f64;
+
+    let f0 = 1681.974450955533;
+    let gain = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let pre_filter = Biquad::new(
+        [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let rlb_filter = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    (pre_filter, rlb_filter)
+}
+
+fn energy_to_loudness(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+fn loudness_to_energy(loudness: f64) -> f64 {
+    10f64.powf((loudness + 0.691) / 10.0)
+}
+
+fn integrated_loudness(blocks: &[f64]) -> Option<f64> {
+    let absolute_gate = loudness_to_energy(ABSOLUTE_GATE);
+    let mean = |threshold: f64| {
+        let gated: Vec<f64> = blocks.iter().copied().filter(|&e| e > threshold).collect();
+        (!gated.is_empty()).then(|| gated.iter().sum::<f64>() / gated.len() as f64)
+    };
+    let relative_gate = mean(absolute_gate)? * 10f64.powf(RELATIVE_GATE / 10.0);
+    mean(absolute_gate.max(relative_gate)).map(energy_to_loudness)
+}
+
+// Peaks between samples are found by 4x oversampling with a windowed sinc,
+// which is enough for the ReplayGain clipping prevention.
+struct TruePeak {
+    taps: Vec<f32>,
+    history: Vec<[f32; TAPS_PER_PHASE]>,
+    position: usize,
+    oversample: bool,
+    peak: f32,
+}
+
+impl TruePeak {
+    fn new(channels: usize, sample_rate: u32) -> Self {
+        let len = OVERSAMPLING * TAPS_PER_PHASE;
+        let center = (len - 1) as f64 / 2.0;
+        let taps = (0..len)
+            .map(|i| {
+                let x = (i as f64 - center) / OVERSAMPLING as f64;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (PI * x).sin() / (PI * x)
+                };
+                let window = 0.42 - 0.5 * (2.0 * PI * i as f64 / (len - 1) as f64).cos()
+                    + 0.08 * (4.0 * PI * i as f64 / (len - 1) as f64).cos();
+                (sinc * window) as f32
+            })
+            .collect();
+        Self {
+            taps,
+            history: vec![[0.0; TAPS_PER_PHASE]; channels],
+            position: 0,
+            oversample: sample_rate < 96000,
+            peak: 0.0,
+        }
+    }
+
+    fn push_frame(&mut self, frame: &[f32]) {
+        for (c, &x) in frame.iter().enumerate() {
+            self.peak = self.peak.max(x.abs());
+            if !self.oversample {
+                continue;
+            }
+            let history = &mut self.history[c];
+            history[self.position] = x;
+            for phase in 0..OVERSAMPLING {
+                let y: f32 = (0..TAPS_PER_PHASE)
+                    .map(|k| {
+                        let sample = history[(self.position + TAPS_PER_PHASE - k) % TAPS_PER_PHASE];
+                        self.taps[phase + k * OVERSAMPLING] * sample
+                    })
+                    .sum();
+                self.peak = self.peak.max(y.abs());
+            }
+        }
+        self.position = (self.position + 1) % TAPS_PER_PHASE;
+    }
+}
+
+// BS.1770 weights per channel, in the order symphonia gives them: no LFE,
+// surround channels +1.5 dB. With both side and rear pairs (7.1) the sides
+// are the surrounds and the rears count like the fronts.
+fn channel_weights(layout: Channels) -> Vec<f64> {
+    let sides = Channels::SIDE_LEFT | Channels::SIDE_RIGHT;
+    let surrounds = if layout.intersects(sides) {
+        sides
+    } else {
+        Channels::REAR_LEFT | Channels::REAR_RIGHT
+    };
+    layout
+        .iter()
+        .map(|channel| {
+            if channel.intersects(Channels::LFE1 | Channels::LFE2) {
+                0.0
+            } else if surrounds.contains(channel) {
+                1.41
+            } else {
+                1.0
+            }
+        })
+        .collect()
+}
+
+fn analyze_file(path: &Path) -> Result<TrackLoudness, String> {
+    // measured before the downmix, which would hide the surround channels
+    let decoder = SymphoniaDecoder::open_unmixed(path)?;
+    let channels = decoder.channels();
+    let mut meter = LoudnessMeter::new(channel_weights(decoder.layout()), decoder.sample_rate());
+
+    let mut frame = Vec::with_capacity(channels as usize);
+    for sample in decoder {
+        frame.push(sample);
+        if frame.len() == channels as usize {
+            meter.push_frame(&frame);
+            frame.clear();
+        }
+    }
+    Ok(meter.finish())
+}
+
+fn write_tags(
+    path: &Path,
+    track_gain: f64,
+    track_peak: f64,
+    album_gain: Option<f64>,
+    album_peak: f64,
+) -> lofty::error::Result<()> {
+    let mut tagged_file = lofty::probe::Probe::open(path)?.read()?;
+    if tagged_file.primary_tag().is_none() {
+        let tag_type = tagged_file.primary_tag_type();
+        tagged_file.insert_tag(Tag::new(tag_type));
+    }
+    let tag = tagged_file.primary_tag_mut().unwrap();
+
+    tag.insert_text(ItemKey::ReplayGainTrackGain, format_gain(track_gain));
+    tag.insert_text(ItemKey::ReplayGainTrackPeak, format_peak(track_peak));
+    if let Some(album_gain) = album_gain {
+        tag.insert_text(ItemKey::ReplayGainAlbumGain, format_gain(album_gain));
+        tag.insert_text(ItemKey::ReplayGainAlbumPeak, format_peak(album_peak));
+    }
+    tag.save_to_path(path, WriteOptions::default())
+}
+
+fn format_gain(gain: f64) -> String {
+    format!("{:.2} dB", gain)
+}
+
+fn format_peak(peak: f64) -> String {
+    format!("{:.6}", peak)
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum AlbumKey {
+    Directory(PathBuf),
+    Tag(Option<String>, String),
+}
+
+fn group_albums(files: Vec<PathBuf>, grouping: AlbumGrouping) -> Vec<Vec<PathBuf>> {
+    let mut albums: BTreeMap<AlbumKey, Vec<PathBuf>> = BTreeMap::new();
+    for path in files {
+        let directory = || AlbumKey::Directory(path.parent().unwrap_or(&path).to_path_buf());
+        let key = match grouping {
+            AlbumGrouping::Directory => directory(),
+            AlbumGrouping::Tag => match get_song_info(&path) {
+                Some(info) if info.album.is_some() => {
+                    AlbumKey::Tag(info.album_artist, info.album.unwrap_or_default())
+                }
+                _ => directory(),
+            },
+        };
+        albums.entry(key).or_default().push(path);
+    }
+    albums.into_values().collect()
+}
+
+pub enum ScanEvent<'a> {
+    Analyzing(&'a Path),
+    Done(&'a Path, Result<String, String>),
+}
+
+// Analyzes every file, then writes the track and album values once all
+// tracks of an album are known.
+pub fn scan(files: Vec<PathBuf>, grouping: AlbumGrouping, mut report: impl FnMut(ScanEvent)) {
+    for album in group_albums(files, grouping) {
+        let mut analyzed = Vec::new();
+        for path in album {
+            report(ScanEvent::Analyzing(&path));
+            match analyze_file(&path) {
+                Ok(loudness) => analyzed.push((path, loudness)),
+                Err(e) => report(ScanEvent::Done(&path, Err(e))),
+            }
+        }
+
+        let album_blocks: Vec<f64> = analyzed
+            .iter()
+            .flat_map(|(_, loudness)| loudness.blocks.iter().copied())
+            .collect();
+        let album_gain = integrated_loudness(&album_blocks).map(|l| REFERENCE_LOUDNESS - l);
+        let album_peak = analyzed
+            .iter()
+            .map(|(_, loudness)| loudness.peak)
+            .fold(0.0, f64::max);
+
+        for (path, loudness) in analyzed {
+            let result = match integrated_loudness(&loudness.blocks) {
+                Some(l) => {
+                    let gain = REFERENCE_LOUDNESS - l;
+                    write_tags(&path, gain, loudness.peak, album_gain, album_peak)
+                        .map(|_| {
+                            format!("{}, peak {}", format_gain(gain), format_peak(loudness.peak))
+                        })
+                        .map_err(|e| e.to_string())
+                }
+                None => Err("too short or silent to measure".to_string()),
+            };
+            report(ScanEvent::Done(&path, result));
+        }
+    }
+}
+
+pub fn collect_audio_files(path: &Path) -> Vec<PathBuf> {
+    if path.is_file() {
+        return match check_audio_file(path) {
+            Ok(true) => vec![path.to_path_buf()],
+            _ => Vec::new(),
+        };
+    }
+    let Ok(entries) = fs::read_dir(path) else {
+        return Vec::new();
+    };
+    let mut entries: Vec<fs::DirEntry> = entries.flatten().collect();
+    entries.sort_by_key(|entry| entry.path());
+    entries
+        .iter()
+        .flat_map(|entry| {
+            let path = entry.path();
+            match entry.file_type() {
+                // a link to a directory may point back up the tree
+                Ok(file_type) if file_type.is_symlink() && path.is_dir() => Vec::new(),
+                Ok(_) => collect_audio_files(&path),
+                Err(_) => Vec::new(),
+            }
+        })
+        .collect()
+}
+
+#[derive(Default)]
+pub struct ScanProgress {
+    pub total: usize,
+    pub analyzing: usize,
+    pub current: String,
+    pub tagged: usize,
+    pub failed: usize,
+    pub finished: bool,
+    // set by the scan, cleared once the progress was shown
+    pub changed: bool,
+}
+
+pub fn spawn_scan(folder: PathBuf, grouping: AlbumGrouping) -> Arc<Mutex<ScanProgress>> {
+    let progress = Arc::new(Mutex::new(ScanProgress::default()));
+    let progress_clone = progress.clone();
+    thread::spawn(move || {
+        let files = collect_audio_files(&folder);
+        let mut progress = progress_clone.lock().unwrap();
+        progress.total = files.len();
+        progress.changed = true;
+        drop(progress);
+        scan(files, grouping, |event| {
+            let mut progress = progress_clone.lock().unwrap();
+            progress.changed = true;
+            match event {
+                ScanEvent::Analyzing(path) => {
+                    progress.analyzing += 1;
+                    progress.current = file_name(path);
+                }
+                ScanEvent::Done(_, Ok(_)) => progress.tagged += 1,
+                ScanEvent::Done(_, Err(_)) => progress.failed += 1,
+            }
+        });
+        let mut progress = progress_clone.lock().unwrap();
+        progress.finished = true;
+        progress.changed = true;
+    });
+    progress
+}
+
+// term_music_rs scan [--group-by directory|tag] PATH...
+pub fn run_cli(args: &[String]) -> Result<()> {
+    let mut grouping = AlbumGrouping::Directory;
+    let mut files = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--group-by" => {
+                grouping = match args.next().map(String::as_str) {
+                    Some("directory") => AlbumGrouping::Directory,
+                    Some("tag") => AlbumGrouping::Tag,
+                    _ => return Err(eyre!("--group-by expects \"directory\" or \"tag\"")),
+                }
+            }
+            path => files.extend(collect_audio_files(Path::new(path))),
+        }
+    }
+    if files.is_empty() {
+        return Err(eyre!("no audio files found"));
+    }
+
+    let total = files.len();
+    let mut analyzing = 0;
+    let mut failed = 0;
+    scan(files, grouping, |event| match event {
+        ScanEvent::Analyzing(path) => {
+            analyzing += 1;
+            println!("[{}/{}] analyzing {}", analyzing, total, path.display());
+        }
+        ScanEvent::Done(path, Ok(result)) => println!("{}: {}", path.display(), result),
+        ScanEvent::Done(path, Err(e)) => {
+            failed += 1;
+            eprintln!("{}: {}", path.display(), e);
+        }
+    });
+
+    if failed > 0 {
+        return Err(eyre!("{} of {} files could not be tagged", failed, total));
+    }
+    Ok(())
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(freq: f64, sample_rate: u32, seconds: f64) -> impl Iterator<Item = f64> {
+        let frames = (seconds * sample_rate as f64) as usize;
+        (0..frames).map(move |i| (2.0 * PI * freq * i as f64 / sample_rate as f64).sin())
+    }
+
+    // Steady state gain of both K-weighting stages in dB, measured over the
+    // second second of a sine.
+    fn k_weighting_gain(freq: f64, sample_rate: u32) -> f64 {
+        let (mut pre_filter, mut rlb_filter) = k_weighting(sample_rate);
+        let settle = sample_rate as usize;
+        let energy: f64 = sine(freq, sample_rate, 2.0)
+            .map(|x| rlb_filter.process(pre_filter.process(x)))
+            .skip(settle)
+            .map(|y| y * y)
+            .sum();
+        10.0 * (energy / settle as f64 / 0.5).log10()
+    }
+
+    fn measure(channels: usize, sample_rate: u32, frames: impl Iterator<Item = Vec<f32>>) -> f64 {
+        let mut meter = LoudnessMeter::new(vec![1.0; channels], sample_rate);
+        for frame in frames {
+            meter.push_frame(&frame);
+        }
+        integrated_loudness(&meter.finish().blocks).unwrap()
+    }
+
+    #[test]
+    fn k_weighting_is_the_same_at_any_sample_rate() {
+        for sample_rate in [44100, 48000, 96000] {
+            // the 0.691 in the loudness formula makes up for this gain
+            assert!((k_weighting_gain(997.0, sample_rate) - 0.691).abs() < 0.02);
+            // the high shelf
+            assert!((k_weighting_gain(8000.0, sample_rate) - 4.0).abs() < 0.2);
+            // the high pass
+            assert!(k_weighting_gain(10.0, sample_rate) < -20.0);
+        }
+    }
+
+    #[test]
+    fn full_scale_sine_on_one_channel_is_minus_3_lufs() {
+        // the calibration signal of ITU-R BS.1770
+        let frames = sine(997.0, 48000, 10.0).map(|x| vec![x as f32, 0.0]);
+        let loudness = measure(2, 48000, frames);
+        assert!((loudness + 3.01).abs() < 0.05, "{}", loudness);
+    }
+
+    #[test]
+    fn surround_channels_are_weighted_and_the_lfe_left_out() {
+        let front = Channels::FRONT_LEFT | Channels::FRONT_RIGHT | Channels::FRONT_CENTRE;
+        // 5.1 as FLAC and WAV have it, with the surrounds as rear channels
+        let layout = front | Channels::LFE1 | Channels::REAR_LEFT | Channels::REAR_RIGHT;
+        assert_eq!(channel_weights(layout), [1.0, 1.0, 1.0, 0.0, 1.41, 1.41]);
+        let layout = front | Channels::LFE1 | Channels::SIDE_LEFT | Channels::SIDE_RIGHT;
+        assert_eq!(channel_weights(layout), [1.0, 1.0, 1.0, 0.0, 1.41, 1.41]);
+        // 7.1
+        let layout = layout | Channels::REAR_LEFT | Channels::REAR_RIGHT;
+        let weights = channel_weights(layout);
+        assert_eq!(weights, [1.0, 1.0, 1.0, 0.0, 1.0, 1.0, 1.41, 1.41]);
+    }
+
+    #[test]
+    fn surround_channels_count_more_than_front_ones() {
+        let layout = Channels::FRONT_LEFT | Channels::FRONT_RIGHT | Channels::REAR_LEFT;
+        let front = measure_weighted(layout, [1.0, 0.0, 0.0]);
+        let rear = measure_weighted(layout, [0.0, 0.0, 1.0]);
+        assert!((rear - front - 1.49).abs() < 0.05, "{} {}", front, rear);
+    }
+
+    fn measure_weighted(layout: Channels, gains: [f32; 3]) -> f64 {
+        let mut meter = LoudnessMeter::new(channel_weights(layout), 48000);
+        for x in sine(997.0, 48000, 5.0) {
+            meter.push_frame(&gains.map(|gain| gain * x as f32));
+        }
+        integrated_loudness(&meter.finish().blocks).unwrap()
+    }
+
+    #[test]
+    fn gating_blocks_overlap_by_three_quarters() {
+        let mut meter = LoudnessMeter::new(vec![1.0], 48000);
+        for x in sine(997.0, 48000, 1.0) {
+            meter.push_frame(&[x as f32]);
+        }
+        // 10 parts of 100ms, every 4 in a row make a block
+        assert_eq!(meter.finish().blocks.len(), 7);
+    }
+
+    #[test]
+    fn blocks_below_the_absolute_gate_are_left_out() {
+        let mut blocks = vec![loudness_to_energy(-20.0); 10];
+        blocks.extend(vec![loudness_to_energy(-75.0); 1000]);
+        let loudness = integrated_loudness(&blocks).unwrap();
+        assert!((loudness + 20.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn blocks_below_the_relative_gate_are_left_out() {
+        // the gate ends up about 13 LU below -20, so -35 is out
+        let mut blocks = vec![loudness_to_energy(-20.0); 10];
+        blocks.extend(vec![loudness_to_energy(-35.0); 10]);
+        let loudness = integrated_loudness(&blocks).unwrap();
+        assert!((loudness + 20.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn blocks_above_both_gates_are_averaged_by_energy() {
+        let blocks = [loudness_to_energy(-20.0), loudness_to_energy(-26.0)];
+        let expected = energy_to_loudness(blocks.iter().sum::<f64>() / 2.0);
+        assert!((integrated_loudness(&blocks).unwrap() - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn silence_has_no_loudness() {
+        assert_eq!(integrated_loudness(&[]), None);
+        assert_eq!(integrated_loudness(&[loudness_to_energy(-80.0); 10]), None);
+    }
+
+    #[test]
+    fn true_peak_finds_peaks_between_samples() {
+        // a quarter of the sample rate, sampled at 45 degrees, never hits 1.0
+        let mut true_peak = TruePeak::new(1, 48000);
+        for i in 0..4800 {
+            let x = (PI / 2.0 * i as f64 + PI / 4.0).sin() as f32;
+            true_peak.push_frame(&[x]);
+        }
+        assert!(true_peak.peak > 0.95, "{}", true_peak.peak);
+    }
+}
@@ -1,19 +1,54 @@
 mod app;
+mod analyzer;
+mod biquad;
+mod channels;
+mod chapters;
+mod compressor;
+mod loudness;
 mod appui;
+mod config;
+mod crossfeed;
+mod cue;
+mod decoder;
+mod equalizer;
 mod music;
+mod opus;
+mod output;
 mod file;
 mod helper;
-use color_eyre::Result;
+mod playback;
+mod replaygain;
+mod resume;
+mod stretch;
+mod wavpack;
+use color_eyre::{eyre::eyre, Result};
 use app::App;
+use output::OutputKind;
 
 
 
 fn main() -> Result<()> {
     color_eyre::install()?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("scan") {
+        return loudness::run_cli(&args[1..]);
+    }
+    let output = parse_output_arg(&args)?;
     let terminal = ratatui::init();
-    let app_result = App::default().run(terminal);
+    let app_result = App::new(output).run(terminal);
     ratatui::restore();
     app_result
 }
 
-
+// term_music_rs [--output device|null|wav:PATH]
+fn parse_output_arg(args: &[String]) -> Result<Option<OutputKind>> {
+    match args {
+        [] => Ok(None),
+        [flag, value] if flag == "--output" => OutputKind::try_from(value.clone())
+            .map(Some)
+            .map_err(|e| eyre!(e)),
+        _ => Err(eyre!(
+            "usage: term_music_rs [--output device|null|wav:PATH]\n       term_music_rs scan [--group-by directory|tag] PATH..."
+        )),
+    }
+}
@@ -1,116 +1,785 @@
 use std::{
-    io::BufReader,
-    path::PathBuf,
-    sync::{Arc, Mutex},
-    thread,
-    time::Duration,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
-use lofty::file::AudioFile;
-use rodio::{OutputStream, OutputStreamHandle, Sink};
+use lofty::{
+    file::{AudioFile, TaggedFileExt},
+    tag::{Accessor, ItemKey},
+};
+use rodio::{
+    source::{SeekError, UniformSourceIterator},
+    Sink, Source,
+};
+
+use crate::analyzer::{Analyzer, Spectrum};
+use crate::channels::{ChannelMix, ChannelSettings};
+use crate::chapters::{chapters_from_tag, Chapter};
+use crate::compressor::{Compressor, CompressorSettings};
+use crate::crossfeed::{Crossfeed, CrossfeedSettings};
+use crate::decoder::SymphoniaDecoder;
+use crate::equalizer::{EqSettings, EqualizerSource};
+use crate::output::{Output, OutputKind};
+use crate::playback::{
+    Activity, IdleGate, LoopMarker, Playback, PlaybackState, Track, TrackSource, CHANNELS,
+};
+use crate::replaygain::{ReplayGain, ReplayGainMode};
+use crate::stretch::{TimeStretch, MAX_SPEED, MIN_SPEED};
+
+// roughly what the time stretcher and the sound card buffer hold
+const OUTPUT_LATENCY: Duration = Duration::from_millis(100);
+// how often the audio thread looks for tracks that ended while playing
+const WATCH_INTERVAL: Duration = Duration::from_millis(50);
+// how far the end of the sleep fade may move before it is set again
+const SLEEP_FADE_SLACK: Duration = Duration::from_secs(1);
 
+struct TrackRequest {
+    id: u64,
+    file: PathBuf,
+    // start and end of the part to play, for the tracks of a cue sheet
+    section: Option<(Duration, Option<Duration>)>,
+    // where playing begins, into the section
+    position: Duration,
+}
+
+// What the audio thread is asked to do, done in the order it was asked.
+enum Command {
+    Play(TrackRequest),
+    Enqueue {
+        request: TrackRequest,
+        after: u64,
+        crossfade: bool,
+    },
+    ClearQueue(u64),
+    TogglePause,
+    Seek(Duration),
+    Stop,
+    SetVolume(f32),
+    SetReplayGainMode(ReplayGainMode),
+    SetSilenceSkip(Option<f32>),
+    // stops, waits up to this long for the fade out and ends the thread
+    Quit(Duration),
+}
+
+// What the audio thread did, reported in the order it happened.
+pub enum PlayerEvent {
+    // the track of `play_new` is playing
+    Started(u64),
+    // the track of `enqueue` follows the current one
+    Queued(u64),
+    // the track of `enqueue` was refused, the current track changed first
+    NotQueued(u64),
+    // the queue behind this track was cleared
+    QueueCleared(u64),
+    // the file of `play_new` or `enqueue` can't be played
+    Failed(u64, String),
+    Seeked(Result<(), SeekError>),
+    // the current track changed, the queued one started or the last one ended
+    TrackChanged,
+    // another second of the current track was played
+    Tick,
+}
+
+// Hands commands to the audio thread, which owns the sink and opens the
+// files, so a slow disk never holds up the keys. What is playing is read
+// straight from the shared playback state.
 pub struct MusicHandle {
-    sink: Arc<Sink>,
-    music_output: Arc<(OutputStream, OutputStreamHandle)>,
-    time_played: Arc<Mutex<u32>>,
+    commands: Sender<Command>,
+    worker: Option<JoinHandle<()>>,
+    _output: Output,
+    playback: Arc<Mutex<PlaybackState>>,
+    equalizer: Arc<Mutex<EqSettings>>,
+    speed: Arc<Mutex<f32>>,
+    channels: Arc<Mutex<ChannelSettings>>,
+    crossfeed: Arc<Mutex<CrossfeedSettings>>,
+    compressor: Arc<Mutex<CompressorSettings>>,
+    // dB the compressor lowers the gain by right now
+    gain_reduction: Arc<Mutex<f32>>,
+    analyzer: Analyzer,
+    next_track_id: u64,
+    // the track sent to play but not opened yet, 0 for none
+    loading: Arc<AtomicU64>,
+    // the latest track sent to be queued, 0 for none
+    queued: Arc<AtomicU64>,
     volume: f32,
+    // when the sleep fade of the playback gets silent, `None` without one
+    sleep_fade_end: Option<Instant>,
+    replay_gain_mode: ReplayGainMode,
+    fade: Duration,
+}
+
+// Lives as long as the music handle, the only thread that opens files and
+// switches tracks.
+struct AudioWorker {
+    sink: Sink,
+    playback: Arc<Mutex<PlaybackState>>,
+    notify: Box<dyn Fn(PlayerEvent) + Send>,
+    loading: Arc<AtomicU64>,
+    queued: Arc<AtomicU64>,
+    sample_rate: u32,
+    replay_gain_mode: ReplayGainMode,
+    silence_threshold: Option<f32>,
+    // what `watch` saw last time
+    watched: (Option<u64>, u64),
 }
 
 impl MusicHandle {
-    pub fn new() -> Self {
-        Self {
-            sink: Arc::new(Sink::new_idle().0),
-            music_output: Arc::new(OutputStream::try_default().unwrap()),
-            time_played: Arc::new(Mutex::new(0)),
+    // Falls back to the null output when the requested one can't be opened,
+    // the returned message tells why. `notify` is called from the audio
+    // thread for every `PlayerEvent`.
+    pub fn new(
+        output: &OutputKind,
+        notify: impl Fn(PlayerEvent) + Send + 'static,
+    ) -> (Self, Option<String>) {
+        let activity = Arc::new(Activity::default());
+        let (output, sink, sample_rate, error) = match Output::open(output, activity.clone()) {
+            Ok((output, sink, sample_rate)) => (output, sink, sample_rate, None),
+            Err(e) => {
+                let (output, sink, sample_rate) =
+                    Output::open(&OutputKind::Null, activity.clone())
+                        .expect("the null output needs no hardware");
+                let error = format!("Audio output unavailable ({}), playing silently", e);
+                (output, sink, sample_rate, Some(error))
+            }
+        };
+        let playback = Arc::new(Mutex::new(PlaybackState::new(sample_rate, activity)));
+
+        let equalizer = Arc::new(Mutex::new(EqSettings::default()));
+        let speed = Arc::new(Mutex::new(1.0));
+        let channels = Arc::new(Mutex::new(ChannelSettings::default()));
+        let crossfeed = Arc::new(Mutex::new(CrossfeedSettings::default()));
+        let compressor = Arc::new(Mutex::new(CompressorSettings::default()));
+        let gain_reduction = Arc::new(Mutex::new(0.0));
+
+        let source = Playback::new(playback.clone(), sample_rate);
+        let jumps = playback.lock().unwrap().jumps();
+        let source = TimeStretch::new(source, speed.clone(), jumps);
+        let source = EqualizerSource::new(source, equalizer.clone());
+        let source = ChannelMix::new(source, channels.clone());
+        let source = Crossfeed::new(source, crossfeed.clone());
+        let source = Compressor::new(source, compressor.clone(), gain_reduction.clone());
+        let analyzer = Analyzer::new(sample_rate);
+        // while nothing plays none of the above runs
+        sink.append(IdleGate::new(analyzer.tap(source), playback.clone()));
+
+        let (commands, command_receiver) = mpsc::channel();
+        let loading = Arc::new(AtomicU64::new(0));
+        let queued = Arc::new(AtomicU64::new(0));
+        let worker = AudioWorker {
+            sink,
+            playback: playback.clone(),
+            notify: Box::new(notify),
+            loading: loading.clone(),
+            queued: queued.clone(),
+            sample_rate,
+            replay_gain_mode: ReplayGainMode::Off,
+            silence_threshold: None,
+            watched: (None, 0),
+        };
+        let worker = thread::spawn(move || worker.run(command_receiver));
+
+        let handle = Self {
+            commands,
+            worker: Some(worker),
+            _output: output,
+            playback,
+            equalizer,
+            speed,
+            channels,
+            crossfeed,
+            compressor,
+            gain_reduction,
+            analyzer,
+            next_track_id: 0,
+            loading,
+            queued,
             volume: 1.0,
+            sleep_fade_end: None,
+            replay_gain_mode: ReplayGainMode::Off,
+            fade: Duration::ZERO,
+        };
+        (handle, error)
+    }
+
+    fn send(&self, command: Command) {
+        // the worker only ends on `Quit`
+        let _ = self.commands.send(command);
+    }
+
+    fn is_loading(&self) -> bool {
+        self.loading.load(Ordering::Relaxed) != 0
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_track_id += 1;
+        self.next_track_id
+    }
+
+    // Whatever is playing goes on until the file is open, and if it can't
+    // be played. The result comes as `Started` or `Failed`.
+    pub fn play_new(
+        &mut self,
+        file_name: PathBuf,
+        section: Option<(Duration, Option<Duration>)>,
+        position: Duration,
+    ) -> u64 {
+        let id = self.next_id();
+        self.loading.store(id, Ordering::Relaxed);
+        self.queued.store(0, Ordering::Relaxed);
+        self.send(Command::Play(TrackRequest {
+            id,
+            file: file_name,
+            section,
+            position,
+        }));
+        id
+    }
+
+    // Queues the track that starts right after the current one ends. The
+    // request is refused if the track `after` is no longer the current one
+    // or another track was queued since, so a queue decided for an outdated
+    // track can never be played.
+    pub fn enqueue(
+        &mut self,
+        file_name: PathBuf,
+        section: Option<(Duration, Option<Duration>)>,
+        position: Duration,
+        after: u64,
+        crossfade: bool,
+    ) -> u64 {
+        let id = self.next_id();
+        self.queued.store(id, Ordering::Relaxed);
+        self.send(Command::Enqueue {
+            request: TrackRequest {
+                id,
+                file: file_name,
+                section,
+                position,
+            },
+            after,
+            crossfade,
+        });
+        id
+    }
+
+    pub fn clear_queue(&mut self, after: u64) {
+        self.queued.store(0, Ordering::Relaxed);
+        self.send(Command::ClearQueue(after));
+    }
+
+    pub fn set_replay_gain_mode(&mut self, mode: ReplayGainMode) {
+        self.replay_gain_mode = mode;
+        self.send(Command::SetReplayGainMode(mode));
+    }
+
+    pub fn replay_gain_mode(&self) -> ReplayGainMode {
+        self.replay_gain_mode
+    }
+
+    // Silence quieter than `threshold_db` (dBFS) at the start and end of the
+    // tracks is skipped, `None` plays everything.
+    pub fn set_silence_skip(&mut self, threshold_db: Option<f32>) {
+        let threshold = threshold_db.map(|db| 10f32.powf(db / 20.0));
+        self.send(Command::SetSilenceSkip(threshold));
+    }
+
+    pub fn set_crossfade(&mut self, t: Duration) {
+        self.playback.lock().unwrap().set_crossfade(t);
+    }
+
+    pub fn change_speed(&mut self, step: f32) {
+        let mut speed = self.speed.lock().unwrap();
+        // round so repeated steps land on exact values again, 1.0 in particular
+        *speed = ((*speed + step) * 100.0)
+            .round()
+            .clamp(MIN_SPEED * 100.0, MAX_SPEED * 100.0)
+            / 100.0;
+    }
+
+    pub fn reset_speed(&mut self) {
+        *self.speed.lock().unwrap() = 1.0;
+    }
+
+    pub fn speed(&self) -> f32 {
+        *self.speed.lock().unwrap()
+    }
+
+    pub fn set_channel_settings(&mut self, settings: ChannelSettings) {
+        *self.channels.lock().unwrap() = ChannelSettings {
+            balance: settings.balance.clamp(-1.0, 1.0),
+            ..settings
+        };
+    }
+
+    pub fn channel_settings(&self) -> ChannelSettings {
+        *self.channels.lock().unwrap()
+    }
+
+    pub fn change_balance(&mut self, step: f32) {
+        let mut channels = self.channels.lock().unwrap();
+        channels.balance = ((channels.balance + step) * 10.0)
+            .round()
+            .clamp(-10.0, 10.0)
+            / 10.0;
+    }
+
+    pub fn center_balance(&mut self) {
+        self.channels.lock().unwrap().balance = 0.0;
+    }
+
+    pub fn next_channel_mode(&mut self) {
+        let mut channels = self.channels.lock().unwrap();
+        channels.mode = channels.mode.next();
+    }
+
+    pub fn set_crossfeed(&mut self, settings: CrossfeedSettings) {
+        *self.crossfeed.lock().unwrap() = settings;
+    }
+
+    pub fn crossfeed(&self) -> CrossfeedSettings {
+        *self.crossfeed.lock().unwrap()
+    }
+
+    pub fn toggle_crossfeed(&mut self) {
+        let mut crossfeed = self.crossfeed.lock().unwrap();
+        crossfeed.enabled = !crossfeed.enabled;
+    }
+
+    // Switches it on as well, the preset is heard right away.
+    pub fn next_crossfeed_preset(&mut self) {
+        let mut crossfeed = self.crossfeed.lock().unwrap();
+        if crossfeed.enabled {
+            crossfeed.preset = crossfeed.preset.next();
         }
+        crossfeed.enabled = true;
     }
-    pub fn play_new(&mut self, file_name: PathBuf) {
-        self.sink.stop();
-        *self.time_played.lock().unwrap() = 0;
 
-        self.sink = Arc::new(Sink::try_new(&self.music_output.1).unwrap());
+    pub fn set_compressor(&mut self, settings: CompressorSettings) {
+        *self.compressor.lock().unwrap() = settings;
+    }
 
-        let file = std::fs::File::open(&file_name).unwrap();
-        self.sink
-            .append(rodio::Decoder::new(BufReader::new(file)).unwrap());
+    pub fn compressor(&self) -> CompressorSettings {
+        *self.compressor.lock().unwrap()
+    }
 
-        self.sink.set_volume(self.volume);
+    // The compressor isn't run while paused, its last reading would stick.
+    pub fn gain_reduction(&self) -> f32 {
+        if self.is_empty() || self.is_paused() {
+            return 0.0;
+        }
+        *self.gain_reduction.lock().unwrap()
+    }
 
-        let sclone = self.sink.clone();
-        let tpclone = self.time_played.clone();
+    // More than two means the file is mixed down to stereo.
+    pub fn current_file_channels(&self) -> Option<u16> {
+        self.playback.lock().unwrap().current_file_channels()
+    }
 
-        let _t1 = thread::spawn(move || {
-            // let file = BufReader::new(File::open(file_name).unwrap());
-            // let source = rodio::Decoder::new(file).unwrap();
+    pub fn set_visualizer(&mut self, visible: bool) {
+        self.analyzer.set_enabled(visible);
+    }
 
-            let sink_clone_2 = sclone.clone();
-            let tpclone2 = tpclone.clone();
-            // sclone.append(source);
+    pub fn visualizer_visible(&self) -> bool {
+        self.analyzer.is_enabled()
+    }
 
-            let _ = thread::spawn(move || {
-                while sink_clone_2.len() == 1 {
-                    thread::sleep(Duration::from_secs(1));
-                    if !sink_clone_2.is_paused() {
-                        *tpclone2.lock().unwrap() += 1;
-                    }
-                }
-            });
-            sclone.sleep_until_end();
-        });
+    // The visualizer rests while paused or stopped.
+    pub fn update_visualizer(&self) {
+        self.analyzer
+            .set_playing(!self.is_empty() && !self.is_paused());
     }
 
-    pub fn play_pause(&mut self) {
-        if self.sink.is_paused() {
-            self.sink.play()
-        } else {
-            self.sink.pause()
+    pub fn spectrum(&self) -> Spectrum {
+        self.analyzer.spectrum()
+    }
+
+    pub fn equalizer_settings(&self) -> Arc<Mutex<EqSettings>> {
+        self.equalizer.clone()
+    }
+
+    // `None` while a new track is being opened.
+    pub fn current_track_id(&self) -> Option<u64> {
+        if self.is_loading() {
+            return None;
         }
+        self.playback.lock().unwrap().current_id()
+    }
+
+    pub fn set_fade(&mut self, t: Duration) {
+        self.fade = t;
+        self.playback.lock().unwrap().set_fade(t);
     }
 
+    pub fn play_pause(&mut self) {
+        self.send(Command::TogglePause);
+    }
+
+    // A track being opened counts as playing.
     pub fn is_empty(&self) -> bool {
-        self.sink.empty()
+        !self.is_loading() && self.playback.lock().unwrap().current.is_none()
     }
 
     pub fn stop(&mut self) {
-        self.sink.stop();
+        self.loading.store(0, Ordering::Relaxed);
+        self.queued.store(0, Ordering::Relaxed);
+        self.send(Command::Stop);
+    }
+
+    // Stops and waits for the fade out, so quitting doesn't click either.
+    // The audio thread is done afterwards.
+    pub fn fade_out(&mut self) {
+        self.send(Command::Quit(self.fade * 2));
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+
+    pub fn set_loop_marker(&mut self, marker: LoopMarker) {
+        self.playback.lock().unwrap().set_loop_marker(marker);
+    }
+
+    pub fn nudge_loop_marker(&mut self, marker: LoopMarker, offset_ms: i64) {
+        let offset = Duration::from_millis(offset_ms.unsigned_abs());
+        self.playback
+            .lock()
+            .unwrap()
+            .nudge_loop_marker(marker, offset, offset_ms > 0);
+    }
+
+    pub fn clear_loop(&mut self) {
+        self.playback.lock().unwrap().clear_loop();
+    }
+
+    pub fn loop_markers(&self) -> (Option<Duration>, Option<Duration>) {
+        self.playback.lock().unwrap().loop_markers()
     }
 
-    pub fn set_time_played(&mut self, t: u32) {
-        *self.time_played.lock().unwrap() = t;
+    pub fn time_played(&self) -> Duration {
+        if self.is_loading() {
+            return Duration::ZERO;
+        }
+        self.playback.lock().unwrap().position()
+    }
+
+    // The result comes as `Seeked`.
+    pub fn seek_to(&mut self, t: Duration) {
+        self.send(Command::Seek(t));
     }
-    pub fn time_played(&self) -> u32 {
-        *self.time_played.lock().unwrap()
+
+    pub fn seek_relative(&mut self, offset: i64) {
+        let played = self.time_played();
+        let offset_abs = Duration::from_secs(offset.unsigned_abs());
+        let t = if offset < 0 {
+            played.saturating_sub(offset_abs)
+        } else {
+            played + offset_abs
+        };
+        self.seek_to(t)
     }
+
     pub fn is_paused(&self) -> bool {
-        self.sink.is_paused()
+        self.playback.lock().unwrap().is_paused()
     }
 
     pub fn change_volume(&mut self, volume: f32) {
-        self.volume += volume;
-        if self.volume < 0. {
-            self.volume = 0.;
-        } else if self.volume > 1. {
-            self.volume = 1.;
-        }
-        self.sink.set_volume(self.volume)
+        self.volume = (self.volume + volume).clamp(0., 1.);
+        self.send(Command::SetVolume(self.volume));
     }
     pub fn get_volume(&self) -> f32 {
-        self.sink.volume()
+        self.volume
+    }
+
+    // Fades the music out until `left` from now. The playback is only told
+    // again when a seek or a pause moved the end.
+    pub fn fade_to_sleep(&mut self, left: Duration) {
+        let end = Instant::now() + left;
+        let moved = self.sleep_fade_end.is_none_or(|old| {
+            old.saturating_duration_since(end)
+                .max(end.saturating_duration_since(old))
+                > SLEEP_FADE_SLACK
+        });
+        if moved {
+            self.sleep_fade_end = Some(end);
+            // the playback goes by the time of the tracks, sped up or not
+            let t = left.mul_f32(self.speed());
+            self.playback.lock().unwrap().fade_to_sleep(t);
+        }
+    }
+
+    pub fn cancel_sleep_fade(&mut self) {
+        if self.sleep_fade_end.take().is_some() {
+            self.playback.lock().unwrap().cancel_sleep_fade();
+        }
     }
 }
 
-pub fn get_song_length(path: &PathBuf) -> Option<u32> {
-    let path = std::path::Path::new(&path);
-    let tagged_file = match lofty::probe::Probe::open(path)
-        .expect("ERROR: Bad path provided!")
-        .read()
-    {
-        Ok(item) => item,
-        Err(_) => return None,
+impl AudioWorker {
+    fn run(mut self, commands: Receiver<Command>) {
+        loop {
+            // nothing changes by itself while nothing plays
+            let command = if self.is_idle() {
+                commands.recv().map_err(|_| RecvTimeoutError::Disconnected)
+            } else {
+                commands.recv_timeout(WATCH_INTERVAL)
+            };
+            match command {
+                Ok(Command::Quit(wait)) => {
+                    self.fade_out(wait);
+                    return;
+                }
+                Ok(command) => self.handle(command),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+            self.watch();
+            self.prepare_loop();
+        }
+    }
+
+    fn handle(&mut self, command: Command) {
+        match command {
+            Command::Play(request) => {
+                let id = request.id;
+                let event = match self.open_track(request) {
+                    Ok(track) => {
+                        let mut playback = self.playback.lock().unwrap();
+                        playback.switch_to(Some(track));
+                        playback.set_paused(false);
+                        PlayerEvent::Started(id)
+                    }
+                    Err(e) => PlayerEvent::Failed(id, e),
+                };
+                // unless another one was asked for in the meantime
+                let _ = self
+                    .loading
+                    .compare_exchange(id, 0, Ordering::Relaxed, Ordering::Relaxed);
+                self.report(event);
+            }
+            Command::Enqueue {
+                request,
+                after,
+                crossfade,
+            } => {
+                let event = self.enqueue(request, after, crossfade);
+                self.report(event);
+            }
+            Command::ClearQueue(after) => {
+                let mut playback = self.playback.lock().unwrap();
+                if playback.current_id() == Some(after) {
+                    playback.next = None;
+                    drop(playback);
+                    self.report(PlayerEvent::QueueCleared(after));
+                }
+            }
+            Command::TogglePause => {
+                let mut playback = self.playback.lock().unwrap();
+                let paused = playback.is_paused();
+                playback.set_paused(!paused);
+            }
+            Command::Seek(t) => {
+                let result = self.playback.lock().unwrap().seek(t);
+                self.report(PlayerEvent::Seeked(result));
+            }
+            Command::Stop => self.playback.lock().unwrap().switch_to(None),
+            Command::SetVolume(volume) => self.sink.set_volume(volume),
+            Command::SetReplayGainMode(mode) => {
+                self.replay_gain_mode = mode;
+                self.playback.lock().unwrap().set_replay_gain_mode(mode);
+            }
+            Command::SetSilenceSkip(threshold) => {
+                self.silence_threshold = threshold;
+                self.playback.lock().unwrap().silence_threshold = threshold;
+            }
+            // ends the thread, see `run`
+            Command::Quit(_) => {}
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        let playback = self.playback.lock().unwrap();
+        (playback.current.is_none() || playback.is_paused())
+            && self.watched.0 == playback.current_id()
+    }
+
+    // Tells when the current track changed and when another second of it
+    // was played.
+    fn watch(&mut self) {
+        let playback = self.playback.lock().unwrap();
+        let seen = (playback.current_id(), playback.position().as_secs());
+        drop(playback);
+        if seen == self.watched {
+            return;
+        }
+        let changed = seen.0 != self.watched.0;
+        self.watched = seen;
+        self.report(if changed {
+            PlayerEvent::TrackChanged
+        } else {
+            PlayerEvent::Tick
+        });
+    }
+
+    // Gets a second source of a looping track to A, so the audio thread
+    // swaps sources at B instead of seeking.
+    fn prepare_loop(&mut self) {
+        let Some(request) = self.playback.lock().unwrap().loop_spare_request() else {
+            return;
+        };
+        let source = match request.source {
+            Some(source) => Ok(source),
+            None => SymphoniaDecoder::open(&request.file).map(|decoder| {
+                let source =
+                    UniformSourceIterator::<_, f32>::new(decoder, CHANNELS, self.sample_rate);
+                Box::new(source) as TrackSource
+            }),
+        };
+        let source = source.and_then(|mut source| {
+            source
+                .try_seek(request.position)
+                .map(|_| source)
+                .map_err(|e| e.to_string())
+        });
+        let mut playback = self.playback.lock().unwrap();
+        match source {
+            Ok(source) => playback.set_loop_spare(request.id, request.start, source),
+            Err(_) => playback.loop_spare_failed(request.id),
+        }
+    }
+
+    fn report(&self, event: PlayerEvent) {
+        (self.notify)(event);
+    }
+
+    fn enqueue(&mut self, request: TrackRequest, after: u64, crossfade: bool) -> PlayerEvent {
+        let id = request.id;
+        // a newer request or a track change made it pointless, don't even
+        // open the file
+        if self.queued.load(Ordering::Relaxed) != id
+            || self.playback.lock().unwrap().current_id() != Some(after)
+        {
+            return PlayerEvent::NotQueued(id);
+        }
+        let mut track = match self.open_track(request) {
+            Ok(track) => track,
+            Err(e) => return PlayerEvent::Failed(id, e),
+        };
+        track.crossfade = crossfade;
+
+        let mut playback = self.playback.lock().unwrap();
+        // it may have ended while the file was opened
+        if playback.current_id() != Some(after) {
+            return PlayerEvent::NotQueued(id);
+        }
+        playback.next = Some(track);
+        PlayerEvent::Queued(id)
+    }
+
+    fn fade_out(&mut self, wait: Duration) {
+        if self.playback.lock().unwrap().is_silent() {
+            return;
+        }
+        self.playback.lock().unwrap().switch_to(None);
+        // the output may have stopped pulling samples, don't wait forever
+        let deadline = Instant::now() + wait;
+        while !self.playback.lock().unwrap().is_silent() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        // let the last samples of the fade pass the rest of the chain
+        thread::sleep(OUTPUT_LATENCY);
+    }
+
+    fn open_track(&mut self, request: TrackRequest) -> Result<Track, String> {
+        let TrackRequest {
+            id,
+            file,
+            section,
+            position,
+        } = request;
+        let decoder = SymphoniaDecoder::open(&file)?;
+        let file_channels = decoder.file_channels();
+        let song_info = get_song_info(&file);
+        let total = decoder
+            .total_duration()
+            .or_else(|| song_info.as_ref().map(|info| info.duration));
+        let source = UniformSourceIterator::<_, f32>::new(decoder, CHANNELS, self.sample_rate);
+
+        let mut track = Track::new(id, Box::new(source), total, self.sample_rate);
+        track.file_channels = file_channels;
+        track.file = Some(file);
+        if let Some((start, end)) = section {
+            track
+                .set_section(start, end, self.sample_rate)
+                .map_err(|e| e.to_string())?;
+        }
+        if !position.is_zero() {
+            // formats that can't seek play from the start
+            let _ = track.seek(position, self.sample_rate);
+        } else if let Some(threshold) = self.silence_threshold {
+            track.skip_leading_silence(threshold);
+        }
+        if let Some(info) = song_info {
+            track.set_replay_gain(info.replay_gain, self.replay_gain_mode);
+        }
+        Ok(track)
+    }
+}
+
+pub struct SongInfo {
+    pub duration: Duration,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub genre: Option<String>,
+    pub replay_gain: ReplayGain,
+    // only those in the tags, the chapter tables of MP4 and Matroska files
+    // are read with `read_chapters` when a song is added
+    pub chapters: Vec<Chapter>,
+}
+
+// Falls back to the decoder for the duration of files lofty can't read,
+// like Matroska audio.
+pub fn get_song_info(path: &Path) -> Option<SongInfo> {
+    let Some(tagged_file) = lofty::probe::Probe::open(path)
+        .ok()
+        .and_then(|probe| probe.read().ok())
+    else {
+        let duration = SymphoniaDecoder::open(path).ok()?.total_duration()?;
+        return Some(SongInfo {
+            duration,
+            album: None,
+            album_artist: None,
+            genre: None,
+            replay_gain: ReplayGain::default(),
+            chapters: Vec::new(),
+        });
     };
 
-    let properties = &tagged_file.properties();
-    let duration = properties.duration();
-    Some(duration.as_secs() as u32)
+    let tag = tagged_file
+        .primary_tag()
+        .or_else(|| tagged_file.first_tag());
+    let album = tag
+        .and_then(|tag| tag.album())
+        .map(|album| album.to_string());
+    let album_artist = tag
+        .and_then(|tag| tag.get_string(&ItemKey::AlbumArtist))
+        .map(|artist| artist.to_string());
+    let genre = tag
+        .and_then(|tag| tag.genre())
+        .map(|genre| genre.to_string());
+    let replay_gain = tag.map(ReplayGain::from_tag).unwrap_or_default();
+    let chapters = tag.map(chapters_from_tag).unwrap_or_default();
+
+    Some(SongInfo {
+        duration: tagged_file.properties().duration(),
+        album,
+        album_artist,
+        genre,
+        replay_gain,
+        chapters,
+    })
 }
@@ -0,0 +1,284 @@
+use std::{
+    ffi::{c_int, c_uchar, c_void},
+    ptr::NonNull,
+    sync::{LazyLock, Mutex},
+};
+
+use libloading::Library;
+use symphonia::core::{
+    audio::{AsAudioBufferRef, AudioBuffer, AudioBufferRef, Channels, Signal, SignalSpec},
+    codecs::{
+        CodecDescriptor, CodecParameters, Decoder, DecoderOptions, FinalizeResult, CODEC_TYPE_OPUS,
+    },
+    errors::{decode_error, unsupported_error, Result},
+    formats::Packet,
+    support_codec,
+};
+
+// Opus through libopus, symphonia finds the packets in Ogg and MKV files
+// but has no decoder for them. libopus is looked for when the first Opus
+// file is opened, so the player builds without it and Opus plays wherever
+// it is installed. The multistream API decodes any number of channels.
+
+// 120ms at 48kHz, the longest packet there is
+const MAX_FRAMES: usize = 5760;
+
+#[cfg(target_os = "windows")]
+const LIBRARY_NAMES: &[&str] = &["opus.dll", "libopus-0.dll"];
+#[cfg(target_os = "macos")]
+const LIBRARY_NAMES: &[&str] = &[
+    "libopus.0.dylib",
+    "/opt/homebrew/lib/libopus.0.dylib",
+    "/usr/local/lib/libopus.0.dylib",
+];
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+const LIBRARY_NAMES: &[&str] = &["libopus.so.0", "libopus.so"];
+
+// from opus_defines.h
+const OPUS_RESET_STATE: c_int = 4028;
+const OPUS_SET_GAIN_REQUEST: c_int = 4034;
+
+type Create = unsafe extern "C" fn(
+    rate: i32,
+    channels: c_int,
+    streams: c_int,
+    coupled_streams: c_int,
+    mapping: *const c_uchar,
+    error: *mut c_int,
+) -> *mut c_void;
+type DecodeFloat = unsafe extern "C" fn(
+    decoder: *mut c_void,
+    data: *const c_uchar,
+    len: i32,
+    pcm: *mut f32,
+    frame_size: c_int,
+    decode_fec: c_int,
+) -> c_int;
+type Ctl = unsafe extern "C" fn(decoder: *mut c_void, request: c_int, ...) -> c_int;
+type Destroy = unsafe extern "C" fn(decoder: *mut c_void);
+
+struct Libopus {
+    create: Create,
+    decode_float: DecodeFloat,
+    ctl: Ctl,
+    destroy: Destroy,
+    // the functions above point into it
+    _library: Library,
+}
+
+static LIBOPUS: LazyLock<Option<Libopus>> = LazyLock::new(|| {
+    LIBRARY_NAMES.iter().find_map(|name| {
+        // libopus has nothing that runs when it is loaded, and the
+        // signatures are the ones of opus_multistream.h
+        unsafe {
+            let library = Library::new(name).ok()?;
+            let create = *library
+                .get::<Create>(b"opus_multistream_decoder_create\0")
+                .ok()?;
+            let decode_float = *library
+                .get::<DecodeFloat>(b"opus_multistream_decode_float\0")
+                .ok()?;
+            let ctl = *library.get::<Ctl>(b"opus_multistream_decoder_ctl\0").ok()?;
+            let destroy = *library
+                .get::<Destroy>(b"opus_multistream_decoder_destroy\0")
+                .ok()?;
+            Some(Libopus {
+                create,
+                decode_float,
+                ctl,
+                destroy,
+                _library: library,
+            })
+        }
+    })
+});
+
+// Where libopus puts the channels, the Vorbis order of the mapping family.
+fn vorbis_order(count: usize) -> &'static [Channels] {
+    const FL: Channels = Channels::FRONT_LEFT;
+    const FR: Channels = Channels::FRONT_RIGHT;
+    const FC: Channels = Channels::FRONT_CENTRE;
+    const LFE: Channels = Channels::LFE1;
+    const RL: Channels = Channels::REAR_LEFT;
+    const RR: Channels = Channels::REAR_RIGHT;
+    const RC: Channels = Channels::REAR_CENTRE;
+    const SL: Channels = Channels::SIDE_LEFT;
+    const SR: Channels = Channels::SIDE_RIGHT;
+    match count {
+        1 => &[FL],
+        2 => &[FL, FR],
+        3 => &[FL, FC, FR],
+        4 => &[FL, FR, RL, RR],
+        5 => &[FL, FC, FR, RL, RR],
+        6 => &[FL, FC, FR, RL, RR, LFE],
+        7 => &[FL, FC, FR, SL, SR, RC, LFE],
+        8 => &[FL, FC, FR, SL, SR, RL, RR, LFE],
+        _ => &[],
+    }
+}
+
+// The plane of each channel libopus gives, symphonia orders them by the
+// position bits.
+fn planes(layout: Channels) -> Vec<usize> {
+    let order = vorbis_order(layout.count());
+    if order.len() != layout.count() || !order.iter().all(|&c| layout.contains(c)) {
+        return (0..layout.count()).collect();
+    }
+    order
+        .iter()
+        .map(|c| (layout.bits() & (c.bits() - 1)).count_ones() as usize)
+        .collect()
+}
+
+// An OpusMSDecoder, used by one thread at a time.
+struct State(NonNull<c_void>);
+
+unsafe impl Send for State {}
+
+impl Drop for State {
+    fn drop(&mut self) {
+        if let Some(libopus) = LIBOPUS.as_ref() {
+            unsafe { (libopus.destroy)(self.0.as_ptr()) }
+        }
+    }
+}
+
+pub struct OpusDecoder {
+    params: CodecParameters,
+    // libopus state can move between threads but not be shared
+    state: Mutex<State>,
+    buffer: AudioBuffer<f32>,
+    // interleaved, as libopus gives it
+    samples: Vec<f32>,
+    planes: Vec<usize>,
+}
+
+impl Decoder for OpusDecoder {
+    fn try_new(params: &CodecParameters, _options: &DecoderOptions) -> Result<Self> {
+        let Some(libopus) = LIBOPUS.as_ref() else {
+            return unsupported_error("opus: libopus not found");
+        };
+        let Some(layout) = params.channels else {
+            return unsupported_error("opus: no channels");
+        };
+        let channels = layout.count();
+        // the OpusHead packet: the output gain in dB as Q7.8, then the
+        // mapping family, and for a family other than 0 the streams
+        let Some(head) = params.extra_data.as_deref().filter(|head| head.len() >= 19) else {
+            return unsupported_error("opus: no header");
+        };
+        let gain = i16::from_le_bytes([head[16], head[17]]);
+        let (streams, coupled, mapping) = if head[18] == 0 {
+            (1, channels - 1, vec![0, 1])
+        } else {
+            match head.get(19..21 + channels) {
+                Some(table) => (table[0] as usize, table[1] as usize, table[2..].to_vec()),
+                None => return unsupported_error("opus: no channel mapping"),
+            }
+        };
+
+        let mut error = 0;
+        let decoder = unsafe {
+            (libopus.create)(
+                48000,
+                channels as c_int,
+                streams as c_int,
+                coupled as c_int,
+                mapping.as_ptr(),
+                &mut error,
+            )
+        };
+        let Some(decoder) = NonNull::new(decoder).filter(|_| error == 0) else {
+            return unsupported_error("opus: libopus refused the channel mapping");
+        };
+        let state = State(decoder);
+        if unsafe { (libopus.ctl)(state.0.as_ptr(), OPUS_SET_GAIN_REQUEST, gain as c_int) } != 0 {
+            return unsupported_error("opus: bad output gain");
+        }
+        Ok(Self {
+            params: params.clone(),
+            state: Mutex::new(state),
+            buffer: AudioBuffer::new(MAX_FRAMES as u64, SignalSpec::new(48000, layout)),
+            samples: vec![0.0; MAX_FRAMES * channels],
+            planes: planes(layout),
+        })
+    }
+
+    fn supported_codecs() -> &'static [CodecDescriptor] {
+        &[support_codec!(CODEC_TYPE_OPUS, "opus", "Opus")]
+    }
+
+    fn reset(&mut self) {
+        // only made once libopus was there
+        if let Some(libopus) = LIBOPUS.as_ref() {
+            let state = self.state.get_mut().unwrap();
+            unsafe { (libopus.ctl)(state.0.as_ptr(), OPUS_RESET_STATE) };
+        }
+    }
+
+    fn codec_params(&self) -> &CodecParameters {
+        &self.params
+    }
+
+    fn decode(&mut self, packet: &Packet) -> Result<AudioBufferRef<'_>> {
+        self.buffer.clear();
+        let Some(libopus) = LIBOPUS.as_ref() else {
+            return decode_error("opus: libopus not found");
+        };
+        let data = packet.buf();
+        let Ok(len) = i32::try_from(data.len()) else {
+            return decode_error("opus: damaged packet");
+        };
+        let state = self.state.get_mut().unwrap();
+        // `samples` holds the longest packet for every channel
+        let frames = unsafe {
+            (libopus.decode_float)(
+                state.0.as_ptr(),
+                data.as_ptr(),
+                len,
+                self.samples.as_mut_ptr(),
+                MAX_FRAMES as c_int,
+                0,
+            )
+        };
+        if frames < 0 {
+            return decode_error("opus: damaged packet");
+        }
+        self.buffer.render_reserved(Some(frames as usize));
+        let channels = self.planes.len();
+        for (c, &plane) in self.planes.iter().enumerate() {
+            let plane = self.buffer.chan_mut(plane);
+            for (i, sample) in plane.iter_mut().enumerate() {
+                *sample = self.samples[i * channels + c];
+            }
+        }
+        Ok(self.buffer.as_audio_buffer_ref())
+    }
+
+    fn finalize(&mut self) -> FinalizeResult {
+        FinalizeResult::default()
+    }
+
+    fn last_decoded(&self) -> AudioBufferRef<'_> {
+        self.buffer.as_audio_buffer_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn surround_channels_go_to_their_planes() {
+        let layout = Channels::FRONT_LEFT
+            | Channels::FRONT_CENTRE
+            | Channels::FRONT_RIGHT
+            | Channels::REAR_LEFT
+            | Channels::REAR_RIGHT
+            | Channels::LFE1;
+        // symphonia has FL FR FC LFE RL RR
+        assert_eq!(planes(layout), vec![0, 2, 1, 4, 5, 3]);
+        let stereo = Channels::FRONT_LEFT | Channels::FRONT_RIGHT;
+        assert_eq!(planes(stereo), vec![0, 1]);
+    }
+}
@@ -0,0 +1,238 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use rodio::{
+    cpal::traits::{DeviceTrait, HostTrait},
+    queue::SourcesQueueOutput,
+    OutputStream, OutputStreamHandle, Sink,
+};
+use serde::Deserialize;
+
+use crate::playback::{Activity, CHANNELS};
+
+// used when there is no device to ask for its rate
+const FALLBACK_SAMPLE_RATE: u32 = 44100;
+// the output threads pull 10ms of audio at a time
+const CHUNKS_PER_SECOND: u32 = 100;
+// how often a waiting output thread looks whether it should stop
+const STOP_POLL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(try_from = "String")]
+pub enum OutputKind {
+    #[default]
+    Device,
+    // throws the samples away, but at the pace a sound card would
+    Null,
+    // records everything that is played into a wav file, pauses left out
+    Wav(PathBuf),
+}
+
+impl TryFrom<String> for OutputKind {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "device" => Ok(Self::Device),
+            "null" => Ok(Self::Null),
+            _ => match value.strip_prefix("wav:") {
+                Some(path) if !path.is_empty() => Ok(Self::Wav(PathBuf::from(path))),
+                _ => Err(format!(
+                    "unknown output \"{}\", expected \"device\", \"null\" or \"wav:PATH\"",
+                    value
+                )),
+            },
+        }
+    }
+}
+
+// Only held so the stream or thread lives as long as the music handle.
+pub enum Output {
+    Device {
+        _stream: OutputStream,
+        _handle: OutputStreamHandle,
+    },
+    Thread {
+        _thread: OutputThread,
+    },
+}
+
+impl Output {
+    // Opens the output and returns a sink playing into it together with the
+    // sample rate everything has to be played at. The threads standing in
+    // for a sound card sleep while `activity` says nothing plays.
+    pub fn open(kind: &OutputKind, activity: Arc<Activity>) -> Result<(Self, Sink, u32), String> {
+        match kind {
+            OutputKind::Device => {
+                let (stream, handle) = OutputStream::try_default().map_err(|e| e.to_string())?;
+                let sink = Sink::try_new(&handle).map_err(|e| e.to_string())?;
+                let output = Self::Device {
+                    _stream: stream,
+                    _handle: handle,
+                };
+                Ok((output, sink, default_output_sample_rate()))
+            }
+            OutputKind::Null => {
+                let (sink, queue) = Sink::new_idle();
+                let thread = OutputThread::spawn(queue, FALLBACK_SAMPLE_RATE, None, activity);
+                Ok((Self::Thread { _thread: thread }, sink, FALLBACK_SAMPLE_RATE))
+            }
+            OutputKind::Wav(path) => {
+                let writer = WavWriter::create(path, FALLBACK_SAMPLE_RATE)
+                    .map_err(|e| format!("{}: {}", path.display(), e))?;
+                let (sink, queue) = Sink::new_idle();
+                let thread =
+                    OutputThread::spawn(queue, FALLBACK_SAMPLE_RATE, Some(writer), activity);
+                Ok((Self::Thread { _thread: thread }, sink, FALLBACK_SAMPLE_RATE))
+            }
+        }
+    }
+}
+
+fn default_output_sample_rate() -> u32 {
+    rodio::cpal::default_host()
+        .default_output_device()
+        .and_then(|device| device.default_output_config().ok())
+        .map(|config| config.sample_rate().0)
+        .unwrap_or(FALLBACK_SAMPLE_RATE)
+}
+
+// Stands in for the audio callback of a sound card: pulls the samples from
+// the sink in real time while something plays and optionally writes them to
+// a wav file.
+pub struct OutputThread {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl OutputThread {
+    fn spawn(
+        mut queue: SourcesQueueOutput<f32>,
+        sample_rate: u32,
+        mut writer: Option<WavWriter>,
+        activity: Arc<Activity>,
+    ) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_clone = stop.clone();
+        let handle = thread::spawn(move || {
+            let chunk_frames = sample_rate / CHUNKS_PER_SECOND;
+            let len = (chunk_frames * CHANNELS as u32) as usize;
+            let mut start = Instant::now();
+            let mut frames_played: u64 = 0;
+            let mut chunk = Vec::with_capacity(len);
+            while !stop_clone.load(Ordering::Relaxed) {
+                if !activity.is_playing() {
+                    activity.wait(STOP_POLL);
+                    // the pace starts over when the music comes back
+                    start = Instant::now();
+                    frames_played = 0;
+                    continue;
+                }
+                chunk.clear();
+                chunk.extend((&mut queue).take(len));
+                chunk.resize(len, 0.0);
+                if let Some(w) = writer.as_mut() {
+                    // a full disk shouldn't stop the music, only the recording
+                    if w.write_samples(&chunk).is_err() {
+                        writer = None;
+                    }
+                }
+
+                frames_played += chunk_frames as u64;
+                let due =
+                    start + Duration::from_secs_f64(frames_played as f64 / sample_rate as f64);
+                if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                    thread::sleep(wait);
+                }
+            }
+            if let Some(w) = writer.as_mut() {
+                let _ = w.finish();
+            }
+        });
+        Self {
+            stop,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for OutputThread {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+// 16 bit PCM. The sizes in the header are kept up to date every second, so
+// the recording stays readable even if the player is killed.
+struct WavWriter {
+    file: BufWriter<File>,
+    data_len: u32,
+    unsynced_len: u32,
+    sync_every: u32,
+}
+
+impl WavWriter {
+    fn create(path: &Path, sample_rate: u32) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let channels = CHANNELS as u32;
+        let block_align = channels * 2;
+        file.write_all(b"RIFF")?;
+        file.write_all(&36u32.to_le_bytes())?;
+        file.write_all(b"WAVEfmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        file.write_all(&1u16.to_le_bytes())?;
+        file.write_all(&(channels as u16).to_le_bytes())?;
+        file.write_all(&sample_rate.to_le_bytes())?;
+        file.write_all(&(sample_rate * block_align).to_le_bytes())?;
+        file.write_all(&(block_align as u16).to_le_bytes())?;
+        file.write_all(&16u16.to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())?;
+        Ok(Self {
+            file,
+            data_len: 0,
+            unsynced_len: 0,
+            sync_every: sample_rate * block_align,
+        })
+    }
+
+    fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        let len = samples.len() as u32 * 2;
+        // the sizes in a wav header are 32 bit
+        if self.data_len.checked_add(len + 36).is_none() {
+            return Err(io::Error::other("wav file size limit reached"));
+        }
+        for sample in samples {
+            let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.file.write_all(&value.to_le_bytes())?;
+        }
+        self.data_len += len;
+        self.unsynced_len += len;
+        if self.unsynced_len >= self.sync_every {
+            self.finish()?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.unsynced_len = 0;
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&(36 + self.data_len).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&self.data_len.to_le_bytes())?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()
+    }
+}
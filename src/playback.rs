@@ -0,0 +1,1048 @@
+use std::{
+    collections::VecDeque,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::Duration,
+};
+
+use rodio::{source::SeekError, Source};
+
+use crate::replaygain::{ReplayGain, ReplayGainMode};
+
+pub const CHANNELS: u16 = 2;
+const BLOCK_LEN: usize = 1024 * CHANNELS as usize;
+// short fades around the jump from B back to A so the loop doesn't click
+const LOOP_FADE: Duration = Duration::from_millis(5);
+pub const MIN_FADE: u64 = 50;
+pub const MAX_FADE: u64 = 300;
+// frames read ahead per frame played while it's unclear whether a silence
+// lasts until the end of the track
+const SILENCE_LOOKAHEAD: usize = 16;
+// silence held back to find out, a longer one ending the track is only cut
+// by this much
+const MAX_SILENCE_HELD: Duration = Duration::from_secs(5);
+// below -120dB the filters are only ringing out
+const QUIET_LEVEL: f32 = 1e-6;
+// how long that has to last before the effects are left alone
+const QUIET_TIME: Duration = Duration::from_millis(100);
+
+pub type TrackSource = Box<dyn Source<Item = f32> + Send>;
+
+pub struct Track {
+    pub id: u64,
+    source: TrackSource,
+    // read from the source but not played yet
+    pending: VecDeque<f32>,
+    // whether `pending` is a silence not known to end before the track does
+    in_silence: bool,
+    // samples of silence `pending` may hold, it never grows past this and
+    // the lookahead on the audio thread
+    max_held: usize,
+    // whether anything of the track has been played
+    started: bool,
+    samples_played: u64,
+    total_samples: Option<u64>,
+    // where the track starts in the source, for a section of a longer file
+    offset: Duration,
+    // samples after which the track ends even if the source goes on
+    end_samples: Option<u64>,
+    // whether the previous track may fade into this one
+    pub crossfade: bool,
+    replay_gain: ReplayGain,
+    gain: f32,
+    // channels in the file, before the mix to stereo
+    pub file_channels: u16,
+    // where the source comes from, to open it again for an A-B loop
+    pub file: Option<PathBuf>,
+}
+
+impl Track {
+    pub fn new(id: u64, source: TrackSource, total: Option<Duration>, sample_rate: u32) -> Self {
+        let max_held = duration_to_samples(MAX_SILENCE_HELD, sample_rate) as usize;
+        Self {
+            id,
+            source,
+            pending: VecDeque::with_capacity(max_held + SILENCE_LOOKAHEAD * CHANNELS as usize),
+            in_silence: false,
+            max_held,
+            started: false,
+            samples_played: 0,
+            total_samples: total.map(|t| duration_to_samples(t, sample_rate)),
+            offset: Duration::ZERO,
+            end_samples: None,
+            crossfade: false,
+            replay_gain: ReplayGain::default(),
+            gain: 1.0,
+            file_channels: CHANNELS,
+            file: None,
+        }
+    }
+
+    pub fn set_replay_gain(&mut self, replay_gain: ReplayGain, mode: ReplayGainMode) {
+        self.replay_gain = replay_gain;
+        self.gain = replay_gain.factor(mode);
+    }
+
+    // Plays only the part of the source from `start` to `end`, positions
+    // stay relative to `start`.
+    pub fn set_section(
+        &mut self,
+        start: Duration,
+        end: Option<Duration>,
+        sample_rate: u32,
+    ) -> Result<(), SeekError> {
+        if start > Duration::ZERO {
+            self.source.try_seek(start)?;
+        }
+        self.offset = start;
+        let length = end
+            .or_else(|| self.source.total_duration())
+            .map(|end| duration_to_samples(end.saturating_sub(start), sample_rate));
+        self.end_samples = end.and(length);
+        if length.is_some() {
+            self.total_samples = length;
+        } else if let Some(total) = self.total_samples.as_mut() {
+            *total = total.saturating_sub(duration_to_samples(start, sample_rate));
+        }
+        Ok(())
+    }
+
+    fn remaining_samples(&self) -> Option<u64> {
+        self.total_samples
+            .map(|total| total.saturating_sub(self.samples_played))
+    }
+
+    fn read_frame(&mut self) -> Option<[f32; CHANNELS as usize]> {
+        let read = self.samples_played + self.pending.len() as u64;
+        if self.end_samples.is_some_and(|end| read >= end) {
+            return None;
+        }
+        let mut frame = [0.0; CHANNELS as usize];
+        for sample in frame.iter_mut() {
+            *sample = self.source.next()?;
+        }
+        Some(frame)
+    }
+
+    // Drops the silence the track starts with. Skipped samples still count
+    // as played, so the position keeps matching the file.
+    pub fn skip_leading_silence(&mut self, threshold: f32) {
+        while let Some(frame) = self.read_frame() {
+            if is_audible(&frame, threshold) {
+                self.pending.extend(frame);
+                return;
+            }
+            self.samples_played += CHANNELS as u64;
+        }
+    }
+
+    // The next sample to play. With a `threshold`, a silence running up to
+    // the end of the track is left out: while in a silence the source is read
+    // ahead faster than played, and if it ends first the rest is dropped.
+    fn next_sample(&mut self, threshold: Option<f32>) -> Option<f32> {
+        if let Some(threshold) = threshold {
+            if self.pending.is_empty() {
+                let frame = self.read_frame()?;
+                self.in_silence = !is_audible(&frame, threshold);
+                self.pending.extend(frame);
+            }
+            if self.in_silence && self.pending.len() < self.max_held {
+                for _ in 0..SILENCE_LOOKAHEAD {
+                    let Some(frame) = self.read_frame() else {
+                        // silent up to the end, which counts as played, also
+                        // when the length said is a bit longer
+                        let end = self.samples_played + self.pending.len() as u64;
+                        self.samples_played = end.max(self.total_samples.unwrap_or(0));
+                        self.pending.clear();
+                        self.in_silence = false;
+                        return None;
+                    };
+                    self.pending.extend(frame);
+                    if is_audible(&frame, threshold) {
+                        self.in_silence = false;
+                        break;
+                    }
+                }
+            }
+        }
+        let sample = match self.pending.pop_front() {
+            Some(sample) => sample,
+            None if self.end_samples.is_some() => {
+                let frame = self.read_frame()?;
+                self.pending.extend(&frame[1..]);
+                frame[0]
+            }
+            None => self.source.next()?,
+        };
+        self.samples_played += 1;
+        self.started = true;
+        Some(sample)
+    }
+
+    pub fn seek(&mut self, pos: Duration, sample_rate: u32) -> Result<(), SeekError> {
+        let pos = match self.total_samples {
+            Some(total) => pos.min(samples_to_duration(total, sample_rate)),
+            None => pos,
+        };
+        self.source.try_seek(self.offset + pos)?;
+        self.pending.clear();
+        self.in_silence = false;
+        self.samples_played = duration_to_samples(pos, sample_rate);
+        Ok(())
+    }
+}
+
+fn is_audible(frame: &[f32], threshold: f32) -> bool {
+    frame.iter().any(|sample| sample.abs() >= threshold)
+}
+
+fn duration_to_samples(t: Duration, sample_rate: u32) -> u64 {
+    (t.as_secs_f64() * sample_rate as f64) as u64 * CHANNELS as u64
+}
+
+fn samples_to_duration(samples: u64, sample_rate: u32) -> Duration {
+    Duration::from_secs_f64(samples as f64 / (sample_rate as f64 * CHANNELS as f64))
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LoopMarker {
+    A,
+    B,
+}
+
+// Positions in samples of the current track. Loops once both are set.
+#[derive(Default)]
+pub struct AbLoop {
+    start: Option<u64>,
+    end: Option<u64>,
+    samples_since_jump: u64,
+    // another source of the track, already at `start`. At B it takes the
+    // place of the playing one, seeking there would hold the lock while
+    // the decoder reads from the disk.
+    spare: Option<LoopSpare>,
+    // the source that was playing before the last jump back
+    used: Option<TrackSource>,
+}
+
+struct LoopSpare {
+    start: u64,
+    source: TrackSource,
+}
+
+// What the worker needs to get a spare source to A.
+pub struct LoopSpareRequest {
+    pub id: u64,
+    pub file: PathBuf,
+    pub start: u64,
+    // of A in the file
+    pub position: Duration,
+    // a source of the track that can be seeked there, otherwise the file
+    // is opened again
+    pub source: Option<TrackSource>,
+}
+
+impl AbLoop {
+    fn range(&self) -> Option<(u64, u64)> {
+        Some((self.start?, self.end?))
+    }
+
+    // How much of the sample at `position` is audible, fading out right
+    // before B and in right after jumping back to A.
+    fn fade(&self, position: u64, fade_samples: u64) -> f32 {
+        let Some((_, end)) = self.range() else {
+            return 1.0;
+        };
+        let to_end = end.saturating_sub(position).min(fade_samples);
+        let from_jump = self.samples_since_jump.min(fade_samples);
+        (to_end.min(from_jump) as f32 / fade_samples as f32).min(1.0)
+    }
+}
+
+// Ramps the volume of everything that is played, so pausing, stopping and
+// skipping don't cut the wave off in the middle and pop.
+struct Fader {
+    gain: f32,
+    step: f32,
+}
+
+impl Fader {
+    // Moves one frame closer to `target` and returns the gain to use.
+    fn approach(&mut self, target: f32) -> f32 {
+        self.gain = if self.gain < target {
+            (self.gain + self.step).min(target)
+        } else {
+            (self.gain - self.step).max(target)
+        };
+        self.gain
+    }
+}
+
+// Shared between the ui thread and the audio thread. The audio thread only
+// takes the lock once per block, so the ui can swap tracks at any time and the
+// switch from `current` to `next` happens on the exact sample the former ends.
+pub struct PlaybackState {
+    pub current: Option<Track>,
+    pub next: Option<Track>,
+    sample_rate: u32,
+    crossfade_samples: u64,
+    pub ab_loop: AbLoop,
+    // the track that was replaced or stopped, played until it has faded out
+    outgoing: Option<Track>,
+    paused: bool,
+    fader: Fader,
+    // the sleep timer fading everything out, on top of `fader`
+    sleep: Fader,
+    sleep_target: f32,
+    // amplitude below which the end of a track counts as silence, `None`
+    // plays everything
+    pub silence_threshold: Option<f32>,
+    // counts the jumps in what is played, seeks and tracks starting without
+    // a fade, so buffers further down can drop what came before
+    jumps: Arc<AtomicU64>,
+    activity: Arc<Activity>,
+}
+
+impl PlaybackState {
+    pub fn new(sample_rate: u32, activity: Arc<Activity>) -> Self {
+        Self {
+            current: None,
+            next: None,
+            sample_rate,
+            crossfade_samples: 0,
+            ab_loop: AbLoop::default(),
+            outgoing: None,
+            paused: false,
+            silence_threshold: None,
+            fader: Fader {
+                gain: 1.0,
+                step: 1.0,
+            },
+            sleep: Fader {
+                gain: 1.0,
+                step: 1.0,
+            },
+            sleep_target: 1.0,
+            jumps: Arc::new(AtomicU64::new(0)),
+            activity,
+        }
+    }
+
+    pub fn jumps(&self) -> Arc<AtomicU64> {
+        self.jumps.clone()
+    }
+
+    fn jumped(&self) {
+        self.jumps.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_fade(&mut self, t: Duration) {
+        let frames = duration_to_samples(t, self.sample_rate) / CHANNELS as u64;
+        self.fader.step = 1.0 / frames.max(1) as f32;
+    }
+
+    // Fades out to silence over `t` of the tracks, from wherever an earlier
+    // fade got to.
+    pub fn fade_to_sleep(&mut self, t: Duration) {
+        let frames = duration_to_samples(t, self.sample_rate) / CHANNELS as u64;
+        self.sleep.step = self.sleep.gain / frames.max(1) as f32;
+        self.sleep_target = 0.0;
+    }
+
+    // Back up as quickly as any other fade.
+    pub fn cancel_sleep_fade(&mut self) {
+        self.sleep.step = self.fader.step;
+        self.sleep_target = 1.0;
+    }
+
+    // Replaces the current track, the old one fades out before the new one
+    // starts. `None` stops.
+    pub fn switch_to(&mut self, track: Option<Track>) {
+        let old = std::mem::replace(&mut self.current, track);
+        // a track waiting behind a fade was never heard, only the one fading
+        // out has to finish
+        if self.outgoing.is_none() && !self.paused {
+            self.outgoing = old.filter(|track| track.started);
+        }
+        // otherwise the new track starts once the fade is over. Either way
+        // it fades in.
+        if self.outgoing.is_none() {
+            self.jumped();
+            self.fader.gain = 0.0;
+        }
+        self.next = None;
+        self.clear_loop();
+        if self.current.is_some() {
+            self.activity.set_playing(true);
+        }
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        if !paused {
+            self.activity.set_playing(true);
+        }
+    }
+
+    // Nothing left to hear: paused and faded out, or stopped.
+    fn is_idle(&self) -> bool {
+        self.outgoing.is_none()
+            && (self.current.is_none() || (self.paused && self.fader.gain == 0.0))
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // Whether nothing is audible any more, fades included.
+    pub fn is_silent(&self) -> bool {
+        self.outgoing.is_none() && (self.current.is_none() || self.fader.gain == 0.0)
+    }
+
+    // Puts a marker at the current position. Setting A behind B removes B,
+    // setting B in front of A moves A to the start of the track.
+    pub fn set_loop_marker(&mut self, marker: LoopMarker) {
+        let Some(track) = self.current.as_ref() else {
+            return;
+        };
+        let position = track.samples_played;
+        match marker {
+            LoopMarker::A => {
+                self.ab_loop.start = Some(position);
+                if self.ab_loop.end.is_some_and(|end| end <= position) {
+                    self.ab_loop.end = None;
+                }
+            }
+            LoopMarker::B => {
+                if position == 0 {
+                    return;
+                }
+                self.ab_loop.end = Some(position);
+                // without a usable A the loop starts at the beginning
+                if !matches!(self.ab_loop.start, Some(start) if start < position) {
+                    self.ab_loop.start = Some(0);
+                }
+            }
+        }
+        self.ab_loop.samples_since_jump = u64::MAX;
+    }
+
+    pub fn nudge_loop_marker(&mut self, marker: LoopMarker, offset: Duration, forward: bool) {
+        let offset = duration_to_samples(offset, self.sample_rate);
+        let total = self.current.as_ref().and_then(|track| track.total_samples);
+        let AbLoop { start, end, .. } = &mut self.ab_loop;
+        let (value, lower, upper) = match marker {
+            LoopMarker::A => (
+                start,
+                Some(0),
+                end.map(|end| end.saturating_sub(CHANNELS as u64)),
+            ),
+            LoopMarker::B => (end, start.map(|start| start + CHANNELS as u64), total),
+        };
+        let Some(value) = value else {
+            return;
+        };
+        let moved = if forward {
+            value.saturating_add(offset)
+        } else {
+            value.saturating_sub(offset)
+        };
+        *value = moved.max(lower.unwrap_or(0)).min(upper.unwrap_or(u64::MAX));
+    }
+
+    pub fn clear_loop(&mut self) {
+        self.ab_loop = AbLoop::default();
+    }
+
+    // `None` while there is no loop or its spare source is ready.
+    pub fn loop_spare_request(&mut self) -> Option<LoopSpareRequest> {
+        let track = self.current.as_ref()?;
+        let (start, _) = self.ab_loop.range()?;
+        if self
+            .ab_loop
+            .spare
+            .as_ref()
+            .is_some_and(|spare| spare.start == start)
+        {
+            return None;
+        }
+        let file = track.file.clone()?;
+        // A moved, the old spare can be seeked again
+        let source = match self.ab_loop.spare.take() {
+            Some(spare) => Some(spare.source),
+            None => self.ab_loop.used.take(),
+        };
+        Some(LoopSpareRequest {
+            id: track.id,
+            file,
+            start,
+            position: track.offset + samples_to_duration(start, self.sample_rate),
+            source,
+        })
+    }
+
+    // Ignored when the track or A changed in the meantime.
+    pub fn set_loop_spare(&mut self, id: u64, start: u64, source: TrackSource) {
+        if self.current_id() != Some(id) {
+            return;
+        }
+        if self.ab_loop.start == Some(start) {
+            self.ab_loop.spare = Some(LoopSpare { start, source });
+        } else {
+            self.ab_loop.used = Some(source);
+        }
+    }
+
+    // The track can't get back to A, it plays on instead.
+    pub fn loop_spare_failed(&mut self, id: u64) {
+        if self.current_id() == Some(id) {
+            self.clear_loop();
+        }
+    }
+
+    pub fn loop_markers(&self) -> (Option<Duration>, Option<Duration>) {
+        let to_duration = |samples: Option<u64>| {
+            samples.map(|samples| samples_to_duration(samples, self.sample_rate))
+        };
+        (
+            to_duration(self.ab_loop.start),
+            to_duration(self.ab_loop.end),
+        )
+    }
+
+    pub fn set_replay_gain_mode(&mut self, mode: ReplayGainMode) {
+        for track in self.current.iter_mut().chain(self.next.iter_mut()) {
+            track.gain = track.replay_gain.factor(mode);
+        }
+    }
+
+    pub fn set_crossfade(&mut self, t: Duration) {
+        self.crossfade_samples = duration_to_samples(t, self.sample_rate);
+    }
+
+    // How far the fade from the current into the next track has progressed,
+    // from 0.0 to 1.0, or `None` while only the current track is audible.
+    fn crossfade_progress(&self) -> Option<f32> {
+        if self.ab_loop.range().is_some() {
+            return None;
+        }
+        let next = self.next.as_ref()?;
+        if !next.crossfade || self.crossfade_samples == 0 {
+            return None;
+        }
+        let remaining = self.current.as_ref()?.remaining_samples()?;
+        if remaining >= self.crossfade_samples {
+            return None;
+        }
+        Some(1.0 - remaining as f32 / self.crossfade_samples as f32)
+    }
+
+    pub fn current_id(&self) -> Option<u64> {
+        self.current.as_ref().map(|t| t.id)
+    }
+
+    pub fn current_file_channels(&self) -> Option<u16> {
+        self.current.as_ref().map(|t| t.file_channels)
+    }
+
+    pub fn position(&self) -> Duration {
+        match &self.current {
+            Some(track) => samples_to_duration(track.samples_played, self.sample_rate),
+            None => Duration::ZERO,
+        }
+    }
+
+    pub fn seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        let sample_rate = self.sample_rate;
+        let Some(track) = self.current.as_mut() else {
+            return Ok(());
+        };
+        track.seek(pos, sample_rate)?;
+        self.jumped();
+
+        // a crossfade that already started has to begin again from scratch
+        if let Some(next) = self.next.as_mut() {
+            if next.started {
+                let _ = next.seek(Duration::ZERO, sample_rate);
+                next.started = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct Playback {
+    state: Arc<Mutex<PlaybackState>>,
+    block: Vec<f32>,
+    index: usize,
+    sample_rate: u32,
+    jumps: Arc<AtomicU64>,
+    // jumps already in `block`
+    jumps_seen: u64,
+}
+
+impl Playback {
+    pub fn new(state: Arc<Mutex<PlaybackState>>, sample_rate: u32) -> Self {
+        let jumps = state.lock().unwrap().jumps();
+        Self {
+            state,
+            block: Vec::with_capacity(BLOCK_LEN),
+            index: 0,
+            sample_rate,
+            jumps,
+            jumps_seen: 0,
+        }
+    }
+
+    fn fill_block(&mut self) {
+        self.block.clear();
+        self.index = 0;
+        let loop_fade = duration_to_samples(LOOP_FADE, self.sample_rate);
+        let mut state = self.state.lock().unwrap();
+        let mut gain = state.fader.gain;
+        let mut sleep = state.sleep.gain;
+        while self.block.len() < BLOCK_LEN {
+            if self.block.len().is_multiple_of(CHANNELS as usize) {
+                let target = if state.paused || state.outgoing.is_some() {
+                    0.0
+                } else {
+                    1.0
+                };
+                gain = state.fader.approach(target);
+                let sleep_target = state.sleep_target;
+                sleep = state.sleep.approach(sleep_target);
+            }
+            let silence_threshold = state.silence_threshold;
+            if let Some(outgoing) = state.outgoing.as_mut() {
+                match outgoing.next_sample(silence_threshold) {
+                    Some(sample) if gain > 0.0 => {
+                        self.block.push(sample * outgoing.gain * gain * sleep);
+                        continue;
+                    }
+                    _ => {
+                        // faded out, the new track fades in from silence
+                        state.outgoing = None;
+                        state.jumped();
+                        if !self.block.len().is_multiple_of(CHANNELS as usize) {
+                            self.block.push(0.0);
+                        }
+                        state.fader.gain = 0.0;
+                        gain = 0.0;
+                        continue;
+                    }
+                }
+            }
+            if state.paused && gain == 0.0 {
+                break;
+            }
+            let crossfade = state.crossfade_progress();
+            let PlaybackState {
+                current,
+                next,
+                ab_loop,
+                ..
+            } = &mut *state;
+            let Some(track) = current.as_mut() else {
+                break;
+            };
+            if let Some((start, end)) = ab_loop.range() {
+                if track.samples_played >= end {
+                    // it faded out before B, and stays silent until the
+                    // worker has the spare source at A
+                    let Some(spare) = ab_loop.spare.take_if(|spare| spare.start == start) else {
+                        self.block.push(0.0);
+                        continue;
+                    };
+                    ab_loop.used = Some(std::mem::replace(&mut track.source, spare.source));
+                    track.pending.clear();
+                    track.in_silence = false;
+                    track.samples_played = start;
+                    ab_loop.samples_since_jump = 0;
+                }
+            }
+            let fade = ab_loop.fade(track.samples_played, loop_fade);
+            ab_loop.samples_since_jump = ab_loop.samples_since_jump.saturating_add(1);
+            match track.next_sample(silence_threshold) {
+                Some(sample) => {
+                    let sample = sample * track.gain * fade * gain * sleep;
+                    let sample = match (crossfade, next.as_mut()) {
+                        (Some(progress), Some(next)) => {
+                            // equal power fade, keeps the loudness steady
+                            let angle = progress * std::f32::consts::FRAC_PI_2;
+                            let incoming =
+                                next.next_sample(silence_threshold).unwrap_or(0.0) * next.gain;
+                            sample * angle.cos() + incoming * angle.sin() * gain * sleep
+                        }
+                        _ => sample,
+                    };
+                    self.block.push(sample);
+                }
+                None => {
+                    // keep the channels aligned if a track ends mid frame
+                    if !self.block.len().is_multiple_of(CHANNELS as usize) {
+                        self.block.push(0.0);
+                    }
+                    state.current = state.next.take();
+                    state.ab_loop = AbLoop::default();
+                }
+            }
+        }
+        self.block.resize(BLOCK_LEN, 0.0);
+        self.jumps_seen = self.jumps.load(Ordering::Relaxed);
+    }
+}
+
+impl Iterator for Playback {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        // the rest of the block is from before a seek or a new track, drop
+        // it on a frame boundary so the channels stay in place
+        let jumped = self.index.is_multiple_of(CHANNELS as usize)
+            && self.jumps.load(Ordering::Relaxed) != self.jumps_seen;
+        if self.index >= self.block.len() || jumped {
+            self.fill_block();
+        }
+        let sample = self.block[self.index];
+        self.index += 1;
+        Some(sample)
+    }
+}
+
+impl Source for Playback {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        CHANNELS
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+// Whether anything is heard, so an output that isn't a sound card can wait
+// instead of pulling silence. Only changed with the playback lock held, a
+// track starting can't slip in between the gate looking and giving up.
+#[derive(Default)]
+pub struct Activity {
+    playing: Mutex<bool>,
+    changed: Condvar,
+}
+
+impl Activity {
+    fn set_playing(&self, playing: bool) {
+        let mut current = self.playing.lock().unwrap();
+        if *current != playing {
+            *current = playing;
+            self.changed.notify_all();
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        *self.playing.lock().unwrap()
+    }
+
+    // Waits at most `timeout` for something to play.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let playing = self.playing.lock().unwrap();
+        let (playing, _) = self
+            .changed
+            .wait_timeout_while(playing, timeout, |playing| !*playing)
+            .unwrap();
+        *playing
+    }
+}
+
+// Goes at the end of the effects. Once nothing plays and what is left of
+// the fade and the filters has died away, it gives silence without asking
+// them, so a paused player costs next to nothing.
+pub struct IdleGate<S> {
+    input: S,
+    state: Arc<Mutex<PlaybackState>>,
+    idle: bool,
+    // samples in a row below QUIET_LEVEL
+    quiet: usize,
+    quiet_needed: usize,
+    countdown: usize,
+}
+
+impl<S: Source<Item = f32>> IdleGate<S> {
+    pub fn new(input: S, state: Arc<Mutex<PlaybackState>>) -> Self {
+        let quiet_needed = duration_to_samples(QUIET_TIME, input.sample_rate()) as usize;
+        Self {
+            input,
+            state,
+            idle: false,
+            quiet: 0,
+            quiet_needed,
+            countdown: 0,
+        }
+    }
+}
+
+impl<S: Source<Item = f32>> Iterator for IdleGate<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        // on a frame boundary, BLOCK_LEN holds whole frames
+        if self.countdown == 0 {
+            self.countdown = BLOCK_LEN;
+            let state = self.state.lock().unwrap();
+            self.idle = state.is_idle() && self.quiet >= self.quiet_needed;
+            state.activity.set_playing(!self.idle);
+        }
+        self.countdown -= 1;
+        if self.idle {
+            return Some(0.0);
+        }
+        let sample = self.input.next()?;
+        if sample.abs() < QUIET_LEVEL {
+            self.quiet += 1;
+        } else {
+            self.quiet = 0;
+        }
+        Some(sample)
+    }
+}
+
+impl<S: Source<Item = f32>> Source for IdleGate<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rodio::buffer::SamplesBuffer;
+
+    use super::*;
+
+    const RATE: u32 = 1000;
+
+    fn constant(id: u64, value: f32, seconds: usize) -> Track {
+        let samples = vec![value; seconds * RATE as usize * CHANNELS as usize];
+        let source = SamplesBuffer::new(CHANNELS, RATE, samples);
+        Track::new(id, Box::new(source), None, RATE)
+    }
+
+    // `seconds` of tone, then silence up to `total`
+    fn tone_then_silence(seconds: usize, total: usize) -> Track {
+        let mut samples = vec![0.5; seconds * RATE as usize * CHANNELS as usize];
+        samples.resize(total * RATE as usize * CHANNELS as usize, 0.0);
+        let source = SamplesBuffer::new(CHANNELS, RATE, samples);
+        let total = Duration::from_secs(total as u64);
+        Track::new(0, Box::new(source), Some(total), RATE)
+    }
+
+    #[test]
+    fn skipped_silence_at_the_end_counts_as_played() {
+        let mut track = tone_then_silence(1, 4);
+        let mut played = 0;
+        while track.next_sample(Some(0.01)).is_some() {
+            played += 1;
+        }
+        // the second of tone and a bit of the silence before it was clear
+        assert!(played < 2 * 2 * RATE as u64, "{}", played);
+        assert_eq!(track.samples_played, track.total_samples.unwrap());
+    }
+
+    #[test]
+    fn held_silence_stays_in_the_preallocated_buffer() {
+        let mut track = tone_then_silence(1, 20);
+        let capacity = track.pending.capacity();
+        let mut most = 0;
+        let mut played = 0;
+        while track.next_sample(Some(0.01)).is_some() {
+            most = most.max(track.pending.len());
+            played += 1;
+        }
+        assert_eq!(track.pending.capacity(), capacity);
+        assert!(most <= track.max_held + SILENCE_LOOKAHEAD * CHANNELS as usize);
+        // a silence longer than what is held is cut by as much
+        let held = duration_to_samples(MAX_SILENCE_HELD, RATE);
+        assert!(played <= 20 * 2 * RATE as u64 - held, "{}", played);
+        assert_eq!(track.samples_played, track.total_samples.unwrap());
+    }
+
+    fn left_channel(playback: &mut Playback, frames: usize) -> Vec<f32> {
+        (0..frames).map(|_| playback.nth(1).unwrap()).collect()
+    }
+
+    #[test]
+    fn next_track_fades_in_after_the_old_one_faded_out() {
+        let state = Arc::new(Mutex::new(PlaybackState::new(RATE, Arc::default())));
+        let mut playback = Playback::new(state.clone(), RATE);
+        // 20 frames at this rate
+        state.lock().unwrap().set_fade(Duration::from_millis(20));
+        state.lock().unwrap().switch_to(Some(constant(0, 0.5, 5)));
+        // the first track fades in as well
+        let start = left_channel(&mut playback, 100);
+        assert!(start[0] < 0.05);
+        assert_eq!(start[99], 0.5);
+
+        state.lock().unwrap().switch_to(Some(constant(1, 1.0, 5)));
+        // what is left of the block in the works plays first
+        let switch = left_channel(&mut playback, 2 * BLOCK_LEN);
+        let lowest = (0..switch.len())
+            .min_by(|&a, &b| switch[a].total_cmp(&switch[b]))
+            .unwrap();
+        assert!(switch[lowest] < 0.05);
+        assert!(switch[..lowest].windows(2).all(|w| w[1] <= w[0]));
+        let rest = &switch[lowest..];
+        assert!(rest.windows(2).all(|w| w[1] >= w[0]), "{:?}", rest);
+        // in about as long as the fade
+        assert!(rest[15] < 1.0 && rest[25] == 1.0, "{:?}", rest);
+    }
+
+    // samples that tell which frame of the track they are
+    fn ramp(from: usize, frames: usize) -> TrackSource {
+        let samples: Vec<f32> = (from..frames).flat_map(|i| [i as f32; 2]).collect();
+        Box::new(SamplesBuffer::new(CHANNELS, RATE, samples))
+    }
+
+    #[test]
+    fn loop_swaps_in_the_spare_source_at_b() {
+        let state = Arc::new(Mutex::new(PlaybackState::new(RATE, Arc::default())));
+        let mut playback = Playback::new(state.clone(), RATE);
+        let mut track = Track::new(0, ramp(0, 10_000), None, RATE);
+        track.file = Some(PathBuf::from("/music/song.flac"));
+        {
+            let mut state = state.lock().unwrap();
+            state.switch_to(Some(track));
+            state.ab_loop.start = Some(1000 * CHANNELS as u64);
+            state.ab_loop.end = Some(2000 * CHANNELS as u64);
+        }
+        let request = state.lock().unwrap().loop_spare_request().unwrap();
+        assert_eq!(request.position, Duration::from_secs(1));
+        assert!(request.source.is_none());
+
+        // without the spare it holds at B
+        let held = left_channel(&mut playback, 2100);
+        assert_eq!(held[1990], 1990.0);
+        assert!(held[2000..].iter().all(|&v| v == 0.0));
+        assert_eq!(state.lock().unwrap().position(), Duration::from_secs(2));
+
+        let spare = ramp(1000, 10_000);
+        state
+            .lock()
+            .unwrap()
+            .set_loop_spare(0, request.start, spare);
+        let looped = left_channel(&mut playback, 2 * BLOCK_LEN);
+        let back = looped.iter().position(|&v| v > 0.0).unwrap();
+        // faded in from A
+        assert!(looped[back] > 0.0 && looped[back] < 1001.0);
+        assert_eq!(looped[back + 10], 1010.0);
+        // and the old source is there to be made the next spare
+        let request = state.lock().unwrap().loop_spare_request().unwrap();
+        assert!(request.source.is_some());
+    }
+
+    #[test]
+    fn sleep_fade_ramps_down_and_back_up() {
+        let state = Arc::new(Mutex::new(PlaybackState::new(RATE, Arc::default())));
+        let mut playback = Playback::new(state.clone(), RATE);
+        state.lock().unwrap().set_fade(Duration::from_millis(20));
+        state.lock().unwrap().switch_to(Some(constant(0, 0.5, 30)));
+        left_channel(&mut playback, 2 * BLOCK_LEN);
+
+        state.lock().unwrap().fade_to_sleep(Duration::from_secs(1));
+        // the block in the works first, then a second down to nothing
+        let fade = left_channel(&mut playback, 2 * BLOCK_LEN + RATE as usize);
+        assert!(fade.windows(2).all(|w| w[1] <= w[0]));
+        let half = fade.iter().position(|&v| v < 0.25).unwrap();
+        assert!(half > 400 && half < 500 + 2 * BLOCK_LEN, "{}", half);
+        assert_eq!(fade[fade.len() - 1], 0.0);
+
+        state.lock().unwrap().cancel_sleep_fade();
+        let back = left_channel(&mut playback, 2 * BLOCK_LEN + 50);
+        assert_eq!(back[back.len() - 1], 0.5);
+    }
+
+    // counts what the gate takes from the effects
+    struct Pulled<S> {
+        input: S,
+        count: Arc<AtomicU64>,
+    }
+
+    impl<S: Source<Item = f32>> Iterator for Pulled<S> {
+        type Item = f32;
+
+        fn next(&mut self) -> Option<f32> {
+            self.count.fetch_add(1, Ordering::Relaxed);
+            self.input.next()
+        }
+    }
+
+    impl<S: Source<Item = f32>> Source for Pulled<S> {
+        fn current_frame_len(&self) -> Option<usize> {
+            None
+        }
+
+        fn channels(&self) -> u16 {
+            self.input.channels()
+        }
+
+        fn sample_rate(&self) -> u32 {
+            self.input.sample_rate()
+        }
+
+        fn total_duration(&self) -> Option<Duration> {
+            None
+        }
+    }
+
+    #[test]
+    fn the_gate_leaves_the_effects_alone_while_paused() {
+        let activity = Arc::new(Activity::default());
+        let state = Arc::new(Mutex::new(PlaybackState::new(RATE, activity.clone())));
+        let count = Arc::new(AtomicU64::new(0));
+        let input = Pulled {
+            input: Playback::new(state.clone(), RATE),
+            count: count.clone(),
+        };
+        let mut gate = IdleGate::new(input, state.clone());
+        let mut play =
+            |blocks: usize| -> Vec<f32> { gate.by_ref().take(blocks * BLOCK_LEN).collect() };
+        // nothing to play
+        play(4);
+        assert!(!activity.is_playing());
+
+        state.lock().unwrap().set_fade(Duration::from_millis(20));
+        state.lock().unwrap().switch_to(Some(constant(0, 0.5, 30)));
+        assert!(activity.is_playing());
+        assert_eq!(play(4).last(), Some(&0.5));
+
+        // the fade out and a bit of quiet still go through, then nothing
+        state.lock().unwrap().set_paused(true);
+        play(4);
+        assert!(!activity.is_playing());
+        let pulled = count.load(Ordering::Relaxed);
+        assert!(play(4).iter().all(|&v| v == 0.0));
+        assert_eq!(count.load(Ordering::Relaxed), pulled);
+
+        state.lock().unwrap().set_paused(false);
+        assert!(activity.is_playing());
+        assert_eq!(play(4).last(), Some(&0.5));
+    }
+}
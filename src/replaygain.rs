@@ -0,0 +1,173 @@
+use lofty::tag::{ItemKey, Tag};
+use serde::Deserialize;
+
+// R128 gains are stored relative to -23 LUFS, ReplayGain 2.0 uses -18 LUFS.
+const R128_TO_REPLAYGAIN_DB: f32 = 5.0;
+
+#[derive(Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplayGainMode {
+    #[default]
+    Off,
+    Track,
+    Album,
+}
+
+impl ReplayGainMode {
+    pub fn next(&self) -> Self {
+        match self {
+            Self::Off => Self::Track,
+            Self::Track => Self::Album,
+            Self::Album => Self::Off,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Off => "Off",
+            Self::Track => "Track",
+            Self::Album => "Album",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct ReplayGain {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+impl ReplayGain {
+    pub fn from_tag(tag: &Tag) -> Self {
+        let value = |key: ItemKey| tag.get_string(&key).and_then(parse_leading_number);
+        let r128 = |key: &str| {
+            tag.get_string(&ItemKey::Unknown(key.to_string()))
+                .and_then(|v| v.trim().parse::<i32>().ok())
+                .map(|v| v as f32 / 256.0 + R128_TO_REPLAYGAIN_DB)
+        };
+
+        Self {
+            track_gain: value(ItemKey::ReplayGainTrackGain).or_else(|| r128("R128_TRACK_GAIN")),
+            track_peak: value(ItemKey::ReplayGainTrackPeak),
+            album_gain: value(ItemKey::ReplayGainAlbumGain).or_else(|| r128("R128_ALBUM_GAIN")),
+            album_peak: value(ItemKey::ReplayGainAlbumPeak),
+        }
+    }
+
+    // Linear factor for the given mode. Album mode falls back to the track
+    // values and the other way round, and the gain is lowered when the peak
+    // says the track would clip with it.
+    pub fn factor(&self, mode: ReplayGainMode) -> f32 {
+        let (gain, peak) = match mode {
+            ReplayGainMode::Off => return 1.0,
+            ReplayGainMode::Track => (
+                self.track_gain.or(self.album_gain),
+                self.track_peak.or(self.album_peak),
+            ),
+            ReplayGainMode::Album => (
+                self.album_gain.or(self.track_gain),
+                self.album_peak.or(self.track_peak),
+            ),
+        };
+        let Some(gain) = gain else {
+            return 1.0;
+        };
+        let factor = 10f32.powf(gain / 20.0);
+        match peak {
+            Some(peak) if peak > 0.0 => factor.min(1.0 / peak),
+            _ => factor,
+        }
+    }
+}
+
+// Tags look like "-6.54 dB" or "0.988547".
+fn parse_leading_number(value: &str) -> Option<f32> {
+    let value = value.trim();
+    let end = value
+        .find(|c: char| !(c.is_ascii_digit() || matches!(c, '+' | '-' | '.')))
+        .unwrap_or(value.len());
+    value[..end].parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use lofty::tag::{ItemValue, TagItem, TagType};
+
+    use super::*;
+
+    // Keys without a mapping come out of a file as `ItemKey::Unknown`,
+    // `insert_text` would refuse them.
+    fn tag(items: &[(ItemKey, &str)]) -> Tag {
+        let mut tag = Tag::new(TagType::VorbisComments);
+        for (key, value) in items {
+            tag.insert_unchecked(TagItem::new(
+                key.clone(),
+                ItemValue::Text(value.to_string()),
+            ));
+        }
+        tag
+    }
+
+    #[test]
+    fn numbers_are_read_up_to_the_unit() {
+        assert_eq!(parse_leading_number("-6.54 dB"), Some(-6.54));
+        assert_eq!(parse_leading_number(" +2.10dB "), Some(2.1));
+        assert_eq!(parse_leading_number("0.988547"), Some(0.988547));
+        assert_eq!(parse_leading_number("dB"), None);
+        assert_eq!(parse_leading_number(""), None);
+    }
+
+    #[test]
+    fn replaygain_tags_are_read() {
+        let gain = ReplayGain::from_tag(&tag(&[
+            (ItemKey::ReplayGainTrackGain, "-6.54 dB"),
+            (ItemKey::ReplayGainTrackPeak, "0.988547"),
+            (ItemKey::ReplayGainAlbumGain, "-5.00 dB"),
+            (ItemKey::ReplayGainAlbumPeak, "1.021"),
+        ]));
+        assert_eq!(gain.track_gain, Some(-6.54));
+        assert_eq!(gain.track_peak, Some(0.988547));
+        assert_eq!(gain.album_gain, Some(-5.0));
+        assert_eq!(gain.album_peak, Some(1.021));
+    }
+
+    #[test]
+    fn r128_gains_are_moved_to_the_replaygain_reference() {
+        // Q7.8 relative to -23 LUFS, -1024 is -4 dB there
+        let gain = ReplayGain::from_tag(&tag(&[
+            (ItemKey::Unknown("R128_TRACK_GAIN".to_string()), "-1024"),
+            (ItemKey::Unknown("R128_ALBUM_GAIN".to_string()), "512"),
+        ]));
+        assert_eq!(gain.track_gain, Some(1.0));
+        assert_eq!(gain.album_gain, Some(7.0));
+    }
+
+    #[test]
+    fn replaygain_tags_win_over_r128() {
+        let gain = ReplayGain::from_tag(&tag(&[
+            (ItemKey::ReplayGainTrackGain, "-3 dB"),
+            (ItemKey::Unknown("R128_TRACK_GAIN".to_string()), "0"),
+        ]));
+        assert_eq!(gain.track_gain, Some(-3.0));
+    }
+
+    #[test]
+    fn factor_falls_back_to_the_other_mode_and_prevents_clipping() {
+        let track_only = ReplayGain {
+            track_gain: Some(-20.0),
+            ..Default::default()
+        };
+        assert_eq!(track_only.factor(ReplayGainMode::Off), 1.0);
+        assert!((track_only.factor(ReplayGainMode::Album) - 0.1).abs() < 1e-6);
+
+        let loud_peak = ReplayGain {
+            track_gain: Some(6.0),
+            track_peak: Some(0.8),
+            ..Default::default()
+        };
+        assert!((loud_peak.factor(ReplayGainMode::Track) - 1.25).abs() < 1e-6);
+        assert_eq!(ReplayGain::default().factor(ReplayGainMode::Track), 1.0);
+    }
+}
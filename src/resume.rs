@@ -0,0 +1,117 @@
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::config_dir;
+use crate::cue::CueTrack;
+
+const POSITIONS_FILE: &str = "positions.toml";
+// closer than this to the start or the end counts as not started or finished
+const MARGIN: Duration = Duration::from_secs(10);
+
+#[derive(Default, Serialize, Deserialize)]
+struct PositionsFile {
+    // seconds into the file
+    positions: BTreeMap<String, f64>,
+}
+
+// Where long files were left off, kept in positions.toml next to config.toml.
+pub struct ResumePositions {
+    enabled: bool,
+    positions: BTreeMap<String, f64>,
+    min_length: Duration,
+    dirs: Vec<PathBuf>,
+}
+
+// The key of a file, or of a track in it for cue sheets.
+pub fn resume_key(path: &Path, cue_track: Option<&CueTrack>) -> String {
+    match cue_track {
+        Some(track) => format!("{}#{:02}", path.display(), track.number),
+        None => path.display().to_string(),
+    }
+}
+
+impl ResumePositions {
+    // Files at least `min_length` long and everything below `dirs` are
+    // remembered. A broken file is reported and started over.
+    pub fn load(enabled: bool, min_length: Duration, dirs: &[PathBuf]) -> (Self, Option<String>) {
+        let mut resume = Self {
+            enabled,
+            positions: BTreeMap::new(),
+            min_length,
+            dirs: dirs.iter().map(|dir| expand_home(dir)).collect(),
+        };
+        if !enabled {
+            return (resume, None);
+        }
+        let Some(path) = config_dir().map(|dir| dir.join(POSITIONS_FILE)) else {
+            return (resume, None);
+        };
+        let Ok(content) = fs::read_to_string(&path) else {
+            return (resume, None);
+        };
+        match toml::from_str::<PositionsFile>(&content) {
+            Ok(file) => {
+                resume.positions = file.positions;
+                (resume, None)
+            }
+            Err(e) => (
+                resume,
+                Some(format!(
+                    "Invalid positions {}: {}",
+                    path.display(),
+                    e.message()
+                )),
+            ),
+        }
+    }
+
+    pub fn applies_to(&self, path: &Path, length: Duration) -> bool {
+        self.enabled
+            && (length >= self.min_length || self.dirs.iter().any(|dir| path.starts_with(dir)))
+    }
+
+    pub fn get(&self, key: &str) -> Option<Duration> {
+        self.positions
+            .get(key)
+            .map(|&secs| Duration::from_secs_f64(secs.max(0.0)))
+    }
+
+    // Keeps `position` for the next time, or forgets the file when it was
+    // hardly started or played to the end. Returns whether anything changed.
+    pub fn update(&mut self, key: String, position: Duration, length: Duration) -> bool {
+        let finished = !length.is_zero() && position + MARGIN >= length;
+        if position < MARGIN || finished {
+            return self.forget(&key);
+        }
+        let secs = (position.as_secs_f64() * 10.0).round() / 10.0;
+        self.positions.insert(key, secs) != Some(secs)
+    }
+
+    pub fn forget(&mut self, key: &str) -> bool {
+        self.positions.remove(key).is_some()
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let dir = config_dir().ok_or("No config directory found")?;
+        let file = PositionsFile {
+            positions: self.positions.clone(),
+        };
+        let content = toml::to_string(&file).map_err(|e| e.to_string())?;
+        fs::create_dir_all(&dir)
+            .and_then(|_| fs::write(dir.join(POSITIONS_FILE), content))
+            .map_err(|e| e.to_string())
+    }
+}
+
+fn expand_home(dir: &Path) -> PathBuf {
+    match (dir.strip_prefix("~"), env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => dir.to_path_buf(),
+    }
+}
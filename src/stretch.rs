@@ -0,0 +1,354 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use rodio::Source;
+
+pub const MIN_SPEED: f32 = 0.5;
+pub const MAX_SPEED: f32 = 3.0;
+// lengths in milliseconds, tuned for speech as in SoundTouch
+const SEQUENCE_MS: u32 = 40;
+const SEEK_WINDOW_MS: u32 = 15;
+const OVERLAP_MS: u32 = 8;
+// samples between two looks at the speed while passing audio through
+const UPDATE_INTERVAL: usize = 1024;
+
+// Changes the tempo without changing the pitch (WSOLA). The input is cut
+// into overlapping sequences; every sequence is moved by up to the seek
+// window so it lines up best with the end of the previous one, which
+// avoids the phasing a plain overlap-add would produce.
+pub struct TimeStretch<S> {
+    input: S,
+    speed: Arc<Mutex<f32>>,
+    current_speed: f32,
+    channels: usize,
+    sequence: usize,
+    seek_window: usize,
+    overlap: usize,
+    // interleaved input not consumed yet
+    buffer: Vec<f32>,
+    // end of the previous sequence, faded into the next one
+    mid: Vec<f32>,
+    output: Vec<f32>,
+    output_index: usize,
+    skip_fraction: f64,
+    countdown: usize,
+    channel: usize,
+    // mono mixes for `best_offset`, kept to not allocate on the audio thread
+    reference: Vec<f32>,
+    candidate: Vec<f32>,
+    // bumped by the input on a seek or a new track, see `PlaybackState`
+    jumps: Arc<AtomicU64>,
+    jumps_seen: u64,
+}
+
+impl<S: Source<Item = f32>> TimeStretch<S> {
+    pub fn new(input: S, speed: Arc<Mutex<f32>>, jumps: Arc<AtomicU64>) -> Self {
+        let channels = input.channels() as usize;
+        let frames = |ms: u32| (input.sample_rate() * ms / 1000) as usize;
+        let sequence = frames(SEQUENCE_MS);
+        let seek_window = frames(SEEK_WINDOW_MS);
+        let overlap = frames(OVERLAP_MS);
+        // the most `process_sequence` ever holds, at the highest speed
+        let longest_skip = ((sequence - overlap) as f32 * MAX_SPEED) as usize + 1;
+        let buffered = (seek_window + sequence).max(longest_skip) * channels;
+        let jumps_seen = jumps.load(Ordering::Relaxed);
+        Self {
+            input,
+            speed,
+            current_speed: 1.0,
+            channels,
+            sequence,
+            seek_window,
+            overlap,
+            buffer: Vec::with_capacity(buffered),
+            mid: Vec::with_capacity(overlap * channels),
+            output: Vec::with_capacity(buffered + overlap * channels),
+            output_index: 0,
+            skip_fraction: 0.0,
+            countdown: 0,
+            channel: 0,
+            reference: Vec::with_capacity(overlap),
+            candidate: Vec::with_capacity(seek_window + overlap),
+            jumps,
+            jumps_seen,
+        }
+    }
+
+    // Forgets everything buffered, it belongs to before the jump.
+    fn reset(&mut self) {
+        self.buffer.clear();
+        self.mid.clear();
+        self.output.clear();
+        self.output_index = 0;
+        self.skip_fraction = 0.0;
+    }
+
+    fn at_frame_start(&self) -> bool {
+        if self.output_index < self.output.len() {
+            self.output_index.is_multiple_of(self.channels)
+        } else {
+            self.channel == 0
+        }
+    }
+
+    fn process_sequence(&mut self) {
+        let c = self.channels;
+        self.output.clear();
+        self.output_index = 0;
+
+        if self.current_speed == 1.0 {
+            // back to normal speed, hand out what is still buffered
+            self.output.append(&mut self.mid);
+            self.output.append(&mut self.buffer);
+            return;
+        }
+
+        let skip =
+            (self.sequence - self.overlap) as f64 * self.current_speed as f64 + self.skip_fraction;
+        let skip_frames = skip as usize;
+        self.skip_fraction = skip - skip_frames as f64;
+
+        let needed = (self.seek_window + self.sequence).max(skip_frames) * c;
+        while self.buffer.len() < needed {
+            match self.input.next() {
+                Some(sample) => self.buffer.push(sample),
+                None => {
+                    self.output.append(&mut self.mid);
+                    self.output.append(&mut self.buffer);
+                    return;
+                }
+            }
+        }
+
+        let offset = if self.mid.is_empty() {
+            self.output
+                .extend_from_slice(&self.buffer[..(self.sequence - self.overlap) * c]);
+            0
+        } else {
+            let offset = self.best_offset();
+            for i in 0..self.overlap {
+                let t = i as f32 / self.overlap as f32;
+                for ch in 0..c {
+                    let old = self.mid[i * c + ch];
+                    let new = self.buffer[(offset + i) * c + ch];
+                    self.output.push(old * (1.0 - t) + new * t);
+                }
+            }
+            self.output.extend_from_slice(
+                &self.buffer
+                    [(offset + self.overlap) * c..(offset + self.sequence - self.overlap) * c],
+            );
+            offset
+        };
+        self.mid.clear();
+        self.mid.extend_from_slice(
+            &self.buffer[(offset + self.sequence - self.overlap) * c..(offset + self.sequence) * c],
+        );
+        self.buffer.drain(..skip_frames * c);
+    }
+
+    // Offset into the buffer where the input looks most like the end of the
+    // previous sequence, by normalized cross correlation of the mono mix.
+    fn best_offset(&mut self) -> usize {
+        let c = self.channels;
+        let mono = |samples: &[f32], frame: usize| -> f32 {
+            samples[frame * c..(frame + 1) * c].iter().sum()
+        };
+        self.reference.clear();
+        self.reference
+            .extend((0..self.overlap).map(|i| mono(&self.mid, i)));
+        self.candidate.clear();
+        self.candidate
+            .extend((0..self.seek_window + self.overlap).map(|i| mono(&self.buffer, i)));
+
+        let mut best = (0, f32::MIN);
+        for offset in 0..self.seek_window {
+            let window = &self.candidate[offset..offset + self.overlap];
+            let correlation: f32 = self.reference.iter().zip(window).map(|(a, b)| a * b).sum();
+            let energy: f32 = window.iter().map(|b| b * b).sum();
+            let score = correlation / (energy + 1e-9).sqrt();
+            if score > best.1 {
+                best = (offset, score);
+            }
+        }
+        best.0
+    }
+}
+
+impl<S: Source<Item = f32>> Iterator for TimeStretch<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let jumps = self.jumps.load(Ordering::Relaxed);
+        if jumps != self.jumps_seen && self.at_frame_start() {
+            self.jumps_seen = jumps;
+            self.reset();
+        }
+        if self.output_index < self.output.len() {
+            let sample = self.output[self.output_index];
+            self.output_index += 1;
+            return Some(sample);
+        }
+
+        // the speed only changes on frame boundaries
+        if self.channel == 0 && (self.countdown == 0 || self.current_speed != 1.0) {
+            self.current_speed = *self.speed.lock().unwrap();
+            self.countdown = UPDATE_INTERVAL;
+            if self.current_speed != 1.0 || !self.mid.is_empty() || !self.buffer.is_empty() {
+                self.process_sequence();
+                if self.output_index < self.output.len() {
+                    self.output_index += 1;
+                    return Some(self.output[0]);
+                }
+            }
+        }
+
+        self.countdown = self.countdown.saturating_sub(1);
+        self.channel = (self.channel + 1) % self.channels;
+        self.input.next()
+    }
+}
+
+impl<S: Source<Item = f32>> Source for TimeStretch<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels as u16
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+    use std::{f32::consts::PI, sync::atomic::AtomicUsize};
+
+    const RATE: u32 = 44100;
+
+    fn stretch<S: Source<Item = f32>>(input: S, speed: f32) -> TimeStretch<S> {
+        TimeStretch::new(
+            input,
+            Arc::new(Mutex::new(speed)),
+            Arc::new(AtomicU64::new(0)),
+        )
+    }
+
+    // A stereo sine with the right channel inverted, to tell them apart.
+    fn sine(freq: f32, seconds: f32) -> SamplesBuffer<f32> {
+        let frames = (seconds * RATE as f32) as usize;
+        let samples = (0..frames)
+            .flat_map(|i| {
+                let x = (2.0 * PI * freq * i as f32 / RATE as f32).sin();
+                [x, -x]
+            })
+            .collect::<Vec<_>>();
+        SamplesBuffer::new(2, RATE, samples)
+    }
+
+    // Mono ramp of the input position, which can be moved like a seek does.
+    struct Counter {
+        position: Arc<AtomicUsize>,
+    }
+
+    impl Iterator for Counter {
+        type Item = f32;
+
+        fn next(&mut self) -> Option<f32> {
+            Some(self.position.fetch_add(1, Ordering::Relaxed) as f32)
+        }
+    }
+
+    impl Source for Counter {
+        fn current_frame_len(&self) -> Option<usize> {
+            None
+        }
+
+        fn channels(&self) -> u16 {
+            1
+        }
+
+        fn sample_rate(&self) -> u32 {
+            RATE
+        }
+
+        fn total_duration(&self) -> Option<Duration> {
+            None
+        }
+    }
+
+    #[test]
+    fn normal_speed_passes_input_through() {
+        let input: Vec<f32> = sine(440.0, 1.0).collect();
+        let output: Vec<f32> = stretch(sine(440.0, 1.0), 1.0).collect();
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn output_length_follows_speed() {
+        let input_len = sine(440.0, 2.0).count();
+        // what is still buffered when the input ends comes out unstretched
+        let tail = (SEEK_WINDOW_MS + SEQUENCE_MS + OVERLAP_MS) * RATE / 1000 * 2;
+        for speed in [MIN_SPEED, 0.75, 1.5, 2.0, MAX_SPEED] {
+            let output_len = stretch(sine(440.0, 2.0), speed).count();
+            assert_eq!(output_len % 2, 0, "speed {speed} split a frame");
+            let expected = input_len as f32 / speed;
+            assert!(
+                (output_len as f32 - expected).abs() < tail as f32,
+                "speed {speed} gave {output_len} samples, expected {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn pitch_is_kept() {
+        for speed in [MIN_SPEED, 2.0] {
+            let output: Vec<f32> = stretch(sine(440.0, 2.0), speed).collect();
+            let left: Vec<f32> = output.iter().step_by(2).copied().collect();
+            let crossings = left
+                .windows(2)
+                .filter(|pair| (pair[0] < 0.0) != (pair[1] < 0.0))
+                .count();
+            let freq = crossings as f32 / 2.0 / (left.len() as f32 / RATE as f32);
+            assert!(
+                (freq - 440.0).abs() < 440.0 * 0.03,
+                "speed {speed} moved 440 Hz to {freq}"
+            );
+            // the channels stay in place
+            assert!(output.chunks(2).all(|frame| frame[0] == -frame[1]));
+        }
+    }
+
+    #[test]
+    fn jump_drops_buffered_audio() {
+        let position = Arc::new(AtomicUsize::new(0));
+        let jumps = Arc::new(AtomicU64::new(0));
+        let counter = Counter {
+            position: position.clone(),
+        };
+        let mut stretcher = TimeStretch::new(counter, Arc::new(Mutex::new(1.5)), jumps.clone());
+        for _ in 0..RATE {
+            assert!(stretcher.next().unwrap() < 100_000.0);
+        }
+
+        position.store(1_000_000, Ordering::Relaxed);
+        jumps.fetch_add(1, Ordering::Relaxed);
+        for _ in 0..RATE {
+            assert!(stretcher.next().unwrap() >= 1_000_000.0);
+        }
+    }
+}
@@ -0,0 +1,1437 @@
+use std::io::{Seek, SeekFrom};
+
+use symphonia::core::{
+    audio::{AsAudioBufferRef, AudioBuffer, AudioBufferRef, Channels, Signal, SignalSpec},
+    codecs::{
+        decl_codec_type, CodecDescriptor, CodecParameters, CodecType, Decoder, DecoderOptions,
+        FinalizeResult,
+    },
+    errors::{decode_error, seek_error, unsupported_error, Error, Result, SeekErrorKind},
+    formats::{Cue, FormatOptions, FormatReader, Packet, SeekMode, SeekTo, SeekedTo, Track},
+    io::{MediaSource, MediaSourceStream, ReadBytes},
+    meta::{Metadata, MetadataLog},
+    probe::{Descriptor, Instantiate, QueryDescriptor},
+    support_codec, support_format,
+    units::TimeBase,
+};
+
+// WavPack for symphonia, which has no reader or decoder for it. Lossless
+// integer streams are decoded, hybrid (lossy), float and DSD streams are
+// refused. Follows the format as libwavpack writes it, version 4 and 5.
+
+pub const CODEC_TYPE_WAVPACK: CodecType = decl_codec_type(b"wvpk");
+
+const HEADER_LEN: usize = 32;
+// libwavpack never writes bigger blocks
+const MAX_BLOCK_LEN: u32 = 1 << 20;
+// nor more than half a second at 384kHz, a bigger count would only make
+// the decoder allocate for a damaged header
+const MAX_BLOCK_SAMPLES: u32 = 1 << 18;
+
+const BYTES_PER_SAMPLE: u32 = 0x3;
+const MONO: u32 = 0x4;
+const HYBRID: u32 = 0x8;
+const JOINT_STEREO: u32 = 0x10;
+const FLOAT_DATA: u32 = 0x80;
+const INITIAL_BLOCK: u32 = 0x800;
+const FINAL_BLOCK: u32 = 0x1000;
+const SHIFT_LSB: u32 = 13;
+const SRATE_LSB: u32 = 23;
+const FALSE_STEREO: u32 = 0x4000_0000;
+const DSD: u32 = 0x8000_0000;
+
+const SAMPLE_RATES: [u32; 15] = [
+    6000, 8000, 9600, 11025, 12000, 16000, 22050, 24000, 32000, 44100, 48000, 64000, 88200, 96000,
+    192000,
+];
+
+// metadata sub-block ids, the upper bits are flags
+const ID_UNIQUE: u8 = 0x3f;
+const ID_ODD_SIZE: u8 = 0x40;
+const ID_LARGE: u8 = 0x80;
+const ID_DECORR_TERMS: u8 = 0x2;
+const ID_DECORR_WEIGHTS: u8 = 0x3;
+const ID_DECORR_SAMPLES: u8 = 0x4;
+const ID_ENTROPY_VARS: u8 = 0x5;
+const ID_INT32_INFO: u8 = 0x9;
+const ID_WV_BITSTREAM: u8 = 0xa;
+const ID_CHANNEL_INFO: u8 = 0xd;
+const ID_SAMPLE_RATE: u8 = 0x27;
+
+const EXTRA_BITS_UNSUPPORTED: &str = "wavpack: samples of more than 24 bits are not supported";
+
+const MAX_TERM: usize = 8;
+const MAX_TERMS: usize = 16;
+
+#[derive(Clone, Copy)]
+struct BlockHeader {
+    // of the whole block, header included
+    len: usize,
+    total_samples: u32,
+    block_index: u32,
+    block_samples: u32,
+    flags: u32,
+}
+
+impl BlockHeader {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        if bytes.len() < HEADER_LEN || &bytes[..4] != b"wvpk" {
+            return None;
+        }
+        let size = u32_at(4);
+        let version = u16_at(8);
+        if !(HEADER_LEN as u32 - 8..=MAX_BLOCK_LEN).contains(&size)
+            || !(0x402..=0x410).contains(&version)
+            || u32_at(20) > MAX_BLOCK_SAMPLES
+        {
+            return None;
+        }
+        Some(Self {
+            len: size as usize + 8,
+            total_samples: u32_at(12),
+            block_index: u32_at(16),
+            block_samples: u32_at(20),
+            flags: u32_at(24),
+        })
+    }
+
+    // channels the block holds, false stereo is stored as mono
+    fn stored_channels(&self) -> usize {
+        if self.flags & (MONO | FALSE_STEREO) != 0 {
+            1
+        } else {
+            2
+        }
+    }
+
+    fn output_channels(&self) -> usize {
+        if self.flags & MONO != 0 {
+            1
+        } else {
+            2
+        }
+    }
+
+    fn is_supported(&self) -> bool {
+        self.flags & (HYBRID | FLOAT_DATA | DSD) == 0
+    }
+}
+
+// The metadata sub-blocks after the header, as (id, data).
+fn sub_blocks(block: &[u8]) -> impl Iterator<Item = (u8, &[u8])> + '_ {
+    let mut rest = &block[HEADER_LEN.min(block.len())..];
+    std::iter::from_fn(move || {
+        let &id = rest.first()?;
+        let (words, start) = if id & ID_LARGE != 0 {
+            let size = rest.get(1..4)?;
+            (
+                size[0] as usize | (size[1] as usize) << 8 | (size[2] as usize) << 16,
+                4,
+            )
+        } else {
+            (*rest.get(1)? as usize, 2)
+        };
+        let data = rest.get(start..start + words * 2)?;
+        rest = &rest[start + words * 2..];
+        let len = if id & ID_ODD_SIZE != 0 {
+            data.len().checked_sub(1)?
+        } else {
+            data.len()
+        };
+        Some((id & ID_UNIQUE, &data[..len]))
+    })
+}
+
+pub struct WavPackReader {
+    source: MediaSourceStream,
+    tracks: Vec<Track>,
+    cues: Vec<Cue>,
+    metadata: MetadataLog,
+    // where the first block starts, seeks look for blocks from there
+    data_start: u64,
+}
+
+impl WavPackReader {
+    // Appends the header to `block` if there is one, and tells what it says.
+    fn read_header(&mut self, block: &mut Vec<u8>) -> Result<Option<BlockHeader>> {
+        let mut bytes = [0; HEADER_LEN];
+        match self.source.read_buf_exact(&mut bytes) {
+            Ok(()) => {}
+            // the end of the file, or tags after the last block
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let header = BlockHeader::parse(&bytes);
+        if header.is_some() {
+            block.extend_from_slice(&bytes);
+        }
+        Ok(header)
+    }
+
+    fn read_block(&mut self, block: &mut Vec<u8>) -> Result<Option<BlockHeader>> {
+        let start = block.len();
+        let Some(header) = self.read_header(block)? else {
+            return Ok(None);
+        };
+        block.resize(start + header.len, 0);
+        self.source
+            .read_buf_exact(&mut block[start + HEADER_LEN..])?;
+        Ok(Some(header))
+    }
+
+    // The blocks from an initial to a final one hold all channels of the
+    // same samples. Frames without samples are skipped.
+    fn read_frame(&mut self) -> Result<Option<(BlockHeader, Vec<u8>)>> {
+        loop {
+            let mut frame = Vec::new();
+            let Some(first) = self.read_block(&mut frame)? else {
+                return Ok(None);
+            };
+            let mut last = first;
+            while last.flags & FINAL_BLOCK == 0 {
+                last = match self.read_block(&mut frame)? {
+                    Some(header) if header.flags & INITIAL_BLOCK == 0 => header,
+                    _ => return decode_error("wavpack: frame without a final block"),
+                };
+            }
+            if first.block_samples > 0 {
+                return Ok(Some((first, frame)));
+            }
+        }
+    }
+}
+
+impl QueryDescriptor for WavPackReader {
+    fn query() -> &'static [Descriptor] {
+        &[support_format!(
+            "wavpack",
+            "WavPack",
+            &["wv"],
+            &["audio/wavpack", "audio/x-wavpack"],
+            &[b"wvpk"]
+        )]
+    }
+
+    fn score(_context: &[u8]) -> u8 {
+        255
+    }
+}
+
+impl FormatReader for WavPackReader {
+    fn try_new(source: MediaSourceStream, _options: &FormatOptions) -> Result<Self> {
+        let data_start = source.pos();
+        let mut reader = Self {
+            source,
+            tracks: Vec::new(),
+            cues: Vec::new(),
+            metadata: MetadataLog::default(),
+            data_start,
+        };
+        let Some((first, frame)) = reader.read_frame()? else {
+            return unsupported_error("wavpack: no audio blocks");
+        };
+        reader.source.seek(SeekFrom::Start(data_start))?;
+
+        let mut channel_count = 0;
+        let mut channel_mask = None;
+        let mut sample_rate = SAMPLE_RATES
+            .get((first.flags >> SRATE_LSB & 0xf) as usize)
+            .copied();
+        let mut offset = 0;
+        while offset < frame.len() {
+            let header = BlockHeader::parse(&frame[offset..]).unwrap();
+            if !header.is_supported() {
+                return unsupported_error("wavpack: hybrid, float and DSD are not supported");
+            }
+            let block = &frame[offset..offset + header.len];
+            for (id, data) in sub_blocks(block) {
+                match id {
+                    ID_INT32_INFO if data.first().is_some_and(|&sent_bits| sent_bits > 0) => {
+                        return unsupported_error(EXTRA_BITS_UNSUPPORTED);
+                    }
+                    ID_SAMPLE_RATE if data.len() >= 3 => {
+                        sample_rate = Some(u32::from_le_bytes([data[0], data[1], data[2], 0]));
+                    }
+                    // the mask is the one of WAVE_FORMAT_EXTENSIBLE, older
+                    // files have a byte for the channel count first
+                    ID_CHANNEL_INFO if (1..=5).contains(&data.len()) => {
+                        let mask = data[1..]
+                            .iter()
+                            .rev()
+                            .fold(0, |mask, &byte| mask << 8 | byte as u32);
+                        channel_mask = Some(mask);
+                    }
+                    _ => {}
+                }
+            }
+            channel_count += header.output_channels();
+            offset += header.len;
+        }
+        let Some(sample_rate) = sample_rate.filter(|&rate| rate > 0) else {
+            return decode_error("wavpack: no sample rate");
+        };
+        let channels = layout(channel_count, channel_mask);
+
+        let bytes = (first.flags & BYTES_PER_SAMPLE) + 1;
+        let mut params = CodecParameters::new();
+        params
+            .for_codec(CODEC_TYPE_WAVPACK)
+            .with_sample_rate(sample_rate)
+            .with_time_base(TimeBase::new(1, sample_rate))
+            .with_bits_per_sample(bytes * 8)
+            .with_channels(channels);
+        if first.total_samples != u32::MAX {
+            params.with_n_frames(first.total_samples as u64);
+        }
+        reader.tracks.push(Track::new(0, params));
+        Ok(reader)
+    }
+
+    fn cues(&self) -> &[Cue] {
+        &self.cues
+    }
+
+    fn metadata(&mut self) -> Metadata<'_> {
+        self.metadata.metadata()
+    }
+
+    // Goes through the block headers from the start, the frames are not
+    // indexed anywhere.
+    fn seek(&mut self, _mode: SeekMode, to: SeekTo) -> Result<SeekedTo> {
+        let track = &self.tracks[0];
+        let required_ts = match to {
+            SeekTo::TimeStamp { ts, .. } => ts,
+            SeekTo::Time { time, .. } => match track.codec_params.time_base {
+                Some(time_base) => time_base.calc_timestamp(time),
+                None => return seek_error(SeekErrorKind::Unseekable),
+            },
+        };
+        if !self.source.is_seekable() {
+            return seek_error(SeekErrorKind::Unseekable);
+        }
+        let track_id = track.id;
+        let mut position = self.data_start;
+        loop {
+            self.source.seek(SeekFrom::Start(position))?;
+            let Some(header) = self.read_header(&mut Vec::new())? else {
+                // past the last block, the end it is
+                return Ok(SeekedTo {
+                    track_id,
+                    required_ts,
+                    actual_ts: required_ts,
+                });
+            };
+            let end = header.block_index as u64 + header.block_samples as u64;
+            if header.flags & INITIAL_BLOCK != 0 && header.block_samples > 0 && end > required_ts {
+                self.source.seek(SeekFrom::Start(position))?;
+                return Ok(SeekedTo {
+                    track_id,
+                    required_ts,
+                    actual_ts: header.block_index as u64,
+                });
+            }
+            position += header.len as u64;
+        }
+    }
+
+    fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    fn next_packet(&mut self) -> Result<Packet> {
+        match self.read_frame()? {
+            Some((header, frame)) => Ok(Packet::new_from_boxed_slice(
+                self.tracks[0].id,
+                header.block_index as u64,
+                header.block_samples as u64,
+                frame.into_boxed_slice(),
+            )),
+            None => Err(Error::IoError(std::io::ErrorKind::UnexpectedEof.into())),
+        }
+    }
+
+    fn into_inner(self: Box<Self>) -> MediaSourceStream {
+        self.source
+    }
+}
+
+// The speaker mask if it fits the channel count, otherwise the first
+// speakers in the usual order.
+fn layout(count: usize, mask: Option<u32>) -> Channels {
+    let mask = mask
+        .map(Channels::from_bits_truncate)
+        .filter(|channels| channels.count() == count);
+    mask.unwrap_or_else(|| {
+        (0..count.min(32)).fold(Channels::empty(), |channels, i| {
+            channels | Channels::from_bits_truncate(1 << i)
+        })
+    })
+}
+
+pub struct WavPackDecoder {
+    params: CodecParameters,
+    buffer: AudioBuffer<f32>,
+    // one block of interleaved samples
+    samples: Vec<i32>,
+}
+
+impl Decoder for WavPackDecoder {
+    fn try_new(params: &CodecParameters, _options: &DecoderOptions) -> Result<Self> {
+        let (Some(rate), Some(channels)) = (params.sample_rate, params.channels) else {
+            return unsupported_error("wavpack: no sample rate or channels");
+        };
+        Ok(Self {
+            params: params.clone(),
+            buffer: AudioBuffer::new(0, SignalSpec::new(rate, channels)),
+            samples: Vec::new(),
+        })
+    }
+
+    fn supported_codecs() -> &'static [CodecDescriptor] {
+        &[support_codec!(CODEC_TYPE_WAVPACK, "wavpack", "WavPack")]
+    }
+
+    fn reset(&mut self) {}
+
+    fn codec_params(&self) -> &CodecParameters {
+        &self.params
+    }
+
+    fn decode(&mut self, packet: &Packet) -> Result<AudioBufferRef<'_>> {
+        self.buffer.clear();
+        let data = packet.buf();
+        let Some(first) = BlockHeader::parse(data) else {
+            return decode_error("wavpack: not a block");
+        };
+        let frames = first.block_samples as usize;
+        if self.buffer.capacity() < frames {
+            self.buffer = AudioBuffer::new(frames as u64, *self.buffer.spec());
+        }
+        self.buffer.render_reserved(Some(frames));
+        let channels = self.buffer.spec().channels.count();
+
+        let mut offset = 0;
+        let mut channel = 0;
+        while offset < data.len() {
+            let header = match BlockHeader::parse(&data[offset..]) {
+                Some(header) if offset + header.len <= data.len() => header,
+                _ => return self.fail("wavpack: damaged block"),
+            };
+            let block = &data[offset..offset + header.len];
+            offset += header.len;
+            if header.block_samples != first.block_samples
+                || channel + header.output_channels() > channels
+            {
+                return self.fail("wavpack: blocks don't match the stream");
+            }
+            if let Err(e) = decode_block(block, &header, &mut self.samples) {
+                return self.fail(e);
+            }
+
+            let bytes = (header.flags & BYTES_PER_SAMPLE) + 1;
+            let scale = 1.0 / (1u64 << (bytes * 8 - 1)) as f32;
+            let stored = header.stored_channels();
+            for c in 0..header.output_channels() {
+                let from = c.min(stored - 1);
+                let plane = self.buffer.chan_mut(channel + c);
+                for (i, sample) in plane.iter_mut().enumerate() {
+                    *sample = self.samples[i * stored + from] as f32 * scale;
+                }
+            }
+            channel += header.output_channels();
+        }
+        if channel != channels {
+            return self.fail("wavpack: channels missing");
+        }
+        Ok(self.buffer.as_audio_buffer_ref())
+    }
+
+    fn finalize(&mut self) -> FinalizeResult {
+        FinalizeResult::default()
+    }
+
+    fn last_decoded(&self) -> AudioBufferRef<'_> {
+        self.buffer.as_audio_buffer_ref()
+    }
+}
+
+impl WavPackDecoder {
+    fn fail(&mut self, error: &'static str) -> Result<AudioBufferRef<'_>> {
+        self.buffer.clear();
+        decode_error(error)
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct DecorrPass {
+    term: i32,
+    delta: i32,
+    weight_a: i32,
+    weight_b: i32,
+    samples_a: [i32; MAX_TERM],
+    samples_b: [i32; MAX_TERM],
+}
+
+// Decodes the samples of one block, interleaved if it is stereo.
+fn decode_block(
+    block: &[u8],
+    header: &BlockHeader,
+    samples: &mut Vec<i32>,
+) -> std::result::Result<(), &'static str> {
+    if !header.is_supported() {
+        return Err("wavpack: hybrid, float and DSD are not supported");
+    }
+    let mono = header.stored_channels() == 1;
+    let mut passes: Vec<DecorrPass> = Vec::new();
+    let mut words = Words::default();
+    let mut bitstream = None;
+    let shift = (header.flags >> SHIFT_LSB) & 0x1f;
+    let mut int32_info = [0; 4];
+
+    for (id, data) in sub_blocks(block) {
+        match id {
+            // stored from the last pass to the first
+            ID_DECORR_TERMS => {
+                if data.len() > MAX_TERMS {
+                    return Err("wavpack: too many decorrelation terms");
+                }
+                passes = data
+                    .iter()
+                    .rev()
+                    .map(|&byte| DecorrPass {
+                        term: (byte & 0x1f) as i32 - 5,
+                        delta: (byte >> 5 & 0x7) as i32,
+                        ..Default::default()
+                    })
+                    .collect();
+                let valid = |term: i32| matches!(term, 1..=8 | 17 | 18 | -3..=-1);
+                if passes
+                    .iter()
+                    .any(|pass| !valid(pass.term) || (mono && pass.term < 0))
+                {
+                    return Err("wavpack: bad decorrelation term");
+                }
+            }
+            ID_DECORR_WEIGHTS => {
+                let per_pass = if mono { 1 } else { 2 };
+                if data.len() / per_pass > passes.len() {
+                    return Err("wavpack: too many weights");
+                }
+                for (pass, weights) in passes.iter_mut().rev().zip(data.chunks_exact(per_pass)) {
+                    pass.weight_a = restore_weight(weights[0] as i8);
+                    pass.weight_b = weights.get(1).map_or(0, |&w| restore_weight(w as i8));
+                }
+            }
+            ID_DECORR_SAMPLES => read_decorr_samples(&mut passes, data, mono)?,
+            ID_ENTROPY_VARS => {
+                if data.len() != if mono { 6 } else { 12 } {
+                    return Err("wavpack: bad entropy variables");
+                }
+                for (i, value) in data.chunks_exact(2).enumerate() {
+                    let log = u16::from_le_bytes([value[0], value[1]]) as i32;
+                    words.median[i / 3][i % 3] = exp2s(log) as u32;
+                }
+            }
+            ID_INT32_INFO if data.len() >= 4 => int32_info.copy_from_slice(&data[..4]),
+            ID_WV_BITSTREAM => bitstream = Some(data),
+            _ => {}
+        }
+    }
+    let Some(bitstream) = bitstream else {
+        return Err("wavpack: no bitstream");
+    };
+
+    let stored = header.stored_channels();
+    let count = header.block_samples as usize * stored;
+    samples.clear();
+    samples.reserve(count);
+    let mut bits = BitReader::new(bitstream);
+    for i in 0..count {
+        let Some(word) = words.next(&mut bits, i % stored) else {
+            return Err("wavpack: bitstream ends early");
+        };
+        samples.push(word);
+    }
+
+    for pass in passes.iter_mut() {
+        if mono {
+            decorr_mono_pass(pass, samples);
+        } else {
+            decorr_stereo_pass(pass, samples);
+        }
+    }
+    if !mono && header.flags & JOINT_STEREO != 0 {
+        for frame in samples.chunks_exact_mut(2) {
+            frame[1] = frame[1].wrapping_sub(frame[0] >> 1);
+            frame[0] = frame[0].wrapping_add(frame[1]);
+        }
+    }
+
+    // samples of more than 24 bits can have low bits that are always the
+    // same, those are left out of the bitstream. Bits sent in a separate
+    // bitstream are not read.
+    let [sent_bits, zeros, ones, dups] = int32_info.map(|b| b as u32);
+    if sent_bits > 0 {
+        return Err(EXTRA_BITS_UNSUPPORTED);
+    }
+    if zeros + ones + dups > 0 {
+        for sample in samples.iter_mut() {
+            *sample = if zeros > 0 {
+                sample.wrapping_shl(zeros)
+            } else if ones > 0 {
+                sample.wrapping_add(1).wrapping_shl(ones).wrapping_sub(1)
+            } else {
+                let odd = *sample & 1;
+                sample
+                    .wrapping_add(odd)
+                    .wrapping_shl(dups)
+                    .wrapping_sub(odd)
+            };
+        }
+    }
+    if shift > 0 {
+        for sample in samples.iter_mut() {
+            *sample = sample.wrapping_shl(shift.min(31));
+        }
+    }
+    Ok(())
+}
+
+fn read_decorr_samples(
+    passes: &mut [DecorrPass],
+    data: &[u8],
+    mono: bool,
+) -> std::result::Result<(), &'static str> {
+    if !data.len().is_multiple_of(2) {
+        return Err("wavpack: bad samples");
+    }
+    let mut values = data.chunks_exact(2);
+    let next = |values: &mut std::slice::ChunksExact<u8>| {
+        let value = values.next().ok_or("wavpack: bad samples")?;
+        Ok::<_, &'static str>(exp2s(i16::from_le_bytes([value[0], value[1]]) as i32))
+    };
+    for pass in passes.iter_mut() {
+        pass.samples_a = [0; MAX_TERM];
+        pass.samples_b = [0; MAX_TERM];
+    }
+    // from the last pass to the first, as far as there are samples
+    for pass in passes.iter_mut().rev() {
+        if values.len() == 0 {
+            break;
+        }
+        match pass.term {
+            17 | 18 => {
+                pass.samples_a[0] = next(&mut values)?;
+                pass.samples_a[1] = next(&mut values)?;
+                if !mono {
+                    pass.samples_b[0] = next(&mut values)?;
+                    pass.samples_b[1] = next(&mut values)?;
+                }
+            }
+            term if term < 0 => {
+                pass.samples_a[0] = next(&mut values)?;
+                pass.samples_b[0] = next(&mut values)?;
+            }
+            term => {
+                for m in 0..term as usize {
+                    pass.samples_a[m] = next(&mut values)?;
+                    if !mono {
+                        pass.samples_b[m] = next(&mut values)?;
+                    }
+                }
+            }
+        }
+    }
+    if values.len() == 0 {
+        Ok(())
+    } else {
+        Err("wavpack: bad samples")
+    }
+}
+
+fn restore_weight(weight: i8) -> i32 {
+    let weight = (weight as i32) << 3;
+    if weight > 0 {
+        weight + ((weight + 64) >> 7)
+    } else {
+        weight
+    }
+}
+
+// round(2^(i/256) * 256) - 256, the fraction of the logarithms the encoder
+// stores
+#[rustfmt::skip]
+const EXP2_TABLE: [u8; 256] = [
+    0x00, 0x01, 0x01, 0x02, 0x03, 0x03, 0x04, 0x05, 0x06, 0x06, 0x07, 0x08, 0x08, 0x09, 0x0a, 0x0b,
+    0x0b, 0x0c, 0x0d, 0x0e, 0x0e, 0x0f, 0x10, 0x10, 0x11, 0x12, 0x13, 0x13, 0x14, 0x15, 0x16, 0x16,
+    0x17, 0x18, 0x19, 0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1d, 0x1e, 0x1f, 0x20, 0x20, 0x21, 0x22, 0x23,
+    0x24, 0x24, 0x25, 0x26, 0x27, 0x28, 0x28, 0x29, 0x2a, 0x2b, 0x2c, 0x2c, 0x2d, 0x2e, 0x2f, 0x30,
+    0x30, 0x31, 0x32, 0x33, 0x34, 0x35, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x3a, 0x3b, 0x3c, 0x3d,
+    0x3e, 0x3f, 0x40, 0x41, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x48, 0x49, 0x4a, 0x4b,
+    0x4c, 0x4d, 0x4e, 0x4f, 0x50, 0x51, 0x51, 0x52, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a,
+    0x5b, 0x5c, 0x5d, 0x5e, 0x5e, 0x5f, 0x60, 0x61, 0x62, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+    0x6a, 0x6b, 0x6c, 0x6d, 0x6e, 0x6f, 0x70, 0x71, 0x72, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79,
+    0x7a, 0x7b, 0x7c, 0x7d, 0x7e, 0x7f, 0x80, 0x81, 0x82, 0x83, 0x84, 0x85, 0x87, 0x88, 0x89, 0x8a,
+    0x8b, 0x8c, 0x8d, 0x8e, 0x8f, 0x90, 0x91, 0x92, 0x93, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0x9b,
+    0x9c, 0x9d, 0x9f, 0xa0, 0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa8, 0xa9, 0xaa, 0xab, 0xac, 0xad,
+    0xaf, 0xb0, 0xb1, 0xb2, 0xb3, 0xb4, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xbc, 0xbd, 0xbe, 0xbf, 0xc0,
+    0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc8, 0xc9, 0xca, 0xcb, 0xcd, 0xce, 0xcf, 0xd0, 0xd2, 0xd3, 0xd4,
+    0xd6, 0xd7, 0xd8, 0xd9, 0xdb, 0xdc, 0xdd, 0xde, 0xe0, 0xe1, 0xe2, 0xe4, 0xe5, 0xe6, 0xe8, 0xe9,
+    0xea, 0xec, 0xed, 0xee, 0xf0, 0xf1, 0xf2, 0xf4, 0xf5, 0xf6, 0xf8, 0xf9, 0xfa, 0xfc, 0xfd, 0xff,
+];
+
+// The value of a logarithm with 8 fractional bits, signed.
+fn exp2s(log: i32) -> i32 {
+    if log < 0 {
+        return -exp2s(-log);
+    }
+    let value = EXP2_TABLE[(log & 0xff) as usize] as u32 | 0x100;
+    let log = log >> 8;
+    if log <= 9 {
+        (value >> (9 - log)) as i32
+    } else {
+        value.wrapping_shl((log - 9) as u32 & 0x1f) as i32
+    }
+}
+
+// weight * sample / 1024, rounded
+fn apply_weight(weight: i32, sample: i32) -> i32 {
+    ((weight as i64 * sample as i64 + 512) >> 10) as i32
+}
+
+// The weight follows whether the prediction had the right sign.
+fn update_weight(weight: &mut i32, delta: i32, source: i32, result: i32) {
+    if source != 0 && result != 0 {
+        let s = (source ^ result) >> 31;
+        *weight = (delta ^ s).wrapping_add(weight.wrapping_sub(s));
+    }
+}
+
+fn update_weight_clip(weight: &mut i32, delta: i32, source: i32, result: i32) {
+    if source != 0 && result != 0 {
+        let s = (source ^ result) >> 31;
+        *weight = ((*weight ^ s).wrapping_add(delta - s)).min(1024);
+        *weight = (*weight ^ s) - s;
+    }
+}
+
+// The prediction of terms 17 and 18 from the last two samples, older terms
+// repeat the sample that many steps back.
+fn predict(term: i32, history: &[i32; MAX_TERM]) -> i32 {
+    match term {
+        17 => history[0].wrapping_mul(2).wrapping_sub(history[1]),
+        _ => ((3 * history[0] as i64 - history[1] as i64) >> 1) as i32,
+    }
+}
+
+fn decorr_mono_pass(pass: &mut DecorrPass, samples: &mut [i32]) {
+    let (delta, term) = (pass.delta, pass.term);
+    let (weight, history) = (&mut pass.weight_a, &mut pass.samples_a);
+    if term > MAX_TERM as i32 {
+        for sample in samples.iter_mut() {
+            let predicted = predict(term, history);
+            history[1] = history[0];
+            history[0] = apply_weight(*weight, predicted).wrapping_add(*sample);
+            update_weight(weight, delta, predicted, *sample);
+            *sample = history[0];
+        }
+    } else {
+        let (mut m, mut k) = (0, term as usize & (MAX_TERM - 1));
+        for sample in samples.iter_mut() {
+            let predicted = history[m];
+            history[k] = apply_weight(*weight, predicted).wrapping_add(*sample);
+            update_weight(weight, delta, predicted, *sample);
+            *sample = history[k];
+            m = (m + 1) & (MAX_TERM - 1);
+            k = (k + 1) & (MAX_TERM - 1);
+        }
+    }
+}
+
+fn decorr_stereo_pass(pass: &mut DecorrPass, samples: &mut [i32]) {
+    let delta = pass.delta;
+    match pass.term {
+        term @ (17 | 18) => {
+            for frame in samples.chunks_exact_mut(2) {
+                let [left, right] = frame else { unreachable!() };
+                for (sample, weight, history) in [
+                    (left, &mut pass.weight_a, &mut pass.samples_a),
+                    (right, &mut pass.weight_b, &mut pass.samples_b),
+                ] {
+                    let predicted = predict(term, history);
+                    history[1] = history[0];
+                    history[0] = apply_weight(*weight, predicted).wrapping_add(*sample);
+                    update_weight(weight, delta, predicted, *sample);
+                    *sample = history[0];
+                }
+            }
+        }
+        // the other channel predicts, the sample at the same time or before
+        -1 => {
+            for frame in samples.chunks_exact_mut(2) {
+                let left = frame[0].wrapping_add(apply_weight(pass.weight_a, pass.samples_a[0]));
+                update_weight_clip(&mut pass.weight_a, delta, pass.samples_a[0], frame[0]);
+                frame[0] = left;
+                pass.samples_a[0] = frame[1].wrapping_add(apply_weight(pass.weight_b, left));
+                update_weight_clip(&mut pass.weight_b, delta, left, frame[1]);
+                frame[1] = pass.samples_a[0];
+            }
+        }
+        -2 => {
+            for frame in samples.chunks_exact_mut(2) {
+                let right = frame[1].wrapping_add(apply_weight(pass.weight_b, pass.samples_b[0]));
+                update_weight_clip(&mut pass.weight_b, delta, pass.samples_b[0], frame[1]);
+                frame[1] = right;
+                pass.samples_b[0] = frame[0].wrapping_add(apply_weight(pass.weight_a, right));
+                update_weight_clip(&mut pass.weight_a, delta, right, frame[0]);
+                frame[0] = pass.samples_b[0];
+            }
+        }
+        -3 => {
+            for frame in samples.chunks_exact_mut(2) {
+                let left = frame[0].wrapping_add(apply_weight(pass.weight_a, pass.samples_a[0]));
+                update_weight_clip(&mut pass.weight_a, delta, pass.samples_a[0], frame[0]);
+                let right = frame[1].wrapping_add(apply_weight(pass.weight_b, pass.samples_b[0]));
+                update_weight_clip(&mut pass.weight_b, delta, pass.samples_b[0], frame[1]);
+                pass.samples_b[0] = left;
+                pass.samples_a[0] = right;
+                frame[0] = left;
+                frame[1] = right;
+            }
+        }
+        term => {
+            let (mut m, mut k) = (0, term as usize & (MAX_TERM - 1));
+            for frame in samples.chunks_exact_mut(2) {
+                let [left, right] = frame else { unreachable!() };
+                for (sample, weight, history) in [
+                    (left, &mut pass.weight_a, &mut pass.samples_a),
+                    (right, &mut pass.weight_b, &mut pass.samples_b),
+                ] {
+                    let predicted = history[m];
+                    history[k] = apply_weight(*weight, predicted).wrapping_add(*sample);
+                    update_weight(weight, delta, predicted, *sample);
+                    *sample = history[k];
+                }
+                m = (m + 1) & (MAX_TERM - 1);
+                k = (k + 1) & (MAX_TERM - 1);
+            }
+        }
+    }
+}
+
+// Bits are read from the lowest of each byte up.
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    fn bit(&mut self) -> Option<u32> {
+        let byte = self.bytes.get(self.position / 8)?;
+        let bit = (byte >> (self.position % 8)) & 1;
+        self.position += 1;
+        Some(bit as u32)
+    }
+
+    fn bits(&mut self, count: u32) -> Option<u32> {
+        (0..count).try_fold(0, |value, i| Some(value | self.bit()? << i))
+    }
+
+    // ones up to a zero, at most `limit` of them
+    fn ones(&mut self, limit: u32) -> Option<u32> {
+        let mut count = 0;
+        while count < limit && self.bit()? == 1 {
+            count += 1;
+        }
+        Some(count)
+    }
+
+    // A count of bits in unary, then that many less one bits below an
+    // implied top bit. Small values are just the unary count.
+    fn escaped(&mut self) -> Option<u32> {
+        let bits = self.ones(33)?;
+        match bits {
+            33 => None,
+            0 | 1 => Some(bits),
+            _ => Some(self.bits(bits - 1)? | 1 << (bits - 1)),
+        }
+    }
+
+    // A value up to `max`, with one bit less for the smallest ones.
+    fn code(&mut self, max: u32) -> Option<u32> {
+        if max < 2 {
+            return if max == 1 { self.bit() } else { Some(0) };
+        }
+        let bits = 32 - max.leading_zeros();
+        let extras = (1u64 << bits) as u32 - max - 1;
+        let code = self.bits(bits - 1)?;
+        if code >= extras {
+            Some((code << 1) - extras + self.bit()?)
+        } else {
+            Some(code)
+        }
+    }
+}
+
+// The adaptive Golomb-like code of the residuals. The three medians of each
+// channel split the values in ranges, the count of ones picks the range.
+#[derive(Default)]
+struct Words {
+    median: [[u32; 3]; 2],
+    zeros: u32,
+    holding_one: bool,
+    holding_zero: bool,
+}
+
+const DIV: [u32; 3] = [128, 64, 32];
+
+impl Words {
+    fn get_med(&self, channel: usize, i: usize) -> u32 {
+        (self.median[channel][i] >> 4) + 1
+    }
+
+    fn inc_med(&mut self, channel: usize, i: usize) {
+        let median = &mut self.median[channel][i];
+        *median = median.wrapping_add(median.wrapping_add(DIV[i]) / DIV[i] * 5);
+    }
+
+    fn dec_med(&mut self, channel: usize, i: usize) {
+        let median = &mut self.median[channel][i];
+        *median = median.saturating_sub(median.wrapping_add(DIV[i] - 2) / DIV[i] * 2);
+    }
+
+    fn next(&mut self, bits: &mut BitReader, channel: usize) -> Option<i32> {
+        if self.holding_zero {
+            self.holding_zero = false;
+            let low = bits.code(self.get_med(channel, 0) - 1)?;
+            self.dec_med(channel, 0);
+            return Some(with_sign(low, bits.bit()?));
+        }
+
+        // runs of zeros are counted when the signal is very quiet
+        if self.median[0][0] < 2 && self.median[1][0] < 2 && !self.holding_one {
+            if self.zeros > 0 {
+                self.zeros -= 1;
+                if self.zeros > 0 {
+                    return Some(0);
+                }
+            } else {
+                self.zeros = bits.escaped()?;
+                if self.zeros > 0 {
+                    self.median = [[0; 3]; 2];
+                    return Some(0);
+                }
+            }
+        }
+
+        let mut ones = bits.ones(17)?;
+        if ones == 17 {
+            return None;
+        }
+        if ones == 16 {
+            ones += bits.escaped()?;
+        }
+        // an odd count says the next value has at least one one, an even
+        // count that it has none and it is sent without
+        let held = self.holding_one;
+        self.holding_one = ones & 1 == 1;
+        ones = if held { (ones >> 1) + 1 } else { ones >> 1 };
+        self.holding_zero = !self.holding_one;
+
+        let mut low;
+        let high;
+        if ones == 0 {
+            low = 0;
+            high = self.get_med(channel, 0) - 1;
+            self.dec_med(channel, 0);
+        } else {
+            low = self.get_med(channel, 0);
+            self.inc_med(channel, 0);
+            if ones == 1 {
+                high = low.wrapping_add(self.get_med(channel, 1) - 1);
+                self.dec_med(channel, 1);
+            } else {
+                low = low.wrapping_add(self.get_med(channel, 1));
+                self.inc_med(channel, 1);
+                if ones == 2 {
+                    high = low.wrapping_add(self.get_med(channel, 2) - 1);
+                    self.dec_med(channel, 2);
+                } else {
+                    low = low.wrapping_add((ones - 2).wrapping_mul(self.get_med(channel, 2)));
+                    high = low.wrapping_add(self.get_med(channel, 2) - 1);
+                    self.inc_med(channel, 2);
+                }
+            }
+        }
+        let value = low.wrapping_add(bits.code(high.wrapping_sub(low))?);
+        Some(with_sign(value, bits.bit()?))
+    }
+}
+
+fn with_sign(value: u32, sign: u32) -> i32 {
+    if sign == 1 {
+        !(value as i32)
+    } else {
+        value as i32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    // An encoder for what the decoder reads, the inverse of every step.
+
+    struct BitWriter {
+        bytes: Vec<u8>,
+        position: usize,
+    }
+
+    impl BitWriter {
+        fn bit(&mut self, bit: u32) {
+            if self.position.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            *self.bytes.last_mut().unwrap() |= (bit as u8 & 1) << (self.position % 8);
+            self.position += 1;
+        }
+
+        fn bits(&mut self, value: u32, count: u32) {
+            for i in 0..count {
+                self.bit(value >> i & 1);
+            }
+        }
+
+        fn unary(&mut self, ones: u32) {
+            for _ in 0..ones {
+                self.bit(1);
+            }
+            self.bit(0);
+        }
+
+        fn escaped(&mut self, value: u32) {
+            if value < 2 {
+                self.unary(value);
+            } else {
+                let bits = 32 - value.leading_zeros();
+                self.unary(bits);
+                self.bits(value, bits - 1);
+            }
+        }
+
+        fn code(&mut self, value: u32, max: u32) {
+            if max < 2 {
+                if max == 1 {
+                    self.bit(value);
+                }
+                return;
+            }
+            let bits = 32 - max.leading_zeros();
+            let extras = (1u64 << bits) as u32 - max - 1;
+            if value < extras {
+                self.bits(value, bits - 1);
+            } else {
+                self.bits((value + extras) >> 1, bits - 1);
+                self.bit((value + extras) & 1);
+            }
+        }
+    }
+
+    // What the decoder needs to read one residual.
+    struct Word {
+        ones: u32,
+        offset: u32,
+        max: u32,
+        sign: u32,
+    }
+
+    // The medians move the same way whatever the bits look like, so the
+    // count of ones of every value is known before any bit is written.
+    fn encode_words(residuals: &[i32], channels: usize, median: u32) -> Vec<u8> {
+        let mut words = Words {
+            median: [[median; 3]; 2],
+            ..Default::default()
+        };
+        let mut coded = Vec::new();
+        for (i, &residual) in residuals.iter().enumerate() {
+            // too loud for runs of zeros
+            assert!(words.median[0][0] >= 2 || words.median[1][0] >= 2);
+            let c = i % channels;
+            let sign = (residual < 0) as u32;
+            let value = if residual < 0 { !residual } else { residual } as u32;
+            let (ones, low, high);
+            let m0 = words.get_med(c, 0);
+            if value < m0 {
+                (ones, low, high) = (0, 0, m0 - 1);
+                words.dec_med(c, 0);
+            } else {
+                words.inc_med(c, 0);
+                let m1 = words.get_med(c, 1);
+                if value - m0 < m1 {
+                    (ones, low, high) = (1, m0, m0 + m1 - 1);
+                    words.dec_med(c, 1);
+                } else {
+                    words.inc_med(c, 1);
+                    let m2 = words.get_med(c, 2);
+                    let steps = (value - m0 - m1) / m2;
+                    ones = 2 + steps;
+                    low = m0 + m1 + steps * m2;
+                    high = low + m2 - 1;
+                    if ones == 2 {
+                        words.dec_med(c, 2);
+                    } else {
+                        words.inc_med(c, 2);
+                    }
+                }
+            }
+            coded.push(Word {
+                ones,
+                offset: value - low,
+                max: high - low,
+                sign,
+            });
+        }
+
+        // the parity of every count tells if the next one is more than zero,
+        // a zero after an even count is sent without one
+        let mut bits = BitWriter {
+            bytes: Vec::new(),
+            position: 0,
+        };
+        let (mut holding_one, mut holding_zero) = (false, false);
+        for (i, word) in coded.iter().enumerate() {
+            if holding_zero {
+                assert_eq!(word.ones, 0);
+                holding_zero = false;
+            } else {
+                let next = coded.get(i + 1).is_some_and(|next| next.ones > 0) as u32;
+                let ones = if holding_one {
+                    word.ones - 1
+                } else {
+                    word.ones
+                };
+                let count = ones * 2 + next;
+                if count >= 16 {
+                    for _ in 0..16 {
+                        bits.bit(1);
+                    }
+                    bits.bit(0);
+                    bits.escaped(count - 16);
+                } else {
+                    bits.unary(count);
+                }
+                holding_one = next == 1;
+                holding_zero = next == 0;
+            }
+            bits.code(word.offset, word.max);
+            bits.bit(word.sign);
+        }
+        bits.bytes
+    }
+
+    // The residuals that give `samples` after the pass, with the state
+    // moving on as in the decoder.
+    fn undo_pass(pass: &mut DecorrPass, samples: &mut [i32], mono: bool) {
+        let delta = pass.delta;
+        match pass.term {
+            -1 => {
+                for frame in samples.chunks_exact_mut(2) {
+                    let [left, right] = [frame[0], frame[1]];
+                    frame[0] = left - apply_weight(pass.weight_a, pass.samples_a[0]);
+                    update_weight_clip(&mut pass.weight_a, delta, pass.samples_a[0], frame[0]);
+                    frame[1] = right - apply_weight(pass.weight_b, left);
+                    update_weight_clip(&mut pass.weight_b, delta, left, frame[1]);
+                    pass.samples_a[0] = right;
+                }
+            }
+            -2 => {
+                for frame in samples.chunks_exact_mut(2) {
+                    let [left, right] = [frame[0], frame[1]];
+                    frame[1] = right - apply_weight(pass.weight_b, pass.samples_b[0]);
+                    update_weight_clip(&mut pass.weight_b, delta, pass.samples_b[0], frame[1]);
+                    frame[0] = left - apply_weight(pass.weight_a, right);
+                    update_weight_clip(&mut pass.weight_a, delta, right, frame[0]);
+                    pass.samples_b[0] = left;
+                }
+            }
+            -3 => {
+                for frame in samples.chunks_exact_mut(2) {
+                    let [left, right] = [frame[0], frame[1]];
+                    frame[0] = left - apply_weight(pass.weight_a, pass.samples_a[0]);
+                    update_weight_clip(&mut pass.weight_a, delta, pass.samples_a[0], frame[0]);
+                    frame[1] = right - apply_weight(pass.weight_b, pass.samples_b[0]);
+                    update_weight_clip(&mut pass.weight_b, delta, pass.samples_b[0], frame[1]);
+                    pass.samples_b[0] = left;
+                    pass.samples_a[0] = right;
+                }
+            }
+            term => {
+                let channels = if mono { 1 } else { 2 };
+                let (mut m, mut k) = (0, term as usize & (MAX_TERM - 1));
+                for frame in samples.chunks_exact_mut(channels) {
+                    for (c, sample) in frame.iter_mut().enumerate() {
+                        let (weight, history) = if c == 0 {
+                            (&mut pass.weight_a, &mut pass.samples_a)
+                        } else {
+                            (&mut pass.weight_b, &mut pass.samples_b)
+                        };
+                        let predicted = if term > MAX_TERM as i32 {
+                            predict(term, history)
+                        } else {
+                            history[m]
+                        };
+                        let output = *sample;
+                        *sample = output - apply_weight(*weight, predicted);
+                        update_weight(weight, delta, predicted, *sample);
+                        if term > MAX_TERM as i32 {
+                            history[1] = history[0];
+                            history[0] = output;
+                        } else {
+                            history[k] = output;
+                        }
+                    }
+                    m = (m + 1) & (MAX_TERM - 1);
+                    k = (k + 1) & (MAX_TERM - 1);
+                }
+            }
+        }
+    }
+
+    fn sub_block(block: &mut Vec<u8>, id: u8, data: &[u8]) {
+        let words = data.len().div_ceil(2);
+        let odd = if data.len() % 2 == 1 { ID_ODD_SIZE } else { 0 };
+        if words > 0xff {
+            block.push(id | odd | ID_LARGE);
+            block.extend_from_slice(&(words as u32).to_le_bytes()[..3]);
+        } else {
+            block.extend_from_slice(&[id | odd, words as u8]);
+        }
+        block.extend_from_slice(data);
+        if odd != 0 {
+            block.push(0);
+        }
+    }
+
+    const WEIGHT: i8 = 40;
+    // 2^(0x0a00 / 256 - 1), a history of 512
+    const HISTORY_LOG: i16 = 0x0a00;
+    // 2^(0x0e00 / 256 - 1), medians of 8192
+    const MEDIAN_LOG: u16 = 0x0e00;
+
+    // One 16 bit block of `samples`, interleaved if there are two channels.
+    fn encode_block(
+        samples: &[i32],
+        mono: bool,
+        terms: &[i32],
+        joint: bool,
+        index: u32,
+    ) -> Vec<u8> {
+        let channels = if mono { 1 } else { 2 };
+        let frames = samples.len() / channels;
+        let mut residuals = samples.to_vec();
+        if joint {
+            for frame in residuals.chunks_exact_mut(2) {
+                let mid = frame[0] - frame[1];
+                frame[1] += mid >> 1;
+                frame[0] = mid;
+            }
+        }
+        let history = exp2s(HISTORY_LOG as i32);
+        let mut passes: Vec<DecorrPass> = terms
+            .iter()
+            .map(|&term| DecorrPass {
+                term,
+                delta: 2,
+                weight_a: restore_weight(WEIGHT),
+                weight_b: restore_weight(WEIGHT),
+                samples_a: [history; MAX_TERM],
+                samples_b: [history; MAX_TERM],
+            })
+            .collect();
+        for pass in passes.iter_mut().rev() {
+            undo_pass(pass, &mut residuals, mono);
+        }
+
+        let mut terms_data = Vec::new();
+        let mut weights = Vec::new();
+        let mut histories = Vec::new();
+        for &term in terms.iter().rev() {
+            terms_data.push((2 << 5 | (term + 5)) as u8);
+            weights.extend(std::iter::repeat_n(WEIGHT as u8, channels));
+            let count = match term {
+                17 | 18 => 2 * channels,
+                term if term < 0 => 2,
+                term => term as usize * channels,
+            };
+            for _ in 0..count {
+                histories.extend_from_slice(&HISTORY_LOG.to_le_bytes());
+            }
+        }
+        let median = exp2s(MEDIAN_LOG as i32) as u32;
+        let entropy: Vec<u8> = std::iter::repeat_n(MEDIAN_LOG.to_le_bytes(), 3 * channels)
+            .flatten()
+            .collect();
+
+        let mut block = vec![0; HEADER_LEN];
+        sub_block(&mut block, ID_DECORR_TERMS, &terms_data);
+        sub_block(&mut block, ID_DECORR_WEIGHTS, &weights);
+        sub_block(&mut block, ID_DECORR_SAMPLES, &histories);
+        sub_block(&mut block, ID_ENTROPY_VARS, &entropy);
+        sub_block(
+            &mut block,
+            ID_WV_BITSTREAM,
+            &encode_words(&residuals, channels, median),
+        );
+        let mut flags = 1 | INITIAL_BLOCK | FINAL_BLOCK | 9 << SRATE_LSB;
+        if mono {
+            flags |= MONO;
+        }
+        if joint {
+            flags |= JOINT_STEREO;
+        }
+        write_header(&mut block, index, frames as u32, flags);
+        block
+    }
+
+    fn write_header(block: &mut [u8], index: u32, frames: u32, flags: u32) {
+        let size = block.len() as u32 - 8;
+        block[..4].copy_from_slice(b"wvpk");
+        block[4..8].copy_from_slice(&size.to_le_bytes());
+        block[8..10].copy_from_slice(&0x410u16.to_le_bytes());
+        block[16..20].copy_from_slice(&index.to_le_bytes());
+        block[20..24].copy_from_slice(&frames.to_le_bytes());
+        block[24..28].copy_from_slice(&flags.to_le_bytes());
+    }
+
+    // A loud chord with some noise, so the residuals are never tiny.
+    fn signal(frames: usize, channels: usize) -> Vec<i32> {
+        let mut noise = 12345u32;
+        (0..frames * channels)
+            .map(|i| {
+                noise = noise.wrapping_mul(1103515245).wrapping_add(12345);
+                let t = (i / channels) as f64 / 44100.0;
+                let c = (i % channels) as f64;
+                let tone = (2.0 * std::f64::consts::PI * (440.0 + 220.0 * c) * t).sin() * 12000.0
+                    + (2.0 * std::f64::consts::PI * 3000.0 * t).sin() * 6000.0;
+                tone as i32 + (noise >> 20) as i32 - 2048
+            })
+            .collect()
+    }
+
+    fn decode(block: &[u8]) -> Vec<i32> {
+        let header = BlockHeader::parse(block).unwrap();
+        let mut samples = Vec::new();
+        decode_block(block, &header, &mut samples).unwrap();
+        samples
+    }
+
+    #[test]
+    fn mono_block_decodes_to_what_was_encoded() {
+        let samples = signal(4000, 1);
+        let block = encode_block(&samples, true, &[17, 18, 2], false, 0);
+        assert_eq!(decode(&block), samples);
+    }
+
+    #[test]
+    fn joint_stereo_block_decodes_to_what_was_encoded() {
+        let samples = signal(4000, 2);
+        let block = encode_block(&samples, false, &[-1, -2, -3, 3, 18], true, 0);
+        assert_eq!(decode(&block), samples);
+    }
+
+    #[test]
+    fn silence_is_a_run_of_zeros() {
+        let mut block = vec![0; HEADER_LEN];
+        sub_block(&mut block, ID_DECORR_TERMS, &[]);
+        sub_block(&mut block, ID_ENTROPY_VARS, &[0; 6]);
+        let mut bits = BitWriter {
+            bytes: Vec::new(),
+            position: 0,
+        };
+        bits.escaped(1000);
+        sub_block(&mut block, ID_WV_BITSTREAM, &bits.bytes);
+        write_header(&mut block, 0, 1000, 1 | MONO | INITIAL_BLOCK | FINAL_BLOCK);
+        assert_eq!(decode(&block), vec![0; 1000]);
+    }
+
+    #[test]
+    fn bad_blocks_are_errors() {
+        let samples = signal(1000, 1);
+        let block = encode_block(&samples, true, &[2], false, 0);
+        let header = BlockHeader::parse(&block).unwrap();
+        let mut out = Vec::new();
+        // the bitstream cut short
+        assert!(decode_block(&block[..block.len() - 200], &header, &mut out).is_err());
+        let mut hybrid = block.clone();
+        hybrid[24] |= HYBRID as u8;
+        let header = BlockHeader::parse(&hybrid).unwrap();
+        assert!(decode_block(&hybrid, &header, &mut out).is_err());
+        // bits in a second bitstream
+        let mut wide = Vec::new();
+        sub_block(&mut wide, ID_INT32_INFO, &[8, 0, 0, 0]);
+        wide.extend_from_slice(&block[HEADER_LEN..]);
+        let mut wide = [&block[..HEADER_LEN], &wide].concat();
+        write_header(&mut wide, 0, 1000, 3 | MONO | INITIAL_BLOCK | FINAL_BLOCK);
+        let header = BlockHeader::parse(&wide).unwrap();
+        assert!(decode_block(&wide, &header, &mut out).is_err());
+        // and files with them are refused when opened
+        let source = MediaSourceStream::new(Box::new(Cursor::new(wide)), Default::default());
+        assert!(WavPackReader::try_new(source, &FormatOptions::default()).is_err());
+        // a sample count no block has
+        let mut huge = block.clone();
+        huge[20..24].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(BlockHeader::parse(&huge).is_none());
+    }
+
+    #[test]
+    fn file_is_read_seeked_and_decoded() {
+        let samples = signal(6000, 2);
+        let mut file = Vec::new();
+        for (i, part) in samples.chunks(2 * 2000).enumerate() {
+            let mut block = encode_block(part, false, &[18, 2], true, i as u32 * 2000);
+            block[12..16].copy_from_slice(&6000u32.to_le_bytes());
+            file.extend_from_slice(&block);
+        }
+        let source = MediaSourceStream::new(Box::new(Cursor::new(file)), Default::default());
+        let mut reader = WavPackReader::try_new(source, &FormatOptions::default()).unwrap();
+        let params = reader.tracks()[0].codec_params.clone();
+        assert_eq!(params.sample_rate, Some(44100));
+        assert_eq!(params.n_frames, Some(6000));
+        assert_eq!(params.channels.unwrap().count(), 2);
+        let mut decoder = WavPackDecoder::try_new(&params, &DecoderOptions::default()).unwrap();
+
+        let mut decoded = Vec::new();
+        while let Ok(packet) = reader.next_packet() {
+            let buffer = decoder.decode(&packet).unwrap();
+            let mut planes = AudioBuffer::<f32>::new(2000, *buffer.spec());
+            buffer.convert(&mut planes);
+            for i in 0..planes.frames() {
+                decoded.push(planes.chan(0)[i]);
+                decoded.push(planes.chan(1)[i]);
+            }
+        }
+        let expected: Vec<f32> = samples.iter().map(|&s| s as f32 / 32768.0).collect();
+        assert_eq!(decoded, expected);
+
+        // lands on the start of the block with the sample in it
+        let seeked = reader
+            .seek(
+                SeekMode::Accurate,
+                SeekTo::TimeStamp {
+                    ts: 4500,
+                    track_id: 0,
+                },
+            )
+            .unwrap();
+        assert_eq!(seeked.actual_ts, 4000);
+        assert_eq!(reader.next_packet().unwrap().ts(), 4000);
+    }
+}